@@ -0,0 +1,138 @@
+//! The wire protocol between `ktest` (inside the kernel) and kboot.
+//!
+//! `ktest` writes one JSON object per line to the `-debugcon` device. Every line
+//! is one of the [`Message`] variants below, distinguished by its identifying field:
+//!
+//! | Message                | Identifying field | Example                                                        |
+//! |------------------------|-------------------|----------------------------------------------------------------|
+//! | [`SnapshotPoint`]      | `snapshot_point`  | `{"snapshot_point":"init_complete"}`                           |
+//! | [`Handshake`]          | `ktest_protocol`  | `{"ktest_protocol":1}`                                         |
+//! | [`TestGroupStart`]     | `test_group`      | `{"test_group":"my_kernel","test_count":3,"use_kview":true}`   |
+//! | [`TestStart`]          | `test_start`      | `{"test_start":"my_kernel::memory::test_alloc"}`               |
+//! | [`TestResultRecord`]   | `test`            | `{"test":"my_kernel::memory::test_alloc","result":"pass","cycle_count":1200}` |
+//!
+//! The handshake should be the first line, so kboot knows which version of the
+//! protocol the kernel speaks. Output without a handshake is treated as version 0,
+//! the format used before the handshake was introduced, which is otherwise identical.
+//!
+//! Compatibility rules:
+//! - Unknown fields are ignored, so new optional fields do not require a new version.
+//! - A line that is not valid JSON or not a known message (e.g. a log line that was
+//!   interleaved on the debugcon port) is recorded as a diagnostic and skipped; it
+//!   does not invalidate the rest of the test group.
+//! - Removing or changing the meaning of a field requires a new protocol version.
+//!
+//! The crate is `no_std` and only needs `alloc`, `serde` and `serde_json` (for the
+//! values of `data`), so it can be shared by `ktest` inside the kernel and kboot.
+
+#![no_std]
+
+extern crate alloc;
+
+use alloc::{collections::BTreeMap, string::String, vec::Vec};
+use serde::{Deserialize, Serialize};
+
+/// The newest protocol version understood by this version of kboot
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// A single line of the ktest protocol.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(untagged)]
+pub enum Message {
+    SnapshotPoint(SnapshotPoint),
+    Handshake(Handshake),
+    TestGroupStart(TestGroupStart),
+    TestStart(TestStart),
+    TestResult(TestResultRecord)
+}
+
+/// Marks the point at which the VM may be snapshotted (see `--snapshot`). After sending
+/// this record, the kernel must wait until kboot releases it: it polls the line status
+/// register of COM2 (port `0x2fd`) until bit 0 is set, then reads the byte from port `0x2f8`.
+/// Without `--snapshot` there is no device at these ports, and the read returns `0xff`,
+/// so the kernel continues immediately.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct SnapshotPoint {
+    pub snapshot_point: String
+}
+
+/// Announces the protocol version spoken by the kernel.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Handshake {
+    pub ktest_protocol: u32
+}
+
+/// Starts a test group, which is one test executable.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct TestGroupStart {
+    pub test_group: String,
+    /// Number of tests in the group, including ignored tests
+    pub test_count: u64,
+    #[serde(default)]
+    pub use_kview: bool
+}
+
+/// Sent before a test runs, so a crash can be attributed to it.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct TestStart {
+    /// Fully qualified test name
+    pub test_start: String
+}
+
+/// The result of a single test.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct TestResultRecord {
+    /// Fully qualified test name
+    pub test: String,
+    /// One of `pass`, `fail`, `ignore`, `should_panic` or `timeout`
+    pub result: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cycle_count: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub location: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub message: Option<String>,
+    /// Why the test was ignored, e.g. from `#[ignore = "reason"]`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+    /// User-defined tags of the test
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tags: Vec<String>,
+    /// Wall-clock duration of the test in microseconds, if the kernel measured it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub duration_us: Option<u64>,
+    /// Arbitrary key/value data attached by the test
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub data: BTreeMap<String, serde_json::Value>
+}
+
+/// Parse a single line of debugcon output into a protocol message.
+pub fn parse_line(line: &str) -> Result<Message, serde_json::Error> {
+    serde_json::from_str(line)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::string::ToString;
+
+    #[test]
+    fn when_line_has_unknown_fields_then_message_is_parsed() {
+        let message = parse_line(r#"{"test":"kernel::mem::alloc","result":"pass","cycle_count":12,"new_field":[1,2]}"#).unwrap();
+        assert_eq!(message, Message::TestResult(TestResultRecord {
+            test: "kernel::mem::alloc".to_string(),
+            result: "pass".to_string(),
+            cycle_count: Some(12),
+            location: None,
+            message: None,
+            reason: None,
+            tags: Vec::new(),
+            duration_us: None,
+            data: BTreeMap::new()
+        }));
+
+        assert!(matches!(parse_line(r#"{"ktest_protocol":1}"#), Ok(Message::Handshake(Handshake { ktest_protocol: 1 }))));
+        assert!(parse_line("[kernel] log line on the wrong port").is_err());
+        assert!(parse_line(r#"{"unknown_message":true}"#).is_err());
+    }
+}
@@ -1,8 +1,15 @@
 use anyhow::{anyhow, Result};
-use std::{env, path::PathBuf, sync::OnceLock};
+use std::{env, io::IsTerminal, path::PathBuf, sync::OnceLock};
 
 use crate::BUILD_DIRECTORY;
 
+/// Environment variable that overrides the build directory of a session
+pub const BUILD_DIRECTORY_VARIABLE: &str = "KBOOT_BUILD_DIRECTORY";
+
+/// Environment variable with the kernel command line of a single run, which
+/// takes precedence over `cmdline` in kboot.toml
+pub const CMDLINE_VARIABLE: &str = "KBOOT_CMDLINE";
+
 // Command line arguments
 pub static ARGUMENTS: OnceLock<Vec<String>> = OnceLock::new();
 
@@ -49,6 +56,11 @@ pub fn get_executable_parent() -> Result<PathBuf> {
 /// Get the workspace root directory by traversing up from the executable path 
 /// until the "target" directory is found
 pub fn get_workspace_root() -> Result<PathBuf> {
+    // `kboot test`, `kboot vm` and `kboot clean` are started from the workspace, rather than by cargo with an executable
+    if is_test_command() || is_vm_command() || is_clean_command() {
+        return Ok(env::current_dir()?);
+    }
+
     let executable_binding = get_executable_parent()?;
     let mut executable_path = executable_binding.as_path();
 
@@ -111,30 +123,11 @@ pub fn is_no_ktest() -> bool {
     args.iter().any(|arg| arg == "--no-ktest")
 }
 
-/// Determine whether QEMU options have been provided
+/// Determine whether QEMU options have been provided. Whether they are
+/// quoted correctly is checked by [`get_qemu_options`].
 pub fn has_qemu_options() -> bool {
     let args = get_arguments();
-    let has_qemu_arg = args.iter().any(|arg| arg == "--qemu");
-    
-    let mut in_quotes = false;
-    for arg in args {
-        if arg.starts_with('"') {
-            in_quotes = true;
-        }
-        if in_quotes {
-            if arg.ends_with('"') {
-                in_quotes = false;
-                break;
-            }
-        }
-    }
-
-    let has_qemu_options = !in_quotes;
-    if has_qemu_arg && !has_qemu_options {
-        panic!("--qemu must be followed by quoted QEMU options");
-    }
-
-    has_qemu_arg && has_qemu_options
+    args.iter().any(|arg| arg == "--qemu")
 }
 
 /// Get the QEMU options provided after the `--qemu` flag
@@ -149,15 +142,70 @@ pub fn get_qemu_options() -> Result<Vec<String>> {
     Ok(qemu_options)
 }
 
+/// Determine whether the executable should be run as a benchmark
+/// (`cargo bench` passes `--bench` to the executable)
+pub fn is_bench() -> bool {
+    let args = get_arguments();
+    args.iter().any(|arg| arg == "--bench")
+}
+
+/// Determine whether the cycle counts of this run should be saved as the new baseline
+pub fn is_save_baseline() -> bool {
+    let args = get_arguments();
+    args.iter().any(|arg| arg == "--save-baseline")
+}
+
+/// Determine whether the kernel's coverage profile should be collected
+pub fn is_coverage() -> bool {
+    let args = get_arguments();
+    args.iter().any(|arg| arg == "--coverage")
+}
+
+/// Determine whether the VM should be snapshotted at the kernel's snapshot point,
+/// and restored from that snapshot in later runs of the same image
+pub fn is_snapshot() -> bool {
+    let args = get_arguments();
+    args.iter().any(|arg| arg == "--snapshot")
+}
+
+/// Determine whether kboot runs without anyone at the terminal: with `--headless`,
+/// in CI (`CI=true`), when stdin is not a terminal, or when built with the `ci` feature.
+///
+/// Headless runs have no keyboard input, web display or kview, and plain output.
+pub fn is_headless() -> bool {
+    let args = get_arguments();
+    args.iter().any(|arg| arg == "--headless")
+        || env::var("CI").is_ok_and(|ci| ci == "true" || ci == "1")
+        || !std::io::stdin().is_terminal()
+        || cfg!(feature = "ci")
+}
+
 pub fn is_legacy_boot() -> bool {
     let args = get_arguments();
     args.iter().any(|arg| arg == "--legacy-boot")
 }
 
-/// Determine whether a ramdisk path has been provided
-pub fn has_ramdisk() -> bool {
-    let args = get_arguments();
-    args.iter().any(|arg| arg == "--ramdisk")
+/// Get the directory where the build artifacts of this session are stored.
+///
+/// `kboot test` gives each test executable its own build directory through
+/// the `KBOOT_BUILD_DIRECTORY` environment variable, so that concurrent VMs
+/// don't share images or testing files. Round history stays in the workspace.
+pub fn get_build_directory() -> Result<PathBuf> {
+    if let Ok(build_directory) = env::var(BUILD_DIRECTORY_VARIABLE) {
+        return Ok(PathBuf::from(build_directory));
+    }
+
+    Ok(get_workspace_root()?.join(BUILD_DIRECTORY))
+}
+
+/// Determine whether this session is one of the test executables run by `kboot test`
+pub fn is_isolated_run() -> bool {
+    env::var_os(BUILD_DIRECTORY_VARIABLE).is_some()
+}
+
+/// Get the kernel command line given through the `KBOOT_CMDLINE` environment variable
+pub fn get_cmdline() -> Option<String> {
+    env::var(CMDLINE_VARIABLE).ok()
 }
 
 /// Get the ramdisk path provided after the `--ramdisk` flag
@@ -178,8 +226,38 @@ pub fn get_ramdisk_path() -> Result<Option<PathBuf>> {
     Ok(None)
 }
 
-pub fn should_clean() -> bool {
-    get_executable().unwrap().to_string_lossy() == "clean"
+/// Determine whether kboot was invoked as `kboot vm <command>`
+pub fn is_vm_command() -> bool {
+    let args = get_arguments();
+    args.get(1).is_some_and(|arg| arg == "vm")
+}
+
+/// Get the arguments following `kboot vm`
+pub fn get_vm_arguments() -> &'static [String] {
+    let args = get_arguments();
+    args.get(2..).unwrap_or_default()
+}
+
+/// Determine whether kboot was invoked as `kboot clean`
+pub fn is_clean_command() -> bool {
+    let args = get_arguments();
+    args.get(1).is_some_and(|arg| arg == "clean")
+}
+
+/// Determine whether kboot was invoked as `kboot test`
+pub fn is_test_command() -> bool {
+    let args = get_arguments();
+    args.get(1).is_some_and(|arg| arg == "test")
+}
+
+/// Get the arguments following `kboot test`, split at `--` into the
+/// arguments for `cargo test` and the arguments for each test executable
+pub fn get_test_arguments() -> (&'static [String], &'static [String]) {
+    let args = get_arguments().get(2..).unwrap_or_default();
+    match args.iter().position(|arg| arg == "--") {
+        Some(index) => (&args[..index], &args[index + 1..]),
+        None => (args, &[])
+    }
 }
 
 /// Determine which bootloader to use based on command line arguments
@@ -192,31 +270,45 @@ pub fn get_bootloader_selection() -> BootloaderSelection {
     }
 }
 
-/// Get the limine.conf by scanning the project directory for it
-pub fn get_limine_conf() -> Result<PathBuf> {
+/// Find the limine.conf by scanning the project directory for it. The package of
+/// the kernel is searched first, so that workspaces with several kernels work.
+///
+/// Returns `None` if there is no limine.conf, in which case one is generated.
+pub fn find_limine_conf() -> Result<Option<PathBuf>> {
+    if let Ok(manifest_dir) = get_manifest_dir() {
+        let mut found = Vec::new();
+        scan_for_limine_conf(&manifest_dir, &mut found);
+        if let Some(limine_conf) = found.into_iter().next() {
+            return Ok(Some(limine_conf));
+        }
+    }
+
     let workspace_root = get_workspace_root()?;
     log::info!("Searching for limine.conf in workspace root: {:?}", workspace_root);
 
-    if let Some(limine_conf) = scan_for_limine_conf(&workspace_root) {
-        Ok(limine_conf)
-    } else {
-        Err(anyhow!("limine.conf not found in workspace"))
+    let mut found = Vec::new();
+    scan_for_limine_conf(&workspace_root, &mut found);
+    if found.len() > 1 {
+        log::warn!("Found several limine.conf files: {:?}", found);
+        eprintln!("kboot: found {} limine.conf files, using {}; set `conf` under [limine] in kboot.toml to choose one",
+            found.len(), found[0].display());
     }
+
+    Ok(found.into_iter().next())
 }
 
-/// Helper to recursively scan a directory for limine.conf file
-fn scan_for_limine_conf(dir: &PathBuf) -> Option<PathBuf> {
+/// Helper to recursively scan a directory for limine.conf files, which are
+/// collected with the files of a directory before those of its subdirectories
+fn scan_for_limine_conf(dir: &PathBuf, found: &mut Vec<PathBuf>) {
     if let Ok(entries) = std::fs::read_dir(dir) {
         // First pass: check for limine.conf in current directory
         for entry in entries.flatten() {
             let path = entry.path();
             
-            if path.is_file() {
-                if let Some(file_name) = path.file_name() {
-                    if file_name == "limine.conf" {
-                        return Some(path);
-                    }
-                }
+            if path.is_file()
+                && let Some(file_name) = path.file_name()
+                && file_name == "limine.conf" {
+                found.push(path);
             }
         }
         
@@ -225,21 +317,18 @@ fn scan_for_limine_conf(dir: &PathBuf) -> Option<PathBuf> {
             for entry in entries.flatten() {
                 let path = entry.path();
                 
-                if path.is_dir() {
-                    if let Some(dir_name) = path.file_name().and_then(|n| n.to_str()) {
-                        if dir_name != "target" && dir_name != BUILD_DIRECTORY && !dir_name.starts_with('.') {
-                            if let Some(found) = scan_for_limine_conf(&path) {
-                                return Some(found);
-                            }
-                        }
-                    }
+                if path.is_dir()
+                    && let Some(dir_name) = path.file_name().and_then(|n| n.to_str())
+                    && dir_name != "target" && dir_name != BUILD_DIRECTORY && !dir_name.starts_with('.') {
+                    scan_for_limine_conf(&path, found);
                 }
             }
         }
     }
-    None
 }
 
+/// Bootloader that the disk image is built with
+#[derive(Debug, Copy, Clone, PartialEq)]
 pub enum BootloaderSelection {
     BootloaderCrate,
     Limine,
@@ -250,8 +339,9 @@ fn get_quoted_args(start_index: usize) -> Result<Vec<String>> {
     let args = get_arguments();
     let mut combined = String::new();
     let mut in_quotes = false;
+    let mut closed = false;
 
-    for arg in &args[start_index..] {
+    for arg in args.get(start_index..).unwrap_or_default() {
         if arg.starts_with('"') {
             in_quotes = true;
         }
@@ -259,16 +349,18 @@ fn get_quoted_args(start_index: usize) -> Result<Vec<String>> {
             combined.push_str(arg);
             combined.push(' ');
         }
-        if arg.ends_with('"') {
+        // a lone quote opens the quoted arguments, unless it closes them
+        if in_quotes && arg.ends_with('"') && (arg.len() > 1 || combined.len() > 2) {
+            closed = true;
             break;
         }
     }
 
-    if !in_quotes {
+    if !closed {
         return Err(anyhow!("Expected quoted arguments starting from index {}", start_index));
     }
 
-    Ok(combined.trim().to_string()
+    Ok(combined.trim()
         .split(" ")
         .map(|s| s.trim_start_matches("\"").trim_end_matches("\"").to_string())
         .collect::<Vec<String>>())
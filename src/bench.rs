@@ -0,0 +1,235 @@
+use std::{collections::BTreeMap, fs, path::{Path, PathBuf}};
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use crate::{args, config::{self, BenchConfig}, interrupt, ktest::{self, TestOutcome}, qemu, stats::{self, Outliers}, BUILD_DIRECTORY};
+
+/// Benchmark reports per test group, keyed by the name of the group.
+/// This is the layout of the baseline file that may be committed to a repository.
+type BenchBaseline = BTreeMap<String, BenchReport>;
+
+/// Boots the kernel the configured number of times and aggregates the
+/// `cycle_count` of every passed test into statistics per test.
+///
+/// Tests that are reported multiple times within a single run (repeated
+/// measurements from ktest) contribute one sample per report.
+pub fn run_benchmarks() -> Result<()> {
+    let bench_config = &config::get_config()?.bench;
+    if bench_config.runs == 0 {
+        return Err(anyhow!("bench.runs must be at least 1"));
+    }
+
+    let mut test_group_name = None;
+    let mut samples: BTreeMap<String, Vec<f64>> = BTreeMap::new();
+    for run in 1..=bench_config.runs {
+        if interrupt::is_interrupted() {
+            break;
+        }
+
+        println!("kboot: benchmark run {}/{}", run, bench_config.runs);
+        log::info!("Starting benchmark run {}/{}", run, bench_config.runs);
+
+        let qemu_run = qemu::run()?;
+        if qemu_run.interrupted {
+            break; // the samples of an unfinished run are incomplete
+        }
+        if qemu_run.is_failure() {
+            return Err(anyhow!("Benchmark run {} failed with QEMU exit code {}", run, qemu_run.exit_code));
+        }
+
+        let test_group = ktest::collect_test_group(&qemu_run)?
+            .ok_or_else(|| anyhow!("No ktest output found for benchmark run {}", run))?;
+
+        for module in &test_group.modules {
+            for test in module.tests.iter().filter(|t| t.outcome == TestOutcome::Pass) {
+                samples.entry(format!("{}::{}", module.module, test.test))
+                    .or_default()
+                    .push(test.cycle_count as f64);
+            }
+        }
+        test_group_name = Some(test_group.test_group);
+    }
+
+    let test_group = test_group_name.ok_or_else(|| anyhow!("No test group found after benchmark runs"))?;
+    let baseline_path = get_baseline_path(bench_config)?;
+    let mut baseline = load_baseline(&baseline_path)?;
+
+    let benchmarks = samples.iter()
+        .filter_map(|(name, samples)| {
+            let baseline = baseline.get(&test_group)
+                .and_then(|b| b.benchmarks.iter().find(|bench| &bench.name == name));
+            summarize(name, samples, baseline, bench_config.noise_percent)
+        })
+        .collect();
+
+    let report = BenchReport { test_group, runs: bench_config.runs, benchmarks };
+    let report_path = get_report_path(&report.test_group)?;
+    write_report(&report, &report_path)?;
+    print_report(&report);
+
+    if args::is_save_baseline() {
+        let test_group = report.test_group.clone();
+        baseline.insert(test_group.clone(), report);
+        fs::write(&baseline_path, serde_json::to_string_pretty(&baseline)?)?;
+        println!("kboot: saved benchmark baseline of {} to {}", test_group, baseline_path.display());
+    }
+
+    Ok(())
+}
+
+/// Compute the statistics for the samples of one benchmark and compare its mean
+/// against the baseline, if one exists.
+fn summarize(name: &str, samples: &[f64], baseline: Option<&Benchmark>, noise_percent: f64) -> Option<Benchmark> {
+    let mean = stats::mean(samples)?;
+    let std_dev = stats::std_dev(samples).unwrap_or(0.0);
+
+    // a relative change cannot be computed against a zero mean
+    let comparison = baseline.filter(|baseline| baseline.mean > 0.0).map(|baseline| {
+        let change_percent = (mean - baseline.mean) / baseline.mean * 100.0;
+
+        // the difference must exceed both the noise threshold and two standard errors
+        let standard_error = (std_dev.powi(2) / samples.len() as f64
+            + baseline.std_dev.powi(2) / baseline.samples as f64).sqrt();
+        let significant = change_percent.abs() >= noise_percent && (mean - baseline.mean).abs() > 2.0 * standard_error;
+
+        let verdict = match (significant, change_percent > 0.0) {
+            (false, _) => BenchVerdict::Unchanged,
+            (true, true) => BenchVerdict::Regressed,
+            (true, false) => BenchVerdict::Improved
+        };
+
+        BenchComparison { baseline_mean: baseline.mean, change_percent, verdict }
+    });
+
+    Some(Benchmark {
+        name: name.to_string(),
+        samples: samples.len(),
+        min: samples.iter().cloned().fold(f64::INFINITY, f64::min),
+        max: samples.iter().cloned().fold(f64::NEG_INFINITY, f64::max),
+        median: stats::median(samples)?,
+        mean,
+        std_dev,
+        outliers: stats::outliers(samples),
+        comparison
+    })
+}
+
+/// Print the benchmark statistics to the terminal, similar to criterion.
+fn print_report(report: &BenchReport) {
+    println!("kboot: benchmark results for {} ({} run(s))", report.test_group, report.runs);
+    for bench in &report.benchmarks {
+        println!("    {:<40} cycles: [{:.0} {:.0} {:.0}]  mean: {:.1} ± {:.1}  ({} samples)",
+            bench.name, bench.min, bench.median, bench.max, bench.mean, bench.std_dev, bench.samples);
+
+        if bench.outliers.mild + bench.outliers.severe > 0 {
+            println!("    {:<40} outliers: {} mild, {} severe", "", bench.outliers.mild, bench.outliers.severe);
+        }
+
+        if let Some(comparison) = &bench.comparison {
+            println!("    {:<40} change: {:+.2}% ({:?})", "", comparison.change_percent, comparison.verdict);
+        }
+    }
+}
+
+fn write_report(report: &BenchReport, path: &Path) -> Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    fs::write(path, serde_json::to_string_pretty(report)?)?;
+    log::info!("Wrote benchmark report to {}", path.display());
+    Ok(())
+}
+
+/// Load the benchmark baseline file, or an empty baseline if it does not exist.
+fn load_baseline(path: &Path) -> Result<BenchBaseline> {
+    if !path.exists() {
+        return Ok(BenchBaseline::new());
+    }
+
+    let content = fs::read_to_string(path)?;
+    serde_json::from_str(&content)
+        .map_err(|e| anyhow!("Failed to parse benchmark baseline {}: {}", path.display(), e))
+}
+
+fn get_report_path(test_group: &str) -> Result<PathBuf> {
+    Ok(args::get_workspace_root()?.join(BUILD_DIRECTORY)
+        .join("bench")
+        .join(format!("bench-{}.json", test_group)))
+}
+
+fn get_baseline_path(bench_config: &BenchConfig) -> Result<PathBuf> {
+    Ok(args::get_workspace_root()?.join(&bench_config.baseline_file))
+}
+
+/// BenchReport holds the statistics of all benchmarks in a test group.
+#[derive(Serialize, Deserialize, Debug)]
+struct BenchReport {
+    test_group: String,
+    runs: usize,
+    benchmarks: Vec<Benchmark>
+}
+
+/// Benchmark holds the statistics of the cycle count samples of one test.
+#[derive(Serialize, Deserialize, Debug)]
+struct Benchmark {
+    name: String,
+    samples: usize,
+    min: f64,
+    max: f64,
+    median: f64,
+    mean: f64,
+    std_dev: f64,
+    outliers: Outliers,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    comparison: Option<BenchComparison>
+}
+
+/// BenchComparison holds the change of a benchmark relative to the saved baseline.
+#[derive(Serialize, Deserialize, Debug)]
+struct BenchComparison {
+    baseline_mean: f64,
+    change_percent: f64,
+    verdict: BenchVerdict
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "lowercase")]
+enum BenchVerdict {
+    Unchanged,
+    Improved,
+    Regressed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn when_baseline_mean_is_zero_then_no_comparison_is_made() {
+        let baseline = summarize("kernel::zero", &[0.0, 0.0], None, 5.0).unwrap();
+
+        let bench = summarize("kernel::zero", &[100.0, 110.0], Some(&baseline), 5.0).unwrap();
+
+        assert!(bench.comparison.is_none());
+        assert_eq!(bench.mean, 105.0);
+    }
+
+    #[test]
+    fn when_baseline_file_has_groups_then_each_group_is_read_by_name() {
+        let path = std::env::temp_dir().join(format!("kboot-bench-baseline-{}.json", uuid::Uuid::new_v4()));
+        assert!(load_baseline(&path).unwrap().is_empty());
+
+        let benchmarks = vec![summarize("kernel::alloc", &[100.0, 110.0], None, 5.0).unwrap()];
+        let baseline = BenchBaseline::from([
+            ("kernel".to_string(), BenchReport { test_group: "kernel".to_string(), runs: 2, benchmarks }),
+            ("kernel@smp4".to_string(), BenchReport { test_group: "kernel@smp4".to_string(), runs: 2, benchmarks: vec![] })
+        ]);
+        fs::write(&path, serde_json::to_string_pretty(&baseline).unwrap()).unwrap();
+
+        let loaded = load_baseline(&path).unwrap();
+        assert_eq!(loaded.len(), 2);
+        assert_eq!(loaded["kernel"].benchmarks[0].mean, 105.0);
+
+        fs::remove_file(path).unwrap();
+    }
+}
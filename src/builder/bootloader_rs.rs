@@ -1,4 +1,12 @@
-use crate::{args, builder::{BuildError, BuilderArguments, BuilderBootloader, DiskImageType}};
+use bootloader::{BootConfig, DiskImageBuilder};
+use bootloader_boot_config::LevelFilter;
+use crate::{builder::{BuildError, BuilderArguments, BuilderBootloader, DiskImageType}, config::{BootLogLevel, BootSettings}};
+
+/// Name of the file on the boot partition that holds the kernel command line
+const CMDLINE_FILE_NAME: &str = "cmdline";
+
+/// Name of the ramdisk file on the boot partition, which the bootloader loads into memory
+const RAMDISK_FILE_NAME: &str = "ramdisk";
 
 pub struct BootloaderRsBootloader {
 
@@ -6,32 +14,55 @@ pub struct BootloaderRsBootloader {
 
 impl BuilderBootloader for BootloaderRsBootloader {
     fn create_disk_image(&self, builder_arguments: &BuilderArguments) -> Result<(), BuildError> {
-        if builder_arguments.image_type == DiskImageType::Bios { // maybe a better way to do this?
-            let mut builder_binding = bootloader::BiosBoot::new(&builder_arguments.executable_path);
-            let mut bios_builder = builder_binding.set_boot_config(&builder_arguments.boot_config);
-
-            if args::has_ramdisk() {
-                let ramdisk_path = args::get_ramdisk_path().map_err(|_| BuildError::RamdiskPathInvalid)?;
-                if let Some(path) = ramdisk_path {
-                    bios_builder = bios_builder.set_ramdisk(&path);
-                }
-            }
+        let mut builder = DiskImageBuilder::new(builder_arguments.executable_path.clone());
+        builder.set_boot_config(&builder_arguments.boot_config);
 
-            bios_builder.create_disk_image(&builder_arguments.image_path).unwrap();
-        } else {
-            let mut builder_binding = bootloader::UefiBoot::new(&builder_arguments.executable_path);
-            let mut uefi_builder = builder_binding.set_boot_config(&builder_arguments.boot_config);
+        if let Some(path) = &builder_arguments.ramdisk {
+            builder.set_ramdisk(path.clone());
+        }
 
-            if args::has_ramdisk() {
-                let ramdisk_path = args::get_ramdisk_path().map_err(|_| BuildError::RamdiskPathInvalid)?;
-                if let Some(path) = ramdisk_path {
-                    uefi_builder = uefi_builder.set_ramdisk(&path);
-                }
+        // the bootloader has no command line, so the kernel reads it from a file, which is
+        // also loaded into memory as the ramdisk; a kernel could not tell the two apart
+        if let Some(cmdline) = &builder_arguments.cmdline {
+            if builder_arguments.ramdisk.is_some() {
+                return Err(BuildError::CmdlineWithRamdisk);
             }
-
-            uefi_builder.create_disk_image(&builder_arguments.image_path).unwrap();
+            builder.set_file_contents(CMDLINE_FILE_NAME.to_string(), cmdline.clone().into_bytes());
+            builder.set_file_contents(RAMDISK_FILE_NAME.to_string(), cmdline.clone().into_bytes());
         }
-        
-        Ok(())
+
+        let result = match builder_arguments.image_type {
+            DiskImageType::Bios => builder.create_bios_image(&builder_arguments.image_path),
+            DiskImageType::Uefi => builder.create_uefi_image(&builder_arguments.image_path)
+        };
+        result.map_err(|source| BuildError::DiskImageFailed { path: builder_arguments.image_path.clone(), source })
+    }
+}
+
+/// Apply the `[boot]` settings to the defaults of the bootloader.
+pub fn get_boot_config(settings: &BootSettings) -> BootConfig {
+    let mut boot_config = BootConfig::default();
+    if settings.minimum_framebuffer_width.is_some() {
+        boot_config.frame_buffer.minimum_framebuffer_width = settings.minimum_framebuffer_width;
+    }
+    if settings.minimum_framebuffer_height.is_some() {
+        boot_config.frame_buffer.minimum_framebuffer_height = settings.minimum_framebuffer_height;
+    }
+    if let Some(log_level) = settings.log_level {
+        boot_config.log_level = match log_level {
+            BootLogLevel::Off => LevelFilter::Off,
+            BootLogLevel::Error => LevelFilter::Error,
+            BootLogLevel::Warn => LevelFilter::Warn,
+            BootLogLevel::Info => LevelFilter::Info,
+            BootLogLevel::Debug => LevelFilter::Debug,
+            BootLogLevel::Trace => LevelFilter::Trace
+        };
+    }
+    if let Some(frame_buffer_logging) = settings.frame_buffer_logging {
+        boot_config.frame_buffer_logging = frame_buffer_logging;
+    }
+    if let Some(serial_logging) = settings.serial_logging {
+        boot_config.serial_logging = serial_logging;
     }
+    boot_config
 }
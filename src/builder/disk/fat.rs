@@ -52,24 +52,23 @@ pub fn create_fat_filesystem(
         .create(true)
         .truncate(true)
         .open(out_fat_path)
-        .unwrap();
+        .with_context(|| format!("failed to create FAT filesystem image at `{}`", out_fat_path.display()))?;
     let fat_size_padded_and_rounded = ((needed_size + 1024 * 64 - 1) / MB + 1) * MB + MB;
-    fat_file.set_len(fat_size_padded_and_rounded).unwrap();
+    fat_file.set_len(fat_size_padded_and_rounded)?;
 
     // choose a file system label
     let mut label = *b"MY_RUST_OS!";
 
     // This __should__ always be a file, but maybe not. Should we allow the caller to set the volume label instead?
-    if let Some(FileDataSource::File(path)) = files.get(KERNEL_FILE_NAME) {
-        if let Some(name) = path.file_stem() {
-            let converted = name.to_string_lossy();
-            let name = converted.as_bytes();
-            let mut new_label = [0u8; 11];
-            let name = &name[..usize::min(new_label.len(), name.len())];
-            let slice = &mut new_label[..name.len()];
-            slice.copy_from_slice(name);
-            label = new_label;
-        }
+    if let Some(FileDataSource::File(path)) = files.get(KERNEL_FILE_NAME)
+        && let Some(name) = path.file_stem() {
+        let converted = name.to_string_lossy();
+        let name = converted.as_bytes();
+        let mut new_label = [0u8; 11];
+        let name = &name[..usize::min(new_label.len(), name.len())];
+        let slice = &mut new_label[..name.len()];
+        slice.copy_from_slice(name);
+        label = new_label;
     }
 
     // format the file system and open it
@@ -109,7 +108,7 @@ pub fn add_files_to_image(
         let mut new_file = root_dir
             .create_file(&normalized_path)
             .with_context(|| format!("failed to create file at `{}`", target_path.display()))?;
-        new_file.truncate().unwrap();
+        new_file.truncate()?;
 
         source.copy_to(&mut new_file).with_context(|| {
             format!(
@@ -1,20 +1,29 @@
-use std::{collections::BTreeMap, fs::{self, read_dir}, path::Path, process::Command};
-use crate::{BUILD_DIRECTORY, args::{self, get_workspace_root}, builder::{BuildError, BuilderArguments, BuilderBootloader, DiskImageType, disk::{file_data_source::FileDataSource, gpt}}};
+use std::{collections::BTreeMap, fs::{self, read_dir}, path::{Path, PathBuf}, process::Command};
+use crate::{args, builder::{BuildError, BuilderArguments, BuilderBootloader, DiskImageType, disk::{file_data_source::FileDataSource, gpt}, preflight}, config, console};
+
+/// Path of the kernel inside the image, as written in limine.conf (`${KERNEL}`)
+const KERNEL_PATH: &str = "boot():/boot/kernel/kernel";
 
 pub struct LimineBootloader;
 
 impl BuilderBootloader for LimineBootloader {
     fn create_disk_image(&self, builder_args: &BuilderArguments) -> Result<(), BuildError> {
         if builder_args.image_type == DiskImageType::Bios {
-            panic!("Limine bootloader does not support BIOS booting (UEFI only).");
+            return Err(BuildError::LimineBiosUnsupported);
         }
 
-        setup_limine_root(&builder_args)?;
-        clone_limine_repo(&builder_args)?;
-        setup_limine_conf(&builder_args)?;
-        setup_limine_bios(&builder_args)?;
+        setup_limine_root(builder_args)?;
+        clone_limine_repo(builder_args)?;
+        let modules = setup_limine_modules(builder_args)?;
+        setup_limine_conf(builder_args, &modules)?;
+        setup_limine_bios(builder_args)?;
+        setup_limine_kernel(builder_args)?;
+
+        // Limine hangs or reboots on these problems, so they are reported before QEMU starts
+        let iso_root = builder_args.build_directory.join("iso_root");
+        preflight::check_limine_image(&iso_root, &iso_root.join("boot").join("kernel").join("kernel"))?;
 
-        build_limine_image(&builder_args)
+        build_limine_image(builder_args)
     }
 }
 
@@ -24,10 +33,11 @@ fn setup_limine_root(builder_args: &BuilderArguments) -> Result<(), BuildError>
     let limine_root = builder_args.build_directory.join("iso_root");
 
     if limine_root.exists() {
-        fs::remove_dir_all(&limine_root).map_err(|_| BuildError::DirectoryCreationFailed)?;
+        fs::remove_dir_all(&limine_root)
+            .map_err(|source| BuildError::DirectoryCreationFailed { path: limine_root.clone(), source })?;
     }
 
-    fs::create_dir_all(&limine_root).map_err(|_| BuildError::DirectoryCreationFailed)
+    create_dir_all(&limine_root)
 }
 
 fn clone_limine_repo(builder_args: &BuilderArguments) -> Result<(), BuildError> {
@@ -43,29 +53,135 @@ fn clone_limine_repo(builder_args: &BuilderArguments) -> Result<(), BuildError>
 
     log::info!("Cloning Limine repository from {} (branch: {})...", URL, BRANCH);
 
-    let repo = match git2::build::RepoBuilder::new().branch(BRANCH).clone(URL, &path) {
-        Ok(repo) => repo,
-        Err(e) => panic!("failed to clone: {}", e),
-    };
-
-    let head = repo.head().map_err(|_| BuildError::CloneLimineBinaryFailed)?;
-    let head_id = head.target().unwrap();
-    let head_commit = repo.find_commit(head_id).map_err(|_| BuildError::CloneLimineBinaryFailed)?;
+    let clone_error = |source| BuildError::CloneLimineBinaryFailed { url: URL, path: path.clone(), source };
+    let repo = git2::build::RepoBuilder::new().branch(BRANCH).clone(URL, &path).map_err(clone_error)?;
+    let head_commit = repo.head()
+        .and_then(|head| head.peel_to_commit())
+        .map_err(clone_error)?;
 
     log::info!("Cloned Limine repository at commit {}", head_commit.id());
     Ok(())
 }
 
-fn setup_limine_conf(builder_args: &BuilderArguments) -> Result<(), BuildError> {
+/// Get the limine.conf configured under `[limine]`, or find one in the workspace.
+///
+/// Returns `None` if there is none, in which case one is generated.
+pub fn find_limine_conf() -> Result<Option<PathBuf>, BuildError> {
+    let config = config::get_config().map_err(BuildError::ConfigInvalid)?;
+    if let Some(conf) = &config.limine.conf {
+        let path = args::get_workspace_root().map_err(BuildError::ArgumentsInvalid)?.join(conf);
+        if !path.is_file() {
+            return Err(BuildError::LimineConfNotFound { searched: path });
+        }
+        return Ok(Some(path));
+    }
+
+    args::find_limine_conf().map_err(BuildError::ArgumentsInvalid)
+}
+
+/// Copy the ramdisk into the image as a module, and return the paths of the
+/// modules as written in limine.conf.
+fn setup_limine_modules(builder_args: &BuilderArguments) -> Result<Vec<String>, BuildError> {
+    let Some(ramdisk) = &builder_args.ramdisk else {
+        return Ok(Vec::new());
+    };
+
+    let modules_dir = builder_args.build_directory.join("iso_root").join("boot").join("modules");
+    let file_name = ramdisk.file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_else(|| "ramdisk".to_string());
+
+    create_dir_all(&modules_dir)?;
+    copy_file(ramdisk, &modules_dir.join(&file_name))?;
+    Ok(vec![format!("boot():/boot/modules/{}", file_name)])
+}
+
+fn setup_limine_conf(builder_args: &BuilderArguments, modules: &[String]) -> Result<(), BuildError> {
     log::info!("Setting up limine.conf for Limine...");
 
-    let limine_conf_src = args::get_limine_conf().map_err(|_| BuildError::LimineConfNotFound)?;
-    let limine_conf_dst = builder_args.build_directory.join("iso_root").join("boot").join("limine").join("limine.conf");
+    let limine_conf_dir = builder_args.build_directory.join("iso_root").join("boot").join("limine");
+    let limine_conf_dst = limine_conf_dir.join("limine.conf");
+    create_dir_all(&limine_conf_dir)?;
 
-    fs::create_dir_all(limine_conf_dst.parent().unwrap()).unwrap();
-    fs::copy(limine_conf_src, limine_conf_dst).unwrap();
+    let Some(limine_conf_src) = &builder_args.limine_conf else {
+        log::info!("No limine.conf found, generating one");
+        return fs::write(&limine_conf_dst, generate_limine_conf(builder_args, modules))
+            .map_err(|source| BuildError::FileWriteFailed { path: limine_conf_dst.clone(), source });
+    };
 
-    Ok(())
+    let copy_error = |source| BuildError::FileCopyFailed { from: limine_conf_src.clone(), to: limine_conf_dst.clone(), source };
+    let limine_conf = fs::read_to_string(limine_conf_src).map_err(copy_error)?;
+    fs::write(&limine_conf_dst, apply_variables(&limine_conf, builder_args.cmdline.as_deref())).map_err(copy_error)
+}
+
+/// Generate a limine.conf that boots the kernel right away, with its command line and modules.
+fn generate_limine_conf(builder_args: &BuilderArguments, modules: &[String]) -> String {
+    let name = builder_args.executable_path.file_stem()
+        .map(|stem| stem.to_string_lossy().to_string())
+        .unwrap_or_else(|| "kernel".to_string());
+
+    let mut limine_conf = format!("timeout: 0\n\n/{}\n    protocol: limine\n    path: {}\n", name, KERNEL_PATH);
+    if let Some(cmdline) = &builder_args.cmdline {
+        limine_conf.push_str(&format!("    cmdline: {}\n", cmdline));
+    }
+    for module in modules {
+        limine_conf.push_str(&format!("    module_path: {}\n", module));
+    }
+
+    limine_conf
+}
+
+/// Substitute `${KERNEL}` and `${CMDLINE}` in a limine.conf. A file that does not
+/// place the command line itself gets it added to every entry.
+fn apply_variables(limine_conf: &str, cmdline: Option<&str>) -> String {
+    let limine_conf = limine_conf.replace("${KERNEL}", KERNEL_PATH);
+    match cmdline {
+        Some(cmdline) if !limine_conf.contains("${CMDLINE}") => apply_cmdline(&limine_conf, cmdline),
+        cmdline => limine_conf.replace("${CMDLINE}", cmdline.unwrap_or_default())
+    }
+}
+
+/// Add the command line to every boot entry of a limine.conf, after the
+/// command line that the entry already has, if any.
+fn apply_cmdline(limine_conf: &str, cmdline: &str) -> String {
+    let mut lines = Vec::new();
+    // index of the current entry's header, and whether it boots a kernel and has a command line
+    let mut entry: Option<(usize, bool, bool)> = None;
+
+    for line in limine_conf.lines() {
+        let trimmed = line.trim();
+        if trimmed.starts_with('/') {
+            insert_cmdline(&mut lines, entry.take(), cmdline);
+            entry = Some((lines.len(), false, false));
+        } else if let Some((_, has_path, has_cmdline)) = entry.as_mut()
+            && let Some((key, value)) = trimmed.split_once(':') {
+            match key.trim().to_ascii_lowercase().as_str() {
+                "path" | "kernel_path" => *has_path = true,
+                "cmdline" | "kernel_cmdline" => {
+                    *has_cmdline = true;
+                    let indent = &line[..line.len() - line.trim_start().len()];
+                    let value = [value.trim(), cmdline].join(" ");
+                    lines.push(format!("{}{}: {}", indent, key.trim(), value.trim()));
+                    continue;
+                },
+                _ => {}
+            }
+        }
+        lines.push(line.to_string());
+    }
+    insert_cmdline(&mut lines, entry, cmdline);
+
+    lines.join("\n") + "\n"
+}
+
+/// Add a `cmdline` option below the header of an entry that boots a kernel without one.
+fn insert_cmdline(lines: &mut Vec<String>, entry: Option<(usize, bool, bool)>, cmdline: &str) {
+    if let Some((header, true, false)) = entry {
+        let indent = lines.get(header + 1)
+            .map(|line| line[..line.len() - line.trim_start().len()].to_string())
+            .unwrap_or_else(|| "    ".to_string());
+        lines.insert(header + 1, format!("{}cmdline: {}", indent, cmdline));
+    }
 }
 
 fn setup_limine_bios(builder_args: &BuilderArguments) -> Result<(), BuildError> {
@@ -77,12 +193,12 @@ fn setup_limine_bios(builder_args: &BuilderArguments) -> Result<(), BuildError>
         "limine-uefi-cd.bin"
     ];
 
-    fs::create_dir_all(builder_args.build_directory.join("iso_root").join("boot").join("limine")).map_err(|_| BuildError::DirectoryCreationFailed)?;
+    create_dir_all(&builder_args.build_directory.join("iso_root").join("boot").join("limine"))?;
     for file in BIOS_FILES.iter() {
         let src = builder_args.build_directory.join("limine").join(file);
         let dst = builder_args.build_directory.join("iso_root").join("boot").join("limine").join(file);
 
-        fs::copy(src, dst).unwrap();
+        copy_file(&src, &dst)?;
     }
 
     const EFI_FILES : [&str; 2] = [
@@ -90,36 +206,40 @@ fn setup_limine_bios(builder_args: &BuilderArguments) -> Result<(), BuildError>
         "BOOTIA32.EFI"
     ];
 
-    fs::create_dir_all(builder_args.build_directory.join("iso_root").join("EFI").join("BOOT")).map_err(|_| BuildError::DirectoryCreationFailed)?;
+    create_dir_all(&builder_args.build_directory.join("iso_root").join("EFI").join("BOOT"))?;
     for file in EFI_FILES.iter() {
         let src = builder_args.build_directory.join("limine").join(file);
         let dst = builder_args.build_directory.join("iso_root").join("EFI").join("BOOT").join(file);
 
-        fs::copy(src, dst).unwrap();
+        copy_file(&src, &dst)?;
     }
 
     Ok(())
 }
 
-fn build_limine_image(builder_args: &BuilderArguments) -> Result<(), BuildError> {
+fn setup_limine_kernel(builder_args: &BuilderArguments) -> Result<(), BuildError> {
     let executable_src = &builder_args.executable_path;
-    let executable_dst = builder_args.build_directory.join("iso_root").join("boot").join("kernel").join("kernel");
+    let executable_dir = builder_args.build_directory.join("iso_root").join("boot").join("kernel");
 
-    fs::create_dir_all(executable_dst.parent().unwrap()).map_err(|_| BuildError::DirectoryCreationFailed)?;
-    fs::copy(executable_src, executable_dst).unwrap();
+    create_dir_all(&executable_dir)?;
+    copy_file(executable_src, &executable_dir.join("kernel"))
+}
 
+fn build_limine_image(builder_args: &BuilderArguments) -> Result<(), BuildError> {
     let iso_root = builder_args.build_directory.join("iso_root");
-    let output_image = builder_args.build_directory.join("kernel.img");
+    let output_image = &builder_args.image_path;
     
     log::info!("Creating disk image at {:?}", output_image);
 
     let mut internal_files = BTreeMap::new();
     let mut dirs_to_process = vec![iso_root.clone()];
     while let Some(current_dir) = dirs_to_process.pop() {
-        for entry in read_dir(&current_dir).map_err(|_| BuildError::DirectoryReadFailed)? {
-            let entry = entry.map_err(|_| BuildError::DirectoryReadFailed)?;
+        let read_error = |source| BuildError::DirectoryReadFailed { path: current_dir.clone(), source };
+        for entry in read_dir(&current_dir).map_err(read_error)? {
+            let entry = entry.map_err(read_error)?;
             let path = entry.path();
-            let relative_path = path.strip_prefix(&iso_root).map_err(|_| BuildError::PathPrefixFailed)?;
+            let relative_path = path.strip_prefix(&iso_root)
+                .map_err(|_| BuildError::PathPrefixFailed { path: path.clone() })?;
 
             if path.is_dir() {
                 dirs_to_process.push(path);
@@ -130,39 +250,44 @@ fn build_limine_image(builder_args: &BuilderArguments) -> Result<(), BuildError>
         }
     }
 
-    let fat_partition = crate::builder::disk::fat::create_fat_filesystem_image(BTreeMap::new(), internal_files).unwrap();
-    gpt::create_gpt_disk(&fat_partition.path(), output_image.as_path()).unwrap();
+    let image_error = |source| BuildError::DiskImageFailed { path: output_image.clone(), source };
+    let fat_partition = crate::builder::disk::fat::create_fat_filesystem_image(BTreeMap::new(), internal_files)
+        .map_err(image_error)?;
+    gpt::create_gpt_disk(fat_partition.path(), output_image.as_path()).map_err(image_error)?;
     
     // install Limine bootloader
-    install_limine(&output_image).unwrap();
+    install_limine(output_image, &builder_args.build_directory.join("limine"))
+        .map_err(|source| BuildError::LimineInstallFailed { image: output_image.clone(), source })?;
 
     // print size of image
-    let metadata = fs::metadata(&output_image).unwrap();
-    log::info!("Disk image size: {} bytes", metadata.len());
-
-    // print hash of image
-    use sha2::{Sha256, Digest};
-    let mut file = fs::File::open(&output_image).unwrap();
-    let mut hasher = Sha256::new();
-    std::io::copy(&mut file, &mut hasher).unwrap();
-    let hash = hasher.finalize();
-    log::info!("Disk image SHA-256 hash: {:x}", hash);
-
-    fat_partition
-        .close().unwrap();
+    if let Ok(metadata) = fs::metadata(output_image) {
+        log::info!("Disk image size: {} bytes", metadata.len());
+    }
+
+    fat_partition.close().map_err(|e| image_error(e.into()))?;
     
     Ok(())
 }
 
-fn install_limine(disk_image: &Path) -> std::io::Result<()> {
+fn create_dir_all(path: &Path) -> Result<(), BuildError> {
+    fs::create_dir_all(path).map_err(|source| BuildError::DirectoryCreationFailed { path: path.to_path_buf(), source })
+}
+
+fn copy_file(from: &Path, to: &Path) -> Result<(), BuildError> {
+    fs::copy(from, to)
+        .map(|_| ())
+        .map_err(|source| BuildError::FileCopyFailed { from: from.to_path_buf(), to: to.to_path_buf(), source })
+}
+
+fn install_limine(disk_image: &Path, limine_directory: &Path) -> std::io::Result<()> {
     let is_windows = cfg!(target_os = "windows");
     let limine_executable = if is_windows {
         "limine.exe"
     } else {
         "limine"
     };
-    let limine_path = get_workspace_root().unwrap().join(BUILD_DIRECTORY).join("limine").join(limine_executable);
-    println!("Installing Limine bootloader using binary at {}", limine_path.display());
+    let limine_path = limine_directory.join(limine_executable);
+    console::print_human(&format!("Installing Limine bootloader using binary at {}", limine_path.display()));
     log::info!("Installing Limine bootloader using binary at {}", limine_path.display());
 
     if !is_windows && !limine_path.exists() {
@@ -171,7 +296,7 @@ fn install_limine(disk_image: &Path) -> std::io::Result<()> {
         let make_check = Command::new("make")
             .arg("--version")
             .output();
-        if make_check.is_err() || !make_check.unwrap().status.success() {
+        if !make_check.is_ok_and(|output| output.status.success()) {
             log::warn!("Make tool not found. Attempting to install make...");
             #[cfg(target_os = "linux")]
             {
@@ -182,8 +307,7 @@ fn install_limine(disk_image: &Path) -> std::io::Result<()> {
                     .arg("make")
                     .output()?;
                 if !install_make.status.success() {
-                    return Err(std::io::Error::new(
-                        std::io::ErrorKind::Other,
+                    return Err(std::io::Error::other(
                         "Failed to install make tool"
                     ));
                 }
@@ -206,7 +330,7 @@ fn install_limine(disk_image: &Path) -> std::io::Result<()> {
             let make_check = Command::new("make")
                 .arg("--version")
                 .output();
-            if make_check.is_err() || !make_check.unwrap().status.success() {
+            if !make_check.is_ok_and(|output| output.status.success()) {
                 return Err(std::io::Error::new(
                     std::io::ErrorKind::NotFound,
                     "Make tool not found after installation attempt"
@@ -215,12 +339,11 @@ fn install_limine(disk_image: &Path) -> std::io::Result<()> {
         }
 
         let build_output = Command::new("make")
-            .current_dir(limine_path.parent().unwrap())
+            .current_dir(limine_directory)
             .output()?;
         if !build_output.status.success() {
             eprintln!("Failed to build Limine with make: {}", String::from_utf8_lossy(&build_output.stderr));
-            return Err(std::io::Error::new(
-                std::io::ErrorKind::Other,
+            return Err(std::io::Error::other(
                 "Failed to build Limine with make"
             ));
         }
@@ -247,8 +370,7 @@ fn install_limine(disk_image: &Path) -> std::io::Result<()> {
     
     if !output.status.success() {
         eprintln!("Limine installation failed: {}", String::from_utf8_lossy(&output.stderr));
-        return Err(std::io::Error::new(
-            std::io::ErrorKind::Other,
+        return Err(std::io::Error::other(
             "Limine installation failed"
         ));
     }
@@ -256,3 +378,45 @@ fn install_limine(disk_image: &Path) -> std::io::Result<()> {
     log::info!("Limine bootloader installed successfully!");
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn when_cmdline_is_applied_then_each_kernel_entry_gets_it() {
+        let limine_conf = [
+            "timeout: 0",
+            "/kernel",
+            "    protocol: limine",
+            "    path: boot():/boot/kernel/kernel",
+            "/kernel (verbose)",
+            "    protocol: limine",
+            "    cmdline: verbose",
+            "    path: boot():/boot/kernel/kernel"
+        ].join("\n");
+
+        let applied = apply_cmdline(&limine_conf, "seed=42");
+
+        assert_eq!(applied, [
+            "timeout: 0",
+            "/kernel",
+            "    cmdline: seed=42",
+            "    protocol: limine",
+            "    path: boot():/boot/kernel/kernel",
+            "/kernel (verbose)",
+            "    protocol: limine",
+            "    cmdline: verbose seed=42",
+            "    path: boot():/boot/kernel/kernel",
+            ""
+        ].join("\n"));
+    }
+
+    #[test]
+    fn when_variables_are_used_then_cmdline_is_not_added_again() {
+        let limine_conf = "/kernel\n    path: ${KERNEL}\n    cmdline: quiet ${CMDLINE}\n";
+
+        assert_eq!(apply_variables(limine_conf, Some("seed=42")), "/kernel\n    path: boot():/boot/kernel/kernel\n    cmdline: quiet seed=42\n");
+        assert_eq!(apply_variables(limine_conf, None), "/kernel\n    path: boot():/boot/kernel/kernel\n    cmdline: quiet \n");
+    }
+}
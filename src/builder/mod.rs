@@ -1,27 +1,168 @@
-use std::{fs, path::PathBuf};
+use std::{fmt, fs, io, path::{Path, PathBuf}};
 use anyhow::Result;
 use bootloader::BootConfig;
-use crate::{args::{self, BootloaderSelection}, BUILD_DIRECTORY};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use crate::{args::{self, BootloaderSelection}, config, matrix, BUILD_DIRECTORY};
+
+/// Name of the file describing the last built image, inside the build directory
+const MANIFEST_FILE_NAME: &str = "manifest.json";
 
 pub mod disk;
 pub mod bootloader_rs;
 pub mod limine;
+pub mod preflight;
 
 /// Build a legacy or UEFI disk image (*.img) that contains the specified executable.
 pub fn build_image() -> Result<(), BuildError> {
-    let builder_args = BuilderArguments::default().map_err(|_| BuildError::DirectoryCreationFailed)?;
+    get_image_builder()?.build()?;
+    Ok(())
+}
+
+/// Configure the image for the executable that kboot was invoked with.
+fn get_image_builder() -> Result<ImageBuilder, BuildError> {
+    let build_directory = args::get_build_directory().map_err(BuildError::ArgumentsInvalid)?;
+    let executable_path = args::get_executable().map_err(BuildError::ArgumentsInvalid)?;
+    let is_test = args::is_test().map_err(BuildError::ArgumentsInvalid)?;
+    let boot_settings = config::get_config().map_err(BuildError::ConfigInvalid)?.boot.get_settings(is_test);
+    let bootloader = args::get_bootloader_selection();
+
+    let image_type = if matrix::is_legacy_boot() {
+        DiskImageType::Bios
+    } else {
+        DiskImageType::Uefi
+    };
+
+    let mut image_builder = ImageBuilder::new(executable_path)
+        .build_directory(&build_directory)
+        .image_path(build_directory.join("kernel.img"))
+        .boot_config(bootloader_rs::get_boot_config(&boot_settings))
+        .image_type(image_type)
+        .bootloader(bootloader);
+
+    if let Some(ramdisk) = args::get_ramdisk_path().map_err(BuildError::RamdiskPathInvalid)? {
+        image_builder = image_builder.ramdisk(ramdisk);
+    }
+    if let Some(cmdline) = args::get_cmdline().or(boot_settings.cmdline) {
+        image_builder = image_builder.cmdline(cmdline);
+    }
+
+    // the workspace is only searched if the configuration is needed
+    if bootloader == BootloaderSelection::Limine && let Some(limine_conf) = limine::find_limine_conf()? {
+        image_builder = image_builder.limine_conf(limine_conf);
+    }
+
+    Ok(image_builder)
+}
 
-    fs::create_dir_all(&builder_args.build_directory).map_err(|_| BuildError::DirectoryCreationFailed)?;
+/// Build the disk image described by the arguments and write its manifest.
+fn build(builder_args: &BuilderArguments) -> Result<Manifest, BuildError> {
+    fs::create_dir_all(&builder_args.build_directory)
+        .map_err(|source| BuildError::DirectoryCreationFailed { path: builder_args.build_directory.clone(), source })?;
 
-    let bootloader: Box<dyn BuilderBootloader> = match args::get_bootloader_selection() {
+    let bootloader: Box<dyn BuilderBootloader> = match builder_args.bootloader {
         BootloaderSelection::BootloaderCrate => Box::new(bootloader_rs::BootloaderRsBootloader {}),
         BootloaderSelection::Limine => Box::new(limine::LimineBootloader {}),
     };
-    bootloader.create_disk_image(&builder_args)?;
+    bootloader.create_disk_image(builder_args)?;
+    write_manifest(builder_args)
+}
 
-    Ok(())
+/// Write the manifest of the image that was just built, so later steps
+/// (e.g. snapshots) can tell whether the image changed.
+fn write_manifest(builder_args: &BuilderArguments) -> Result<Manifest, BuildError> {
+    let path = builder_args.build_directory.join(MANIFEST_FILE_NAME);
+    let manifest_error = |source: anyhow::Error| BuildError::ManifestWriteFailed { path: path.clone(), source };
+
+    // Limine is configured through limine.conf instead
+    let boot_config = match builder_args.bootloader {
+        BootloaderSelection::BootloaderCrate => Some(serde_json::to_value(&builder_args.boot_config).map_err(|e| manifest_error(e.into()))?),
+        BootloaderSelection::Limine => None
+    };
+
+    let manifest = Manifest {
+        executable: builder_args.executable_path.clone(),
+        image: builder_args.image_path.clone(),
+        image_sha256: hash_file(&builder_args.image_path).map_err(manifest_error)?,
+        inputs_sha256: hash_inputs(builder_args).map_err(manifest_error)?,
+        image_type: format!("{:?}", builder_args.image_type),
+        bootloader: format!("{:?}", builder_args.bootloader),
+        boot_config,
+        cmdline: builder_args.cmdline.clone()
+    };
+    log::info!("Disk image SHA-256 hash: {}", manifest.image_sha256);
+    if let Some(boot_config) = &manifest.boot_config {
+        log::info!("Boot config: {}", boot_config);
+    }
+    if let Some(cmdline) = &manifest.cmdline {
+        log::info!("Kernel command line: {}", cmdline);
+    }
+
+    let content = serde_json::to_string_pretty(&manifest).map_err(|e| manifest_error(e.into()))?;
+    fs::write(&path, content).map_err(|e| manifest_error(e.into()))?;
+
+    Ok(manifest)
+}
+
+/// Read the manifest of the last built image.
+pub fn read_manifest() -> Result<Manifest> {
+    let path = args::get_build_directory()?.join(MANIFEST_FILE_NAME);
+    Ok(serde_json::from_str(&fs::read_to_string(path)?)?)
+}
+
+/// Hash everything the image is built from. Building the same inputs twice gives
+/// different images, since their partition GUIDs are random, but the same hash.
+fn hash_inputs(builder_args: &BuilderArguments) -> Result<String> {
+    let mut hasher = Sha256::new();
+    let mut add = |value: &[u8]| {
+        hasher.update(value);
+        hasher.update([0]);
+    };
+
+    add(hash_file(&builder_args.executable_path)?.as_bytes());
+    add(format!("{:?} {:?}", builder_args.bootloader, builder_args.image_type).as_bytes());
+    // Limine is configured through limine.conf instead
+    if builder_args.bootloader == BootloaderSelection::BootloaderCrate {
+        add(&serde_json::to_vec(&builder_args.boot_config)?);
+    }
+    add(builder_args.cmdline.as_deref().unwrap_or_default().as_bytes());
+    for file in [&builder_args.ramdisk, &builder_args.limine_conf] {
+        match file {
+            Some(path) => add(hash_file(path)?.as_bytes()),
+            None => add(&[])
+        }
+    }
+
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+fn hash_file(path: &Path) -> Result<String> {
+    let mut file = fs::File::open(path)?;
+    let mut hasher = Sha256::new();
+    std::io::copy(&mut file, &mut hasher)?;
+    Ok(format!("{:x}", hasher.finalize()))
 }
 
+/// Manifest describes the last built image.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Manifest {
+    pub executable: PathBuf,
+    pub image: PathBuf,
+    pub image_sha256: String,
+    /// Hash of the kernel and the boot inputs (bootloader, its configuration, command line,
+    /// ramdisk and limine.conf), which is the same for every build of them
+    #[serde(default)]
+    pub inputs_sha256: String,
+    pub image_type: String,
+    pub bootloader: String,
+    /// The `BootConfig` of the bootloader crate, as passed to the bootloader
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub boot_config: Option<serde_json::Value>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cmdline: Option<String>
+}
+
+/// Firmware the disk image boots with
 #[derive(Debug, Copy, Clone, PartialEq)]
 pub enum DiskImageType {
     Uefi,
@@ -37,39 +178,212 @@ pub struct BuilderArguments {
     pub build_directory: PathBuf,
     pub image_path: PathBuf,
     pub boot_config: BootConfig,
-    pub image_type: DiskImageType
-}
-
-impl BuilderArguments {
-    fn default() -> Result<Self> {
-        let workspace_directory = args::get_workspace_root()?;
-        let build_directory = workspace_directory.join(BUILD_DIRECTORY);
-        let image_path = build_directory.join("kernel.img");
-        let executable_path = args::get_executable()?;
-        let boot_config = BootConfig::default();
-
-        let image_type = if args::is_legacy_boot() {
-            DiskImageType::Bios
-        } else {
-            DiskImageType::Uefi
-        };
-
-        Ok(Self {
-            executable_path,
-            build_directory,
+    pub image_type: DiskImageType,
+    pub bootloader: BootloaderSelection,
+    pub ramdisk: Option<PathBuf>,
+    /// Used by Limine, which generates one if not set
+    pub limine_conf: Option<PathBuf>,
+    /// Command line passed to the kernel
+    pub cmdline: Option<String>
+}
+
+/// Builds a bootable disk image for a kernel ELF, without reading the command line
+/// or `kboot.toml`. The image is written to `kernel.img` in the build directory,
+/// next to the files of the bootloader and a manifest describing the image.
+///
+/// ```no_run
+/// use kboot::{BootloaderSelection, ImageBuilder};
+///
+/// let manifest = ImageBuilder::new("target/x86_64-unknown-none/debug/kernel")
+///     .build_directory("target/kboot")
+///     .bootloader(BootloaderSelection::Limine)
+///     .limine_conf("kernel/limine.conf")
+///     .build()?;
+/// println!("built {}", manifest.image.display());
+/// # Ok::<(), kboot::BuildError>(())
+/// ```
+pub struct ImageBuilder {
+    executable_path: PathBuf,
+    build_directory: PathBuf,
+    image_path: Option<PathBuf>,
+    boot_config: BootConfig,
+    image_type: DiskImageType,
+    bootloader: BootloaderSelection,
+    ramdisk: Option<PathBuf>,
+    limine_conf: Option<PathBuf>,
+    cmdline: Option<String>
+}
+
+impl ImageBuilder {
+    /// Build a UEFI image with the bootloader crate in `.build`, relative to the current directory.
+    pub fn new(executable_path: impl Into<PathBuf>) -> Self {
+        Self {
+            executable_path: executable_path.into(),
+            build_directory: PathBuf::from(BUILD_DIRECTORY),
+            image_path: None,
+            boot_config: BootConfig::default(),
+            image_type: DiskImageType::Uefi,
+            bootloader: BootloaderSelection::BootloaderCrate,
+            ramdisk: None,
+            limine_conf: None,
+            cmdline: None
+        }
+    }
+
+    pub fn build_directory(mut self, build_directory: impl Into<PathBuf>) -> Self {
+        self.build_directory = build_directory.into();
+        self
+    }
+
+    /// Write the image somewhere else than `kernel.img` in the build directory.
+    pub fn image_path(mut self, image_path: impl Into<PathBuf>) -> Self {
+        self.image_path = Some(image_path.into());
+        self
+    }
+
+    pub fn boot_config(mut self, boot_config: BootConfig) -> Self {
+        self.boot_config = boot_config;
+        self
+    }
+
+    pub fn image_type(mut self, image_type: DiskImageType) -> Self {
+        self.image_type = image_type;
+        self
+    }
+
+    pub fn bootloader(mut self, bootloader: BootloaderSelection) -> Self {
+        self.bootloader = bootloader;
+        self
+    }
+
+    pub fn ramdisk(mut self, ramdisk: impl Into<PathBuf>) -> Self {
+        self.ramdisk = Some(ramdisk.into());
+        self
+    }
+
+    /// The `limine.conf` copied into the image; if not set, one is generated for Limine.
+    pub fn limine_conf(mut self, limine_conf: impl Into<PathBuf>) -> Self {
+        self.limine_conf = Some(limine_conf.into());
+        self
+    }
+
+    /// Pass a command line to the kernel, see the Kernel Command Line section of the README.
+    /// The bootloader crate passes it as the ramdisk, so it cannot be combined with a ramdisk.
+    pub fn cmdline(mut self, cmdline: impl Into<String>) -> Self {
+        self.cmdline = Some(cmdline.into());
+        self
+    }
+
+    /// Build the image and return its manifest, which names the image and its hash.
+    pub fn build(self) -> Result<Manifest, BuildError> {
+        let image_path = self.image_path.unwrap_or_else(|| self.build_directory.join("kernel.img"));
+
+        build(&BuilderArguments {
+            executable_path: self.executable_path,
+            build_directory: self.build_directory,
             image_path,
-            boot_config,
-            image_type
+            boot_config: self.boot_config,
+            image_type: self.image_type,
+            bootloader: self.bootloader,
+            ramdisk: self.ramdisk,
+            limine_conf: self.limine_conf,
+            cmdline: self.cmdline
         })
     }
 }
 
+/// Errors while building the disk image. Each variant names the path it is
+/// about, and keeps the underlying error as its source.
 #[derive(Debug)]
 pub enum BuildError {
-    DirectoryCreationFailed,
-    CloneLimineBinaryFailed,
-    RamdiskPathInvalid,
-    LimineConfNotFound,
-    DirectoryReadFailed,
-    PathPrefixFailed
-}
\ No newline at end of file
+    /// The executable or build directory could not be determined from the arguments
+    ArgumentsInvalid(anyhow::Error),
+    /// `kboot.toml` could not be read
+    ConfigInvalid(anyhow::Error),
+    DirectoryCreationFailed { path: PathBuf, source: io::Error },
+    DirectoryReadFailed { path: PathBuf, source: io::Error },
+    FileCopyFailed { from: PathBuf, to: PathBuf, source: io::Error },
+    FileReadFailed { path: PathBuf, source: io::Error },
+    FileWriteFailed { path: PathBuf, source: io::Error },
+    CloneLimineBinaryFailed { url: &'static str, path: PathBuf, source: git2::Error },
+    /// Building Limine from source or installing it to the image failed
+    LimineInstallFailed { image: PathBuf, source: io::Error },
+    LimineBiosUnsupported,
+    /// The image would not boot with Limine, for each of the given reasons
+    LimineCheckFailed { problems: Vec<String> },
+    RamdiskPathInvalid(anyhow::Error),
+    /// The bootloader crate passes the command line as the ramdisk, so it cannot pass both
+    CmdlineWithRamdisk,
+    /// The limine.conf configured under `[limine]` does not exist
+    LimineConfNotFound { searched: PathBuf },
+    PathPrefixFailed { path: PathBuf },
+    DiskImageFailed { path: PathBuf, source: anyhow::Error },
+    ManifestWriteFailed { path: PathBuf, source: anyhow::Error }
+}
+
+impl BuildError {
+    /// A suggestion for fixing the error, if there is a common cause
+    pub fn hint(&self) -> Option<String> {
+        match self {
+            BuildError::ArgumentsInvalid(_) => Some("kboot must be run by cargo with the executable as its last argument (`runner = \"kboot\"` in .cargo/config.toml)".to_string()),
+            BuildError::ConfigInvalid(_) => Some("check the syntax of kboot.toml, see the Configuration section of the kboot README".to_string()),
+            BuildError::DirectoryCreationFailed { .. } | BuildError::FileCopyFailed { .. } | BuildError::FileWriteFailed { .. } =>
+                Some("check that the workspace is writable and the disk is not full, or start over with `kboot clean`".to_string()),
+            BuildError::CloneLimineBinaryFailed { path, .. } =>
+                Some(format!("check the network connection, or clone the v10.x-binary branch of Limine into {} manually", path.display())),
+            BuildError::LimineInstallFailed { .. } => Some("Limine is built from source, which requires `make` and a C compiler".to_string()),
+            BuildError::LimineCheckFailed { .. } => Some("see the Considerations for Limine section of the kboot README".to_string()),
+            BuildError::LimineBiosUnsupported => Some("remove --legacy-boot (or `bios` from the [matrix] boot modes), or use the bootloader crate".to_string()),
+            BuildError::RamdiskPathInvalid(_) => Some("pass the ramdisk as a single quoted path, e.g. --ramdisk \"ramdisk.img\"".to_string()),
+            BuildError::CmdlineWithRamdisk => Some("remove the `cmdline` from kboot.toml (or KBOOT_CMDLINE) or the --ramdisk, or boot with Limine, which passes both".to_string()),
+            BuildError::LimineConfNotFound { .. } => Some("`conf` under [limine] in kboot.toml is relative to the workspace root; remove it to generate a limine.conf".to_string()),
+            BuildError::DiskImageFailed { .. } => Some("check that the executable is a kernel ELF built for the bootloader".to_string()),
+            BuildError::DirectoryReadFailed { .. } | BuildError::FileReadFailed { .. } | BuildError::PathPrefixFailed { .. }
+            | BuildError::ManifestWriteFailed { .. } => None
+        }
+    }
+}
+
+impl fmt::Display for BuildError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BuildError::ArgumentsInvalid(_) => write!(f, "could not determine the executable to build an image for"),
+            BuildError::ConfigInvalid(_) => write!(f, "could not read the kboot configuration"),
+            BuildError::DirectoryCreationFailed { path, .. } => write!(f, "failed to create directory {}", path.display()),
+            BuildError::DirectoryReadFailed { path, .. } => write!(f, "failed to read directory {}", path.display()),
+            BuildError::FileCopyFailed { from, to, .. } => write!(f, "failed to copy {} to {}", from.display(), to.display()),
+            BuildError::FileReadFailed { path, .. } => write!(f, "failed to read {}", path.display()),
+            BuildError::FileWriteFailed { path, .. } => write!(f, "failed to write {}", path.display()),
+            BuildError::CloneLimineBinaryFailed { url, path, .. } => write!(f, "failed to clone Limine from {} into {}", url, path.display()),
+            BuildError::LimineInstallFailed { image, .. } => write!(f, "failed to install Limine to {}", image.display()),
+            BuildError::LimineCheckFailed { problems } => write!(f, "Limine would not boot the kernel: {}", problems.join("; ")),
+            BuildError::LimineBiosUnsupported => write!(f, "Limine does not support BIOS booting with kboot (UEFI only)"),
+            BuildError::RamdiskPathInvalid(_) => write!(f, "invalid --ramdisk argument"),
+            BuildError::CmdlineWithRamdisk => write!(f, "the bootloader crate cannot pass both a kernel command line and a ramdisk"),
+            BuildError::LimineConfNotFound { searched } => write!(f, "limine.conf not found at {}", searched.display()),
+            BuildError::PathPrefixFailed { path } => write!(f, "{} is not inside the Limine ISO root", path.display()),
+            BuildError::DiskImageFailed { path, .. } => write!(f, "failed to create disk image {}", path.display()),
+            BuildError::ManifestWriteFailed { path, .. } => write!(f, "failed to write the image manifest {}", path.display())
+        }
+    }
+}
+
+impl std::error::Error for BuildError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            BuildError::DirectoryCreationFailed { source, .. }
+            | BuildError::DirectoryReadFailed { source, .. }
+            | BuildError::FileCopyFailed { source, .. }
+            | BuildError::FileReadFailed { source, .. }
+            | BuildError::FileWriteFailed { source, .. }
+            | BuildError::LimineInstallFailed { source, .. } => Some(source),
+            BuildError::CloneLimineBinaryFailed { source, .. } => Some(source),
+            BuildError::ArgumentsInvalid(source)
+            | BuildError::ConfigInvalid(source)
+            | BuildError::RamdiskPathInvalid(source)
+            | BuildError::DiskImageFailed { source, .. }
+            | BuildError::ManifestWriteFailed { source, .. } => Some(source.as_ref()),
+            BuildError::LimineBiosUnsupported | BuildError::CmdlineWithRamdisk | BuildError::LimineCheckFailed { .. } | BuildError::LimineConfNotFound { .. } | BuildError::PathPrefixFailed { .. } => None
+        }
+    }
+}
@@ -0,0 +1,217 @@
+use std::{fs, path::Path};
+use crate::builder::BuildError;
+
+/// First two words of the Limine base revision tag (`LIMINE_BASE_REVISION`)
+const BASE_REVISION_MAGIC: [u64; 2] = [0xf9562b2d5c95a6c8, 0x6a7b384944536bdc];
+
+/// First two words of the ID of every Limine request (`LIMINE_COMMON_MAGIC`)
+const COMMON_MAGIC: [u64; 2] = [0xc7b1dd30df4c8b88, 0x0a82e883a194f07b];
+
+/// Machine type of x86_64 executables in the ELF header
+const MACHINE_X86_64: u16 = 0x3e;
+
+/// Program header type of a loadable segment, and its flag for executable segments
+const PT_LOAD: u32 = 1;
+const PF_X: u32 = 1;
+
+/// Check the ISO root and the kernel for problems that would make Limine hang or
+/// reboot without an error: paths in limine.conf that do not exist in the image,
+/// and a kernel without the Limine base revision, requests or a sane entry point.
+pub fn check_limine_image(iso_root: &Path, kernel: &Path) -> Result<(), BuildError> {
+    log::info!("Checking limine.conf and the kernel before booting...");
+
+    let limine_conf_path = iso_root.join("boot").join("limine").join("limine.conf");
+    let limine_conf = fs::read_to_string(&limine_conf_path)
+        .map_err(|source| BuildError::FileReadFailed { path: limine_conf_path.clone(), source })?;
+    let kernel_bytes = fs::read(kernel)
+        .map_err(|source| BuildError::FileReadFailed { path: kernel.to_path_buf(), source })?;
+
+    let mut problems = check_limine_conf(&limine_conf, iso_root);
+    problems.extend(check_kernel(&kernel_bytes, uses_limine_protocol(&limine_conf)));
+    if !problems.is_empty() {
+        return Err(BuildError::LimineCheckFailed { problems });
+    }
+
+    Ok(())
+}
+
+/// Check that limine.conf has a boot entry, and that every entry that boots a
+/// kernel names its protocol and only refers to files that exist in the ISO root.
+fn check_limine_conf(limine_conf: &str, iso_root: &Path) -> Vec<String> {
+    let mut problems = Vec::new();
+    // line number and name of the current entry, and whether it has a protocol and a kernel path
+    let mut entry: Option<(usize, String, bool, bool)> = None;
+    let mut entries = 0;
+
+    for (index, line) in limine_conf.lines().enumerate() {
+        let line_number = index + 1;
+        let trimmed = line.trim();
+        if let Some(name) = trimmed.strip_prefix('/') {
+            check_entry(entry.take(), &mut problems);
+            entry = Some((line_number, name.trim_start_matches('/').trim().to_string(), false, false));
+            entries += 1;
+            continue;
+        }
+
+        let Some((key, value)) = trimmed.split_once(':') else {
+            continue;
+        };
+        let key = key.trim().to_ascii_lowercase();
+        let value = value.trim();
+        if let Some((_, _, has_protocol, has_path)) = entry.as_mut() {
+            match key.as_str() {
+                "protocol" => *has_protocol = true,
+                "path" | "kernel_path" => *has_path = true,
+                _ => {}
+            }
+        }
+
+        if matches!(key.as_str(), "path" | "kernel_path" | "module_path")
+            && let Some(relative_path) = get_boot_partition_path(value)
+            && !iso_root.join(relative_path).is_file() {
+            problems.push(format!("limine.conf line {}: {} {} does not exist in the image", line_number, key, value));
+        }
+    }
+    check_entry(entry, &mut problems);
+
+    if entries == 0 {
+        problems.push("limine.conf has no boot entries (lines starting with `/`)".to_string());
+    }
+    problems
+}
+
+/// Check that an entry that boots a kernel names its protocol.
+fn check_entry(entry: Option<(usize, String, bool, bool)>, problems: &mut Vec<String>) {
+    if let Some((line_number, name, false, true)) = entry {
+        problems.push(format!("limine.conf line {}: entry {} has no protocol (e.g. `protocol: limine`)", line_number, name));
+    }
+}
+
+/// Get the path of a file on the boot partition, relative to its root, from a
+/// Limine path such as `boot():/boot/kernel` (or the older `boot:///boot/kernel`).
+/// Paths on other partitions or the network are not checked.
+fn get_boot_partition_path(value: &str) -> Option<&str> {
+    // a hash may follow the path to verify the file
+    let path = value.split('#').next().unwrap_or(value);
+    path.strip_prefix("boot():/")
+        .or_else(|| path.strip_prefix("boot:///"))
+        .map(|path| path.trim_start_matches('/'))
+}
+
+/// Whether an entry boots its kernel with the Limine protocol, rather than e.g. multiboot2
+fn uses_limine_protocol(limine_conf: &str) -> bool {
+    limine_conf.lines()
+        .filter_map(|line| line.trim().split_once(':'))
+        .any(|(key, value)| key.trim().eq_ignore_ascii_case("protocol") && value.trim().eq_ignore_ascii_case("limine"))
+}
+
+/// Check that the kernel is an x86_64 ELF with its entry point in executable code,
+/// and, for the Limine protocol, that it declares the base revision and a request.
+fn check_kernel(kernel: &[u8], limine_protocol: bool) -> Vec<String> {
+    let mut problems = Vec::new();
+    if kernel.len() < 64 || &kernel[..4] != b"\x7fELF" || kernel[4] != 2 || kernel[5] != 1 {
+        problems.push("the kernel is not a 64-bit little-endian ELF executable".to_string());
+        return problems;
+    }
+
+    let machine = read_u16(kernel, 18).unwrap_or_default();
+    if machine != MACHINE_X86_64 {
+        problems.push(format!("the kernel is built for ELF machine type {:#x}, not x86_64", machine));
+    }
+
+    let entry = read_u64(kernel, 24).unwrap_or_default();
+    if entry == 0 {
+        problems.push("the kernel has no entry point; the linker must use `_start` as the entry point".to_string());
+    } else if !is_in_executable_segment(kernel, entry) {
+        problems.push(format!("the entry point {:#x} of the kernel is not in an executable segment; the linker must use `_start` as the entry point", entry));
+    }
+
+    if !limine_protocol {
+        return problems;
+    }
+    if !contains_magic(kernel, BASE_REVISION_MAGIC) {
+        problems.push("the kernel has no Limine base revision (`BaseRevision`); it must be a static that is not optimized away".to_string());
+    }
+    if !contains_magic(kernel, COMMON_MAGIC) {
+        problems.push("the kernel has no Limine requests; they must be statics that are not optimized away".to_string());
+    }
+
+    problems
+}
+
+/// Whether an address is inside a loadable, executable segment of the ELF file
+fn is_in_executable_segment(kernel: &[u8], address: u64) -> bool {
+    let program_header_offset = read_u64(kernel, 32).unwrap_or_default() as usize;
+    let program_header_size = read_u16(kernel, 54).unwrap_or_default() as usize;
+    let program_header_count = read_u16(kernel, 56).unwrap_or_default() as usize;
+
+    (0..program_header_count)
+        .map(|index| program_header_offset + index * program_header_size)
+        .filter_map(|offset| Some((
+            read_u32(kernel, offset)?,
+            read_u32(kernel, offset + 4)?,
+            read_u64(kernel, offset + 16)?,
+            read_u64(kernel, offset + 40)?
+        )))
+        .any(|(segment_type, flags, virtual_address, memory_size)| segment_type == PT_LOAD && flags & PF_X != 0
+            && address >= virtual_address && address < virtual_address.saturating_add(memory_size))
+}
+
+/// Whether the two words are stored next to each other (little-endian)
+fn contains_magic(kernel: &[u8], magic: [u64; 2]) -> bool {
+    let bytes = [magic[0].to_le_bytes(), magic[1].to_le_bytes()].concat();
+    kernel.windows(bytes.len()).any(|window| window == bytes)
+}
+
+fn read_u16(bytes: &[u8], offset: usize) -> Option<u16> {
+    Some(u16::from_le_bytes(bytes.get(offset..offset + 2)?.try_into().ok()?))
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(bytes.get(offset..offset + 4)?.try_into().ok()?))
+}
+
+fn read_u64(bytes: &[u8], offset: usize) -> Option<u64> {
+    Some(u64::from_le_bytes(bytes.get(offset..offset + 8)?.try_into().ok()?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn when_limine_conf_refers_to_missing_files_then_each_is_reported() {
+        let iso_root = tempfile::tempdir().unwrap();
+        fs::create_dir_all(iso_root.path().join("boot").join("kernel")).unwrap();
+        fs::write(iso_root.path().join("boot").join("kernel").join("kernel"), b"").unwrap();
+
+        let limine_conf = [
+            "timeout: 0",
+            "/kernel",
+            "    protocol: limine",
+            "    path: boot():/boot/kernel/kernel",
+            "    module_path: boot():/boot/modules/initrd#0123abcd",
+            "/kernel (old)",
+            "    kernel_path: boot:///boot/kernel/kernel",
+            "    module_path: tftp():/initrd"
+        ].join("\n");
+
+        let problems = check_limine_conf(&limine_conf, iso_root.path());
+
+        assert_eq!(problems, [
+            "limine.conf line 5: module_path boot():/boot/modules/initrd#0123abcd does not exist in the image",
+            "limine.conf line 6: entry kernel (old) has no protocol (e.g. `protocol: limine`)"
+        ]);
+        assert_eq!(check_limine_conf("timeout: 0", iso_root.path()).len(), 1);
+    }
+
+    #[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+    #[test]
+    fn when_kernel_is_checked_then_elf_header_and_entry_point_are_validated() {
+        // the test executable is an x86_64 ELF with its entry point in executable code
+        let executable = fs::read(std::env::current_exe().unwrap()).unwrap();
+
+        assert!(check_kernel(&executable, false).is_empty());
+        assert_eq!(check_kernel(b"#!/bin/sh", true), ["the kernel is not a 64-bit little-endian ELF executable"]);
+    }
+}
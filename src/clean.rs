@@ -1,8 +1,8 @@
-use std::path::PathBuf;
-use crate::{BUILD_DIRECTORY, KbootError};
+use crate::{args, KbootError};
 
 pub fn clean() -> Result<(), KbootError> {
-    let build_dir = PathBuf::from(BUILD_DIRECTORY);
+    let build_dir = args::get_build_directory()
+        .map_err(|e| KbootError::ArgumentFailedToParse(e, "Failed to get the build directory".to_string()))?;
     if build_dir.exists() {
         std::fs::remove_dir_all(&build_dir)
             .map_err(|e| KbootError::LoggerIoError(e, format!("Failed to clean build directory: {}", build_dir.display())))?;
@@ -11,6 +11,10 @@ pub fn clean() -> Result<(), KbootError> {
         log::info!("Build directory does not exist, nothing to clean: {}", build_dir.display());
     }
 
+    if let Err(e) = crate::container::remove_orphaned_containers() {
+        log::warn!("Failed to remove orphaned containers: {}", e);
+    }
+
     let status = std::process::Command::new("cargo")
         .arg("clean")
         .status()
@@ -0,0 +1,357 @@
+use std::{path::PathBuf, sync::OnceLock};
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use crate::args;
+
+/// Name of the optional configuration file in the workspace root
+pub const CONFIG_FILE_NAME: &str = "kboot.toml";
+
+/// Configuration loaded from `kboot.toml`, if one exists
+static CONFIG: OnceLock<Config> = OnceLock::new();
+
+/// Get the kboot configuration, loading it from the workspace root on first use.
+///
+/// A missing configuration file is not an error; the defaults are used instead.
+pub fn get_config() -> Result<&'static Config> {
+    if let Some(config) = CONFIG.get() {
+        return Ok(config);
+    }
+
+    let config = load_config(&get_config_path()?)?;
+    Ok(CONFIG.get_or_init(|| config))
+}
+
+/// Get the path where the configuration file is expected to be found
+pub fn get_config_path() -> Result<PathBuf> {
+    Ok(args::get_workspace_root()?.join(CONFIG_FILE_NAME))
+}
+
+/// Parse the configuration file at the given path
+fn load_config(path: &PathBuf) -> Result<Config> {
+    if !path.exists() {
+        log::info!("No {} found at {}, using default configuration", CONFIG_FILE_NAME, path.display());
+        return Ok(Config::default());
+    }
+
+    let content = std::fs::read_to_string(path)?;
+    let config: Config = toml::from_str(&content)
+        .map_err(|e| anyhow!("Failed to parse {}: {}", path.display(), e))?;
+
+    log::info!("Loaded configuration from {}: {:?}", path.display(), config);
+    Ok(config)
+}
+
+/// Top-level structure of `kboot.toml`
+#[derive(Deserialize, Debug, Default)]
+#[serde(default)]
+pub struct Config {
+    pub performance: PerformanceConfig,
+    pub bench: BenchConfig,
+    pub retry: RetryConfig,
+    pub output: OutputConfig,
+    pub vm: VmConfig,
+    pub matrix: MatrixConfig,
+    pub ports: PortsConfig,
+    pub test: TestConfig,
+    pub boot: BootSection,
+    pub limine: LimineConfig
+}
+
+/// Settings for cycle count regression tracking, under `[performance]`
+#[derive(Deserialize, Debug)]
+#[serde(default)]
+pub struct PerformanceConfig {
+    /// Number of previous rounds used as the rolling baseline
+    pub window: usize,
+    /// Minimum slowdown (in percent) before a test is flagged as a regression
+    pub threshold_percent: f64,
+    /// Minimum number of standard deviations above the baseline mean
+    /// before a slowdown is considered significant
+    pub z_score: f64,
+    /// If set, the run fails when any test slows down significantly (see `z_score`) by more
+    /// than this percentage, whether or not that exceeds `threshold_percent`
+    pub fail_percent: Option<f64>,
+    /// Baseline file, relative to the workspace root, which may be committed
+    pub baseline_file: PathBuf
+}
+
+impl Default for PerformanceConfig {
+    fn default() -> Self {
+        Self {
+            window: 5,
+            threshold_percent: 10.0,
+            z_score: 3.0,
+            fail_percent: None,
+            baseline_file: PathBuf::from("kboot-baseline.json")
+        }
+    }
+}
+
+/// Settings for benchmark mode, under `[bench]`
+#[derive(Deserialize, Debug)]
+#[serde(default)]
+pub struct BenchConfig {
+    /// Number of times the kernel is booted to collect samples
+    pub runs: usize,
+    /// Changes relative to the baseline below this percentage are considered noise
+    pub noise_percent: f64,
+    /// Baseline file, relative to the workspace root, which may be committed
+    pub baseline_file: PathBuf
+}
+
+impl Default for BenchConfig {
+    fn default() -> Self {
+        Self {
+            runs: 10,
+            noise_percent: 2.0,
+            baseline_file: PathBuf::from("kboot-bench-baseline.json")
+        }
+    }
+}
+
+/// Settings for re-running failed tests, under `[retry]`
+#[derive(Deserialize, Debug, Default)]
+#[serde(default)]
+pub struct RetryConfig {
+    /// Number of times a test group with failed tests is re-booted (0 disables retries)
+    pub attempts: u32
+}
+
+/// Settings for the virtual machine, under `[vm]`
+///
+/// Hardware settings that are not set keep the defaults of the backend.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct VmConfig {
+    /// Number of seconds after which a running test group is stopped through QMP
+    pub timeout: Option<u64>,
+    pub backend: VmBackend,
+    /// Memory size, e.g. `512M` or `2G`
+    pub memory: Option<String>,
+    pub smp: SmpConfig,
+    /// Machine type, e.g. `q35` or `pc`
+    pub machine: Option<String>,
+    /// CPU model, e.g. `qemu64`, `host` or `max`
+    pub cpu: Option<String>,
+    /// CPU feature flags, e.g. `+x2apic` or `-pdpe1gb`
+    pub cpu_features: Vec<String>,
+    pub accel: Option<Accel>,
+    /// Additional devices, each given as a `-device` specification
+    pub devices: Vec<String>,
+    /// QEMU binary used by the native backend
+    pub qemu_binary: String,
+    /// UEFI firmware (OVMF) used by the native backend, found automatically if not set
+    pub firmware: Option<PathBuf>
+}
+
+impl Default for VmConfig {
+    fn default() -> Self {
+        Self {
+            timeout: None,
+            backend: VmBackend::default(),
+            memory: None,
+            smp: SmpConfig::default(),
+            machine: None,
+            cpu: None,
+            cpu_features: Vec::new(),
+            accel: None,
+            devices: Vec::new(),
+            qemu_binary: "qemu-system-x86_64".to_string(),
+            firmware: None
+        }
+    }
+}
+
+/// How QEMU is run
+#[derive(Deserialize, Debug, Default, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum VmBackend {
+    /// Inside the `qemux/qemu` Docker container, with a web display (noVNC)
+    #[default]
+    Docker,
+    /// The QEMU binary installed on the host
+    Native
+}
+
+/// SMP topology of the VM, under `[vm.smp]`
+#[derive(Deserialize, Debug, Default, Clone)]
+#[serde(default)]
+pub struct SmpConfig {
+    pub cpus: Option<u32>,
+    pub sockets: Option<u32>,
+    pub cores: Option<u32>,
+    pub threads: Option<u32>
+}
+
+/// Accelerator used to run the VM
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Accel {
+    Kvm,
+    Tcg
+}
+
+/// Settings for additional test result formats, under `[output]`
+#[derive(Deserialize, Debug, Default)]
+#[serde(default)]
+pub struct OutputConfig {
+    /// Format written for each test group, besides kboot's own JSON
+    pub format: OutputFormat,
+    /// File the results are written to, relative to the workspace root (stdout if not set).
+    /// `{group}` is replaced by the test group name; otherwise all groups of a round
+    /// are written to the same file.
+    pub file: Option<PathBuf>
+}
+
+/// Formats in which test results can be written, see `report.rs`
+#[derive(Deserialize, Debug, Default, Clone, Copy, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum OutputFormat {
+    /// Only kboot's own JSON layout
+    #[default]
+    Kboot,
+    /// Test Anything Protocol, version 13
+    Tap,
+    /// The JSON events of libtest's `--format json`
+    LibtestJson
+}
+
+/// Test matrix, under `[matrix]`. Every test executable is run once for
+/// each combination of the given values; empty dimensions are not varied.
+#[derive(Deserialize, Debug, Default)]
+#[serde(default)]
+pub struct MatrixConfig {
+    /// Numbers of CPUs
+    pub smp: Vec<u32>,
+    /// CPU models, optionally with feature flags (e.g. `qemu64,+x2apic`)
+    pub cpu: Vec<String>,
+    pub boot: Vec<BootMode>
+}
+
+impl MatrixConfig {
+    pub fn is_empty(&self) -> bool {
+        self.smp.is_empty() && self.cpu.is_empty() && self.boot.is_empty()
+    }
+}
+
+/// Firmware interface the image is booted with
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum BootMode {
+    Uefi,
+    Bios
+}
+
+/// Host ports for the containers, under `[ports]`
+#[derive(Deserialize, Debug, Default)]
+#[serde(default)]
+pub struct PortsConfig {
+    /// Range (inclusive) used when a preferred port (e.g. 8006 for noVNC) is taken;
+    /// without a range, the operating system assigns a free port
+    pub range: Option<[u16; 2]>
+}
+
+/// Settings for `kboot test`, under `[test]`
+#[derive(Deserialize, Debug, Default)]
+#[serde(default)]
+pub struct TestConfig {
+    /// Number of test executables run concurrently (the number of host CPUs if not set)
+    pub jobs: Option<usize>
+}
+
+/// Settings for Limine, under `[limine]`
+#[derive(Deserialize, Debug, Default)]
+#[serde(default)]
+pub struct LimineConfig {
+    /// limine.conf copied into the image, relative to the workspace root. If not set,
+    /// the package of the kernel and then the workspace are searched for one, and
+    /// one is generated if there is none.
+    pub conf: Option<PathBuf>
+}
+
+/// Settings of the bootloader crate, under `[boot]`, with overrides for `cargo run`
+/// under `[boot.run]` and for test executables under `[boot.test]`.
+#[derive(Deserialize, Debug, Default)]
+#[serde(default)]
+pub struct BootSection {
+    #[serde(flatten)]
+    pub settings: BootSettings,
+    pub run: BootSettings,
+    pub test: BootSettings
+}
+
+impl BootSection {
+    /// Get the settings for a test executable or a regular run, with the overrides applied
+    pub fn get_settings(&self, is_test: bool) -> BootSettings {
+        let overrides = if is_test { &self.test } else { &self.run };
+        self.settings.merge(overrides)
+    }
+}
+
+/// Kernel command line and fields of the `BootConfig` of the bootloader crate.
+/// Settings that are not set keep the defaults of the bootloader; Limine only
+/// uses the command line.
+#[derive(Deserialize, Debug, Default, Clone, PartialEq)]
+#[serde(default)]
+pub struct BootSettings {
+    /// Command line passed to the kernel (see `KBOOT_CMDLINE`)
+    pub cmdline: Option<String>,
+    pub minimum_framebuffer_width: Option<u64>,
+    pub minimum_framebuffer_height: Option<u64>,
+    /// Minimum level of the messages logged by the bootloader
+    pub log_level: Option<BootLogLevel>,
+    pub frame_buffer_logging: Option<bool>,
+    pub serial_logging: Option<bool>
+}
+
+impl BootSettings {
+    /// Combine these settings with overrides, which take precedence where they are set
+    fn merge(&self, overrides: &BootSettings) -> BootSettings {
+        BootSettings {
+            cmdline: overrides.cmdline.clone().or_else(|| self.cmdline.clone()),
+            minimum_framebuffer_width: overrides.minimum_framebuffer_width.or(self.minimum_framebuffer_width),
+            minimum_framebuffer_height: overrides.minimum_framebuffer_height.or(self.minimum_framebuffer_height),
+            log_level: overrides.log_level.or(self.log_level),
+            frame_buffer_logging: overrides.frame_buffer_logging.or(self.frame_buffer_logging),
+            serial_logging: overrides.serial_logging.or(self.serial_logging)
+        }
+    }
+}
+
+/// Log levels of the bootloader crate
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum BootLogLevel {
+    Off,
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn when_boot_mode_has_overrides_then_they_replace_the_shared_settings() {
+        let config: Config = toml::from_str(r#"
+            [boot]
+            log_level = "info"
+            serial_logging = true
+            minimum_framebuffer_width = 1280
+
+            [boot.test]
+            serial_logging = false
+        "#).unwrap();
+
+        let test = config.boot.get_settings(true);
+        assert_eq!(test.log_level, Some(BootLogLevel::Info));
+        assert_eq!(test.serial_logging, Some(false));
+        assert_eq!(test.minimum_framebuffer_width, Some(1280));
+        assert_eq!(test.frame_buffer_logging, None);
+
+        assert_eq!(config.boot.get_settings(false).serial_logging, Some(true));
+    }
+}
@@ -0,0 +1,296 @@
+use std::{fs, io::{self, BufRead, Read, Seek, Write}, path::{Path, PathBuf}, sync::{atomic::{AtomicBool, Ordering}, Arc}, thread::{self, JoinHandle}, time::Duration};
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use crate::config::OutputFormat;
+
+/// Interval at which the -debugcon output file is checked for new lines
+const DEBUGCON_POLL_INTERVAL: Duration = Duration::from_millis(20);
+
+/// Number of milliseconds since the Unix epoch, used to timestamp console lines
+pub fn timestamp_millis() -> u128 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_millis())
+        .unwrap_or(0)
+}
+
+/// Forward the serial console (the stdout of the QEMU container) to the terminal
+/// unchanged, while writing each line prefixed with a timestamp to the log file.
+///
+/// In headless mode, the terminal gets complete lines without ANSI escape sequences,
+/// which CI logs would show as garbage.
+pub fn tee_serial(mut serial: impl Read + Send + 'static, log_path: PathBuf) -> JoinHandle<Result<()>> {
+    thread::spawn(move || {
+        let headless = crate::args::is_headless();
+        let mut log_file = io::BufWriter::new(fs::File::create(&log_path)?);
+        let mut stdout = human_output();
+        let mut line = Vec::new();
+        let mut buffer = [0u8; 4096];
+
+        loop {
+            let count = serial.read(&mut buffer)?;
+            if count == 0 {
+                break;
+            }
+
+            if !headless {
+                stdout.write_all(&buffer[..count])?;
+                stdout.flush()?;
+            }
+
+            for byte in &buffer[..count] {
+                if *byte == b'\n' {
+                    if headless {
+                        write_plain_line(&mut stdout, &line)?;
+                    }
+                    write_serial_line(&mut log_file, &line)?;
+                    line.clear();
+                } else {
+                    line.push(*byte);
+                }
+            }
+        }
+
+        if !line.is_empty() {
+            if headless {
+                write_plain_line(&mut stdout, &line)?;
+            }
+            write_serial_line(&mut log_file, &line)?;
+        }
+
+        log_file.flush()?;
+        Ok(())
+    })
+}
+
+fn write_serial_line(log_file: &mut impl Write, line: &[u8]) -> io::Result<()> {
+    let text = String::from_utf8_lossy(line);
+    writeln!(log_file, "[{}] {}", timestamp_millis(), text.trim_end_matches('\r'))
+}
+
+fn write_plain_line(stdout: &mut impl Write, line: &[u8]) -> io::Result<()> {
+    let text = String::from_utf8_lossy(line);
+    writeln!(stdout, "{}", strip_ansi_escapes(text.trim_end_matches('\r')))?;
+    stdout.flush()
+}
+
+/// Remove ANSI escape sequences (e.g. colors and cursor movement) from a line.
+fn strip_ansi_escapes(line: &str) -> String {
+    let mut plain = String::with_capacity(line.len());
+    let mut chars = line.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        if c != '\x1b' {
+            plain.push(c);
+            continue;
+        }
+
+        // control sequences (`ESC [`) end with a byte in the range `@` to `~`, other
+        // escape sequences with the first byte after their intermediate bytes (` ` to `/`)
+        if chars.next_if_eq(&'[').is_some() {
+            for c in chars.by_ref() {
+                if ('@'..='~').contains(&c) {
+                    break;
+                }
+            }
+        } else {
+            while chars.next_if(|c| (' '..='/').contains(c)).is_some() {}
+            chars.next();
+        }
+    }
+
+    plain
+}
+
+/// Whether stdout carries a machine-readable test report, i.e. a test run with a TAP or
+/// libtest JSON `[output] format` and no `[output] file`. Human-readable output then goes
+/// to stderr, so consumers of the report can read stdout as is.
+pub fn is_stdout_reserved() -> bool {
+    let is_test_run = crate::args::is_test_command() || crate::args::is_test().unwrap_or(false);
+    is_test_run && crate::config::get_config()
+        .is_ok_and(|config| config.output.format != OutputFormat::Kboot && config.output.file.is_none())
+}
+
+/// Get the stream for human-readable output: stdout, unless it is reserved for the report.
+pub fn human_output() -> Box<dyn Write + Send> {
+    if is_stdout_reserved() {
+        Box::new(io::stderr())
+    } else {
+        Box::new(io::stdout())
+    }
+}
+
+/// Print a line of human-readable output, see `human_output`.
+pub fn print_human(line: &str) {
+    let mut output = human_output();
+    let _ = writeln!(output, "{}", line);
+    let _ = output.flush();
+}
+
+/// Print a line to the terminal while QEMU is running. The container's
+/// interactive terminal puts the local terminal in raw mode, which
+/// requires an explicit carriage return; headless runs have no raw mode.
+pub fn print_live(line: &str) {
+    let mut stdout = human_output();
+    let line_ending = if crate::args::is_headless() { "\n" } else { "\r\n" };
+    let _ = write!(stdout, "{}{}", line, line_ending);
+    let _ = stdout.flush();
+}
+
+/// Read a timestamped serial log written by `tee_serial`.
+pub fn read_serial_log(log_path: &Path) -> Result<Vec<(u128, String)>> {
+    let file = fs::File::open(log_path)?;
+    let mut lines = Vec::new();
+
+    for line in io::BufReader::new(file).lines() {
+        let line = line?;
+        let (timestamp, text) = line.strip_prefix('[')
+            .and_then(|l| l.split_once("] "))
+            .ok_or_else(|| anyhow!("Serial log line is missing its timestamp: {}", line))?;
+        lines.push((timestamp.parse()?, text.to_string()));
+    }
+
+    Ok(lines)
+}
+
+/// Watches the -debugcon output file while QEMU is running and hands each
+/// complete line to a callback as soon as it was written, along with its timestamp.
+pub struct DebugconWatcher {
+    stop: Arc<AtomicBool>,
+    handle: JoinHandle<Result<()>>
+}
+
+impl DebugconWatcher {
+    pub fn start(debugcon_path: PathBuf, mut on_line: impl FnMut(&str, u128) + Send + 'static) -> Self {
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread_stop = stop.clone();
+
+        let handle = thread::spawn(move || {
+            let mut offset = 0;
+
+            loop {
+                // read once more after being stopped, to catch the final lines
+                let stopping = thread_stop.load(Ordering::Acquire);
+                offset = read_new_lines(&debugcon_path, offset, &mut on_line)?;
+                if stopping {
+                    break;
+                }
+
+                thread::sleep(DEBUGCON_POLL_INTERVAL);
+            }
+
+            Ok(())
+        });
+
+        Self { stop, handle }
+    }
+
+    /// Stop watching after all remaining lines have been handled.
+    pub fn stop(self) -> Result<()> {
+        self.stop.store(true, Ordering::Release);
+        self.handle.join()
+            .map_err(|_| anyhow!("Debugcon watcher thread panicked"))?
+    }
+}
+
+/// Hand each complete line after the given offset to the callback,
+/// returning the offset after the last complete line.
+fn read_new_lines(path: &Path, offset: u64, on_line: &mut impl FnMut(&str, u128)) -> Result<u64> {
+    let Ok(mut file) = fs::File::open(path) else {
+        return Ok(offset);
+    };
+
+    file.seek(io::SeekFrom::Start(offset))?;
+    let mut content = Vec::new();
+    file.read_to_end(&mut content)?;
+
+    let now = timestamp_millis();
+    let mut consumed = 0;
+    for (index, byte) in content.iter().enumerate() {
+        if *byte == b'\n' {
+            let line = String::from_utf8_lossy(&content[consumed..index]);
+            on_line(line.trim_end_matches('\r'), now);
+            consumed = index + 1;
+        }
+    }
+
+    Ok(offset + consumed as u64)
+}
+
+/// Reference to the lines of a serial log that were written while a test ran.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SerialLogLink {
+    /// File name of the serial log, next to the test group JSON
+    pub file: String,
+    /// First line (1-based) written during the test
+    pub first_line: usize,
+    /// Last line (1-based, inclusive) written during the test
+    pub last_line: usize
+}
+
+/// Find the serial log lines that were written between `start` and `end`.
+pub fn link_serial_output(serial_lines: &[(u128, String)], file: &str, start: u128, end: u128) -> Option<SerialLogLink> {
+    let first = serial_lines.iter().position(|(timestamp, _)| *timestamp >= start)?;
+    let last = serial_lines.iter().rposition(|(timestamp, _)| *timestamp <= end)?;
+    if last < first {
+        return None;
+    }
+
+    Some(SerialLogLink {
+        file: file.to_string(),
+        first_line: first + 1,
+        last_line: last + 1
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn when_serial_output_is_teed_then_each_line_is_timestamped() {
+        let log_path = std::env::temp_dir().join(format!("kboot-serial-{}.log", uuid::Uuid::new_v4()));
+        let serial = io::Cursor::new(b"booting\r\nkernel::test_alloc\npanic".to_vec());
+        tee_serial(serial, log_path.clone()).join().unwrap().unwrap();
+
+        let lines = read_serial_log(&log_path).unwrap();
+        let texts: Vec<&str> = lines.iter().map(|(_, text)| text.as_str()).collect();
+        assert_eq!(texts, ["booting", "kernel::test_alloc", "panic"]);
+        assert!(lines.windows(2).all(|pair| pair[0].0 <= pair[1].0));
+
+        fs::remove_file(log_path).unwrap();
+    }
+
+    #[test]
+    fn when_test_ran_between_timestamps_then_serial_lines_in_between_are_linked() {
+        let serial_lines: Vec<(u128, String)> = [(10, "boot"), (20, "alloc 1"), (25, "alloc 2"), (40, "next test")]
+            .iter().map(|(timestamp, text)| (*timestamp, text.to_string())).collect();
+
+        let link = link_serial_output(&serial_lines, "serial-kernel.log", 15, 30).unwrap();
+        assert_eq!((link.first_line, link.last_line), (2, 3));
+        assert!(link_serial_output(&serial_lines, "serial-kernel.log", 30, 35).is_none());
+    }
+
+    #[test]
+    fn when_debugcon_line_is_incomplete_then_it_is_handled_once_complete() {
+        let path = std::env::temp_dir().join(format!("kboot-debugcon-{}.json", uuid::Uuid::new_v4()));
+        fs::write(&path, "first\nsec").unwrap();
+
+        let mut lines = Vec::new();
+        let offset = read_new_lines(&path, 0, &mut |line, _| lines.push(line.to_string())).unwrap();
+        assert_eq!((lines.as_slice(), offset), (["first".to_string()].as_slice(), 6));
+
+        fs::OpenOptions::new().append(true).open(&path).unwrap().write_all(b"ond\r\n").unwrap();
+        read_new_lines(&path, offset, &mut |line, _| lines.push(line.to_string())).unwrap();
+        assert_eq!(lines, ["first", "second"]);
+
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn when_strip_ansi_escapes_then_colors_and_cursor_movement_removed() {
+        let line = "\x1b[1;32mok\x1b[0m test \x1b[2Kdone\x1b(B";
+
+        assert_eq!(strip_ansi_escapes(line), "ok test done");
+    }
+}
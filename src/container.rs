@@ -0,0 +1,218 @@
+use std::{net::TcpListener, process::Command, time::{Duration, SystemTime, UNIX_EPOCH}};
+use anyhow::{anyhow, Result};
+use uuid::Uuid;
+use crate::{args, config};
+
+/// Label that marks the containers started by kboot, with the role of the container
+const ROLE_LABEL: &str = "kboot.role";
+
+/// Label with the ID of the kboot process that started a container
+const PID_LABEL: &str = "kboot.pid";
+
+/// Label with the time at which a container was started, in seconds since the Unix epoch
+const STARTED_LABEL: &str = "kboot.started";
+
+/// Age after which a container is considered orphaned if it cannot be told whether its
+/// kboot process is still running, longer than any test run is expected to take
+const ORPHAN_AGE: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Host port on which a port of the QEMU container is published
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PublishedPort {
+    /// A port that was free before the container started
+    Fixed(u16),
+    /// A free port chosen by Docker when it starts the container, from the range if one is given
+    Assigned(Option<[u16; 2]>)
+}
+
+impl PublishedPort {
+    /// Docker argument that publishes the container port on this host port, optionally on the loopback interface only
+    pub fn get_argument(&self, container_port: u16, local_only: bool) -> String {
+        let host_port = match self {
+            Self::Fixed(port) => port.to_string(),
+            Self::Assigned(Some([start, end])) => format!("{}-{}", start, end),
+            Self::Assigned(None) => String::new()
+        };
+        match (local_only, host_port.is_empty()) {
+            (true, _) => format!("127.0.0.1:{}:{}", host_port, container_port),
+            (false, true) => container_port.to_string(),
+            (false, false) => format!("{}:{}", host_port, container_port)
+        }
+    }
+}
+
+/// Name of the QEMU container of a session, so concurrent runs do not conflict
+pub fn get_qemu_container_name(session: &Uuid) -> String {
+    format!("kboot-qemu-{}", &session.simple().to_string()[..12])
+}
+
+/// Docker arguments that label the QEMU container of a session, so that
+/// it can be found again if this process dies before removing it.
+pub fn get_qemu_labels(session: &Uuid) -> Vec<String> {
+    vec![
+        "--label".to_string(), format!("{}=qemu", ROLE_LABEL),
+        "--label".to_string(), format!("{}={}", PID_LABEL, std::process::id()),
+        "--label".to_string(), format!("{}={}", STARTED_LABEL, get_unix_time()),
+        "--label".to_string(), format!("kboot.session={}", session)
+    ]
+}
+
+/// Remove QEMU containers whose kboot process is no longer running, e.g. because
+/// it was killed. Containers of other running sessions are left alone.
+///
+/// If it cannot be told whether the process is running, containers are only
+/// removed once they are older than [`ORPHAN_AGE`].
+pub fn remove_orphaned_containers() -> Result<()> {
+    let output = Command::new("docker")
+        .args(["ps", "-a", "--filter", &format!("label={}=qemu", ROLE_LABEL)])
+        .args(["--format", &format!("{{{{.Names}}}} {{{{.Label \"{}\"}}}} {{{{.Label \"{}\"}}}}", PID_LABEL, STARTED_LABEL)])
+        .output()?;
+    if !output.status.success() {
+        return Err(anyhow!("Failed to list containers: {}", String::from_utf8_lossy(&output.stderr)));
+    }
+
+    for line in String::from_utf8_lossy(&output.stdout).lines() {
+        let mut fields = line.split(' ');
+        let (Some(name), Some(Ok(pid))) = (fields.next(), fields.next().map(str::parse::<u32>)) else {
+            continue;
+        };
+        // containers of older kboot versions have no start time
+        let started = fields.next().and_then(|started| started.parse::<u64>().ok());
+
+        match is_process_running(pid) {
+            Some(true) => {},
+            Some(false) => {
+                log::warn!("Removing orphaned container {} of kboot process {}", name, pid);
+                Command::new("docker").args(["rm", "-f", name]).output()?;
+            },
+            None if started.is_some_and(|started| is_older_than(started, get_unix_time(), ORPHAN_AGE)) => {
+                log::warn!("Removing container {} that was started more than {}h ago", name, ORPHAN_AGE.as_secs() / 3600);
+                Command::new("docker").args(["rm", "-f", name]).output()?;
+            },
+            None => log::info!("Cannot tell whether kboot process {} is running, keeping its container {} until it is {}h old",
+                pid, name, ORPHAN_AGE.as_secs() / 3600)
+        }
+    }
+
+    Ok(())
+}
+
+/// Whether a process is running, or `None` if this cannot be determined
+#[cfg(unix)]
+fn is_process_running(pid: u32) -> Option<bool> {
+    let pid = libc::pid_t::try_from(pid).ok()?;
+    // signal 0 only checks whether the process exists; processes of other users cannot be signaled
+    if unsafe { libc::kill(pid, 0) } == 0 {
+        return Some(true);
+    }
+    match std::io::Error::last_os_error().raw_os_error() {
+        Some(libc::EPERM) => Some(true),
+        Some(libc::ESRCH) => Some(false),
+        _ => None
+    }
+}
+
+/// Whether a process is running, or `None` if this cannot be determined
+#[cfg(windows)]
+fn is_process_running(pid: u32) -> Option<bool> {
+    let output = Command::new("tasklist")
+        .args(["/FI", &format!("PID eq {}", pid), "/FO", "CSV", "/NH"])
+        .output()
+        .ok()
+        .filter(|output| output.status.success())?;
+    Some(String::from_utf8_lossy(&output.stdout).contains(&format!("\"{}\"", pid)))
+}
+
+/// Whether a process is running, or `None` if this cannot be determined
+#[cfg(not(any(unix, windows)))]
+fn is_process_running(_pid: u32) -> Option<bool> {
+    None
+}
+
+fn is_older_than(started: u64, now: u64, age: Duration) -> bool {
+    now.saturating_sub(started) > age.as_secs()
+}
+
+fn get_unix_time() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+/// Choose the host port of a port of a container: the preferred port if it is free,
+/// so that single runs keep well-known URLs. Otherwise Docker chooses a free port from
+/// the range (or from all free ports) when it starts the container, which cannot be taken
+/// by a concurrent run in the meantime; the port is read back with [`get_published_port`].
+pub fn choose_published_port(preferred: Option<u16>, range: Option<[u16; 2]>) -> PublishedPort {
+    match preferred {
+        Some(preferred) if is_port_free(preferred) => PublishedPort::Fixed(preferred),
+        _ => PublishedPort::Assigned(range)
+    }
+}
+
+/// Get the host port on which a port of the QEMU container of a session was published,
+/// or `None` if Docker has not started the container yet.
+pub fn get_published_port(session: &Uuid, container_port: u16) -> Result<Option<u16>> {
+    let output = Command::new("docker")
+        .args(["port", &get_qemu_container_name(session), &format!("{}/tcp", container_port)])
+        .output()?;
+    if !output.status.success() {
+        return Ok(None);
+    }
+
+    // e.g. `127.0.0.1:49153`, or one line per address family
+    Ok(String::from_utf8_lossy(&output.stdout).lines()
+        .find_map(|line| line.rsplit_once(':').and_then(|(_, port)| port.trim().parse::<u16>().ok())))
+}
+
+/// Find a free host port, preferring the given port so that single runs keep
+/// well-known URLs. Otherwise a port from the `[ports] range` is used, or one
+/// assigned by the operating system if no range is configured.
+///
+/// The executables run concurrently by `kboot test` don't prefer the port,
+/// as they would all find it free before any of them has bound it.
+pub fn allocate_port(preferred: u16) -> Result<u16> {
+    let preferred = if args::is_isolated_run() { None } else { Some(preferred) };
+    find_free_port(preferred, config::get_config()?.ports.range)
+}
+
+/// Find a free host port: the preferred port if it is free, otherwise one from
+/// the range, or one assigned by the operating system if there is no range.
+pub fn find_free_port(preferred: Option<u16>, range: Option<[u16; 2]>) -> Result<u16> {
+    if let Some(preferred) = preferred && is_port_free(preferred) {
+        return Ok(preferred);
+    }
+
+    if let Some([start, end]) = range {
+        return (start..=end)
+            .find(|port| is_port_free(*port))
+            .ok_or_else(|| anyhow!("No free port in the configured range {}-{}", start, end));
+    }
+
+    let listener = TcpListener::bind(("0.0.0.0", 0))?;
+    Ok(listener.local_addr()?.port())
+}
+
+fn is_port_free(port: u16) -> bool {
+    TcpListener::bind(("0.0.0.0", port)).is_ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn when_port_is_published_then_docker_argument_matches_host_port() {
+        assert_eq!(PublishedPort::Fixed(4444).get_argument(4444, true), "127.0.0.1:4444:4444");
+        assert_eq!(PublishedPort::Assigned(None).get_argument(4444, true), "127.0.0.1::4444");
+        assert_eq!(PublishedPort::Assigned(Some([20000, 20100])).get_argument(8006, false), "20000-20100:8006");
+        assert_eq!(PublishedPort::Assigned(None).get_argument(8006, false), "8006");
+    }
+
+    #[test]
+    fn when_process_state_is_unknown_then_only_old_containers_are_removed() {
+        assert_eq!(is_process_running(std::process::id()), Some(true));
+
+        let now = get_unix_time();
+        assert!(is_older_than(now - ORPHAN_AGE.as_secs() - 1, now, ORPHAN_AGE));
+        assert!(!is_older_than(now - 60, now, ORPHAN_AGE));
+    }
+}
@@ -0,0 +1,136 @@
+use std::{fs, path::{Path, PathBuf}, process::Command};
+use anyhow::{anyhow, Result};
+use crate::{args, console, qemu, UUID};
+
+/// I/O port of the second `isa-debugcon` device, to which the kernel writes
+/// its raw LLVM profile (`.profraw`) after all tests have run
+pub const COVERAGE_IOBASE: &str = "0xea";
+
+/// Name of the directory the profiles are collected in, inside the testing directory
+const COVERAGE_DIRECTORY: &str = "coverage";
+
+/// Arguments for QEMU that add the coverage channel, which is written to a
+/// file in the testing directory.
+pub fn get_qemu_arguments() -> Result<Vec<String>> {
+    Ok(vec![
+        "-chardev".to_string(),
+        format!("file,id=coverage,path={}/coverage-{}.profraw", qemu::get_qemu_testing_dir()?, UUID.get().unwrap()),
+        "-device".to_string(),
+        format!("isa-debugcon,iobase={},chardev=coverage", COVERAGE_IOBASE)
+    ])
+}
+
+/// Move the profile written by the last QEMU run into the coverage directory,
+/// named after the test group, together with a copy of the kernel ELF that
+/// `llvm-cov` needs to map the counters back to source lines.
+pub fn collect_profile(test_group: &str) -> Result<()> {
+    let testing_dir = args::get_build_directory()?.join("testing");
+    let profile_path = testing_dir.join(format!("coverage-{}.profraw", UUID.get().unwrap()));
+    if !profile_path.exists() {
+        return Ok(());
+    }
+
+    if fs::metadata(&profile_path)?.len() == 0 {
+        log::warn!("Kernel did not write a coverage profile for {}", test_group);
+        fs::remove_file(&profile_path)?;
+        return Ok(());
+    }
+
+    let coverage_dir = testing_dir.join(COVERAGE_DIRECTORY);
+    fs::create_dir_all(&coverage_dir)?;
+
+    // retries of the same group are merged with the original run
+    let mut file_name = format!("{}.profraw", test_group);
+    let mut attempt = 1;
+    while coverage_dir.join(&file_name).exists() {
+        attempt += 1;
+        file_name = format!("{}-{}.profraw", test_group, attempt);
+    }
+
+    fs::rename(&profile_path, coverage_dir.join(file_name))?;
+    fs::copy(args::get_executable()?, coverage_dir.join(format!("{}.elf", test_group)))?;
+    Ok(())
+}
+
+/// Move the profiles of the round into the timestamped round directory,
+/// merge them and export an lcov report to `coverage/lcov.info`.
+pub fn generate_report(testing_dir: &Path, round_dir: &Path) -> Result<()> {
+    let source_dir = testing_dir.join(COVERAGE_DIRECTORY);
+    if !source_dir.exists() {
+        return Ok(());
+    }
+
+    let coverage_dir = round_dir.join(COVERAGE_DIRECTORY);
+    fs::rename(&source_dir, &coverage_dir)?;
+
+    let mut profiles = Vec::new();
+    let mut objects = Vec::new();
+    for entry in fs::read_dir(&coverage_dir)? {
+        let path = entry?.path();
+        match path.extension().and_then(|s| s.to_str()) {
+            Some("profraw") => profiles.push(path),
+            Some("elf") => objects.push(path),
+            _ => {}
+        }
+    }
+    if profiles.is_empty() {
+        return Ok(());
+    }
+    profiles.sort();
+    objects.sort();
+
+    let profdata_path = coverage_dir.join("merged.profdata");
+    let merge_output = Command::new(find_llvm_tool("llvm-profdata"))
+        .args(["merge", "-sparse"])
+        .args(&profiles)
+        .arg("-o").arg(&profdata_path)
+        .output()
+        .map_err(|e| anyhow!("Failed to run llvm-profdata (install it with `rustup component add llvm-tools`): {}", e))?;
+    if !merge_output.status.success() {
+        return Err(anyhow!("llvm-profdata failed: {}", String::from_utf8_lossy(&merge_output.stderr)));
+    }
+
+    let mut export_command = Command::new(find_llvm_tool("llvm-cov"));
+    export_command.args(["export", "-format=lcov"])
+        .arg("-instr-profile").arg(&profdata_path);
+    for (index, object) in objects.iter().enumerate() {
+        // the first object is positional, further objects need -object
+        if index > 0 {
+            export_command.arg("-object");
+        }
+        export_command.arg(object);
+    }
+
+    let export_output = export_command.output()
+        .map_err(|e| anyhow!("Failed to run llvm-cov (install it with `rustup component add llvm-tools`): {}", e))?;
+    if !export_output.status.success() {
+        return Err(anyhow!("llvm-cov failed: {}", String::from_utf8_lossy(&export_output.stderr)));
+    }
+
+    let lcov_path = coverage_dir.join("lcov.info");
+    fs::write(&lcov_path, &export_output.stdout)?;
+
+    console::print_human(&format!("kboot: coverage report written to {}", lcov_path.display()));
+    log::info!("Coverage report written to {}", lcov_path.display());
+    Ok(())
+}
+
+/// Find an LLVM tool from the `llvm-tools` rustup component, which matches the
+/// LLVM version of rustc, falling back to the tool on the PATH.
+fn find_llvm_tool(name: &str) -> PathBuf {
+    let sysroot = Command::new("rustc")
+        .args(["--print", "sysroot"])
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .map(|output| PathBuf::from(String::from_utf8_lossy(&output.stdout).trim()));
+
+    let bundled = sysroot.and_then(|sysroot| {
+        fs::read_dir(sysroot.join("lib").join("rustlib")).ok()?
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.path().join("bin").join(name))
+            .find(|path| path.exists())
+    });
+
+    bundled.unwrap_or_else(|| PathBuf::from(name))
+}
@@ -0,0 +1,221 @@
+use std::fs;
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use crate::{args, console, ktest::{TestGroup, TestModule, TestOutcome, TestResult}, perf, qemu::{QemuExitCode, QemuRun}, BUILD_DIRECTORY};
+
+/// Number of serial console lines attached to a crash report
+const SERIAL_TAIL_LINES: usize = 20;
+
+/// Determines whether the kernel died before all tests of the group were reported,
+/// e.g. by a triple fault or a panic outside of ktest's handler. If QEMU was
+/// stopped by the timeout, the running test timed out instead of crashing, and
+/// if kboot was interrupted, nothing crashed and the unfinished tests did not run.
+///
+/// The test that was running is taken from the last test start record without a
+/// result. If ktest did not send one, it is inferred from the order in which the
+/// tests were reported in the most recent round that contains this group: the first
+/// test that was not reported crashed, and the remaining tests did not run.
+pub fn attribute_crash(test_group: &mut TestGroup, started_test: Option<&str>, qemu_run: &QemuRun, serial_lines: &[(u128, String)]) -> Result<()> {
+    let reported = test_group.modules.iter().map(|m| m.tests.len() as u64).sum::<u64>();
+    let incomplete = reported < test_group.summary.total
+        && (qemu_run.exit_code != QemuExitCode::Success as i32 || qemu_run.timed_out || qemu_run.interrupted);
+    if started_test.is_none() && !incomplete {
+        return Ok(());
+    }
+
+    let previous_order = get_previous_test_order(&test_group.test_group)?;
+    record_crash(test_group, started_test, previous_order, qemu_run, serial_lines);
+    Ok(())
+}
+
+/// Add the crashed (or timed out) test and the tests that did not run to the group, given
+/// the order in which its tests were reported before, and attach the crash report.
+fn record_crash(test_group: &mut TestGroup, started_test: Option<&str>, previous_order: Vec<String>, qemu_run: &QemuRun, serial_lines: &[(u128, String)]) {
+    let mut missing_tests = previous_order.into_iter()
+        .filter(|name| !is_reported(test_group, name))
+        .collect::<Vec<String>>();
+
+    let crashed_test = match started_test {
+        Some(test) => Some(test.to_string()),
+        None if !missing_tests.is_empty() => Some(missing_tests[0].clone()),
+        None => None
+    };
+    missing_tests.retain(|name| Some(name) != crashed_test.as_ref());
+
+    if qemu_run.interrupted {
+        for test in crashed_test.iter().chain(&missing_tests) {
+            add_test(test_group, test, TestOutcome::NotRun, Some("kboot was interrupted before this test finished".to_string()));
+        }
+        return;
+    }
+
+    if let Some(test) = &crashed_test {
+        if qemu_run.timed_out {
+            add_test(test_group, test, TestOutcome::Timeout, Some("QEMU was stopped by the timeout while this test was running".to_string()));
+        } else {
+            let message = format!("kernel stopped while this test was running (QEMU exit code {})", qemu_run.exit_code);
+            add_test(test_group, test, TestOutcome::Crashed, Some(message));
+        }
+    }
+    for test in &missing_tests {
+        add_test(test_group, test, TestOutcome::NotRun, None);
+    }
+
+    let serial_tail = serial_lines.iter()
+        .skip(serial_lines.len().saturating_sub(SERIAL_TAIL_LINES))
+        .map(|(_, line)| line.clone())
+        .collect();
+
+    test_group.crash = Some(CrashReport {
+        test: crashed_test,
+        exit_code: qemu_run.exit_code,
+        serial_tail
+    });
+}
+
+/// Print the crash of the test group to the terminal.
+pub fn print_crash(test_group: &TestGroup) {
+    let Some(crash) = &test_group.crash else {
+        return;
+    };
+
+    let test = crash.test.as_deref().unwrap_or("an unknown test");
+    console::print_human(&format!("kboot: kernel crashed in {} while running {} (QEMU exit code {}); {} test(s) did not run",
+        test_group.test_group, test, crash.exit_code, test_group.summary.not_run));
+    log::error!("Kernel crashed in {} while running {} (QEMU exit code {})", test_group.test_group, test, crash.exit_code);
+
+    if !crash.serial_tail.is_empty() {
+        console::print_human("kboot: last serial console output:");
+        for line in &crash.serial_tail {
+            console::print_human(&format!("    {}", line));
+        }
+    }
+}
+
+fn is_reported(test_group: &TestGroup, name: &str) -> bool {
+    test_group.modules.iter()
+        .any(|m| m.tests.iter().any(|t| format!("{}::{}", m.module, t.test) == name))
+}
+
+/// Add a test result for a test that was not reported by ktest.
+fn add_test(test_group: &mut TestGroup, name: &str, outcome: TestOutcome, message: Option<String>) {
+    let (module_name, function_name) = match name.rsplit_once("::") {
+        Some((module, function)) => (module.to_string(), function.to_string()),
+        None => ("unknown".to_string(), name.to_string())
+    };
+
+    let test = TestResult::new(function_name, outcome, 0, None, message);
+    if let Some(module) = test_group.modules.iter_mut().find(|m| m.module == module_name) {
+        module.tests.push(test);
+    } else {
+        test_group.modules.push(TestModule {
+            module: module_name,
+            tests: vec![test],
+        });
+    }
+}
+
+/// Get the fully qualified names of the tests in the group in the order in which
+/// they were reported, from the most recent round that contains the group.
+fn get_previous_test_order(test_group: &str) -> Result<Vec<String>> {
+    let build_dir = args::get_workspace_root()?.join(BUILD_DIRECTORY);
+    let previous_path = perf::get_round_directories(&build_dir)?.into_iter()
+        .map(|dir| dir.join(format!("tests-{}.json", test_group)))
+        .find(|path| path.exists());
+
+    let Some(previous_path) = previous_path else {
+        return Ok(Vec::new());
+    };
+
+    let content = fs::read_to_string(&previous_path)?;
+    let Ok(previous) = serde_json::from_str::<TestGroup>(&content) else {
+        log::warn!("Skipping unreadable test history {}", previous_path.display());
+        return Ok(Vec::new());
+    };
+
+    Ok(previous.modules.iter()
+        .flat_map(|m| m.tests.iter()
+            .filter(|t| t.outcome != TestOutcome::NotRun)
+            .map(move |t| format!("{}::{}", m.module, t.test)))
+        .collect())
+}
+
+/// CrashReport describes a kernel that stopped before all tests were reported.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CrashReport {
+    /// Fully qualified name of the test that was running, if known
+    pub test: Option<String>,
+    pub exit_code: i32,
+    /// The last lines of the serial console
+    pub serial_tail: Vec<String>
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use crate::parser::TestResultParser;
+    use super::*;
+
+    fn get_test_group() -> TestGroup {
+        let output = [
+            r#"{"test_group":"kernel","test_count":3,"use_kview":false}"#,
+            r#"{"test":"kernel::memory::test_alloc","result":"pass","cycle_count":1200}"#
+        ].join("\n");
+        TestResultParser::parse(&output).unwrap()
+    }
+
+    fn get_previous_order() -> Vec<String> {
+        ["kernel::memory::test_alloc", "kernel::memory::test_free", "kernel::memory::test_realloc"]
+            .map(String::from).to_vec()
+    }
+
+    fn get_qemu_run(exit_code: i32, timed_out: bool) -> QemuRun {
+        QemuRun { duration: Duration::from_secs(1), exit_code, timed_out, interrupted: false }
+    }
+
+    fn get_outcome(test_group: &TestGroup, test: &str) -> Option<TestOutcome> {
+        test_group.modules.iter()
+            .flat_map(|m| m.tests.iter())
+            .find(|t| t.test == test)
+            .map(|t| t.outcome.clone())
+    }
+
+    #[test]
+    fn when_kernel_crashes_without_start_record_then_first_unreported_test_crashed() {
+        let mut test_group = get_test_group();
+        let serial_lines = (0..30).map(|i| (i as u128, format!("line {}", i))).collect::<Vec<_>>();
+
+        record_crash(&mut test_group, None, get_previous_order(), &get_qemu_run(1, false), &serial_lines);
+
+        assert_eq!(get_outcome(&test_group, "test_free"), Some(TestOutcome::Crashed));
+        assert_eq!(get_outcome(&test_group, "test_realloc"), Some(TestOutcome::NotRun));
+        let crash = test_group.crash.unwrap();
+        assert_eq!(crash.test.as_deref(), Some("kernel::memory::test_free"));
+        assert_eq!(crash.serial_tail.len(), SERIAL_TAIL_LINES);
+        assert_eq!(crash.serial_tail[0], "line 10");
+    }
+
+    #[test]
+    fn when_started_test_times_out_then_it_is_reported_as_timeout() {
+        let mut test_group = get_test_group();
+
+        record_crash(&mut test_group, Some("kernel::memory::test_realloc"), get_previous_order(), &get_qemu_run(0, true), &[]);
+
+        assert_eq!(get_outcome(&test_group, "test_realloc"), Some(TestOutcome::Timeout));
+        assert_eq!(get_outcome(&test_group, "test_free"), Some(TestOutcome::NotRun));
+        assert_eq!(test_group.crash.unwrap().test.as_deref(), Some("kernel::memory::test_realloc"));
+    }
+
+    #[test]
+    fn when_run_is_interrupted_then_unfinished_tests_did_not_run() {
+        let mut test_group = get_test_group();
+        let qemu_run = QemuRun { interrupted: true, ..get_qemu_run(130, false) };
+
+        record_crash(&mut test_group, Some("kernel::memory::test_free"), get_previous_order(), &qemu_run, &[]);
+
+        assert_eq!(get_outcome(&test_group, "test_alloc"), Some(TestOutcome::Pass));
+        assert_eq!(get_outcome(&test_group, "test_free"), Some(TestOutcome::NotRun));
+        assert_eq!(get_outcome(&test_group, "test_realloc"), Some(TestOutcome::NotRun));
+        assert!(test_group.crash.is_none());
+    }
+}
@@ -1,74 +1,80 @@
 use std::path::PathBuf;
 
-use anyhow::Result;
-use crate::BUILD_DIRECTORY;
+use anyhow::{anyhow, Context, Result};
 
 /// Writes an event to the event log file in compact JSON format.
-pub fn write_event(event: &dyn Event) {
+pub fn write_event(event: &dyn Event) -> Result<()> {
     use std::io::Write;
     
-    let event_log_path = get_event_log_path().unwrap();
+    let event_log_path = get_event_log_path()?;
     let mut file = std::fs::OpenOptions::new()
         .append(true)
         .open(&event_log_path)
-        .unwrap();
+        .with_context(|| format!("Failed to open event log {}", event_log_path.display()))?;
 
-    let event_json = event.to_json().unwrap();
-    writeln!(file, "{}", event_json.replace("\n", "")).unwrap();
+    let event_json = event.to_json()?;
+    writeln!(file, "{}", event_json.replace("\n", ""))
+        .with_context(|| format!("Failed to write to event log {}", event_log_path.display()))?;
+
+    Ok(())
 }
 
 /// Writes start events for a test group and possibly a test round.
 pub fn write_start_events() -> Result<TestGroupStartedEvent> {
-    if is_start_of_test_round() {
+    if is_start_of_test_round()? {
         let round_started_event = TestRoundStartedEvent;
-        write_event(&round_started_event);
+        write_event(&round_started_event)?;
     }
 
-    let test_group_event = TestGroupStartedEvent::new();
-    write_event(&test_group_event);
+    let test_group_event = TestGroupStartedEvent::new()?;
+    write_event(&test_group_event)?;
 
     Ok(test_group_event)
 }
 
 /// Writes end events for possibly a test round.
+///
+/// An interrupted session always ends the round, so that the next run starts a new one.
 pub fn write_end_events(start_event: &TestGroupStartedEvent) -> Result<()> {
-    if start_event.current_test_group + 1 >= start_event.total_test_groups {
+    if crate::interrupt::is_interrupted() {
+        write_event(&TestRoundInterruptedEvent)?;
+    } else if start_event.current_test_group + 1 >= start_event.total_test_groups {
         let round_ended_event = TestRoundEndedEvent;
-        write_event(&round_ended_event);
+        write_event(&round_ended_event)?;
     }
 
     Ok(())
 }
 
 /// Reads the event log to determine the current test group index.
-pub fn get_current_test_group() -> usize {
-    use std::io::BufRead;
-
-    let event_log_path = get_event_log_path().unwrap();
-    let file = std::fs::File::open(&event_log_path).unwrap();
-    let reader = std::io::BufReader::new(file);
-    let lines: Vec<String> = reader.lines().map(|l| l.unwrap()).collect();
+pub fn get_current_test_group() -> Result<usize> {
+    let lines = read_event_log()?;
 
     for line in lines.iter().rev() {
-        if line.contains("TestRoundEndedEvent") {
-            return 0; // if previous round ended, start from first test group
+        if line.contains("TestRoundEndedEvent") || line.contains("TestRoundInterruptedEvent") {
+            return Ok(0); // if previous round ended, start from first test group
         }
 
         if line.contains("TestGroupStartedEvent") {
-            let json: serde_json::Value = serde_json::from_str(line).unwrap();
-            let current_test_group = json.get("current_test_group").unwrap().as_u64().unwrap();
-            return (current_test_group as usize) + 1;
+            let json: serde_json::Value = serde_json::from_str(line)
+                .with_context(|| format!("Malformed event in the event log: {}", line))?;
+            let current_test_group = json.get("current_test_group")
+                .and_then(|group| group.as_u64())
+                .ok_or_else(|| anyhow!("Event in the event log has no current_test_group: {}", line))?;
+            return Ok((current_test_group as usize) + 1);
         }
     }
 
-    0 // default to first test group
+    Ok(0) // default to first test group
 }
 
 /// Determines the total number of test groups based on the Cargo workspace structure.
-pub fn get_total_test_groups() -> usize {
+pub fn get_total_test_groups() -> Result<usize> {
     let manifest_toml_path = PathBuf::from("Cargo.toml");
-    let manifest_content = std::fs::read_to_string(manifest_toml_path).unwrap();
-    let manifest: toml::Value = toml::from_str(&manifest_content).unwrap();
+    let manifest_content = std::fs::read_to_string(&manifest_toml_path)
+        .with_context(|| format!("Failed to read {} in the current directory", manifest_toml_path.display()))?;
+    let manifest: toml::Value = toml::from_str(&manifest_content)
+        .with_context(|| format!("Failed to parse {}", manifest_toml_path.display()))?;
 
     let is_workspace = manifest.get("workspace").is_some();
     if is_workspace {
@@ -76,44 +82,49 @@ pub fn get_total_test_groups() -> usize {
             .get("workspace")
             .and_then(|ws| ws.get("members"))
             .and_then(|m| m.as_array())
-            .unwrap();
+            .ok_or_else(|| anyhow!("[workspace] in {} has no members", manifest_toml_path.display()))?;
 
-        members.len() + 1 // + 1 for the binary (main.rs)
+        Ok(members.len() + 1) // + 1 for the binary (main.rs)
     } else {
-        2 // 1 for binary (main.rs), 1 for library (lib.rs)
+        Ok(2) // 1 for binary (main.rs), 1 for library (lib.rs)
     }
 }
 
 /// Determines if the current execution is the start of a new test round.
-pub fn is_start_of_test_round() -> bool {
-    use std::io::BufRead;
-
-    let event_log_path = get_event_log_path().unwrap();
-    let file = std::fs::File::open(&event_log_path).unwrap();
-    let reader = std::io::BufReader::new(file);
-    let lines: Vec<String> = reader.lines().map(|l| l.unwrap()).collect();
+pub fn is_start_of_test_round() -> Result<bool> {
+    let lines = read_event_log()?;
     
     for line in lines.iter().rev() {
-        if line.contains("TestRoundEndedEvent") { // previous round was ended, start of new round
-            return true;
+        if line.contains("TestRoundEndedEvent") || line.contains("TestRoundInterruptedEvent") { // previous round was ended, start of new round
+            return Ok(true);
         }
         if line.contains("TestRoundStartedEvent") { // round already started
-            return false;
+            return Ok(false);
         }
     }
 
-    true // first round ever
+    Ok(true) // first round ever
+}
+
+/// Reads all lines of the event log.
+fn read_event_log() -> Result<Vec<String>> {
+    let event_log_path = get_event_log_path()?;
+    let content = std::fs::read_to_string(&event_log_path)
+        .with_context(|| format!("Failed to read event log {}", event_log_path.display()))?;
+
+    Ok(content.lines().map(|line| line.to_string()).collect())
 }
 
 /// Gets the path to the event log file, creating it if necessary.
 fn get_event_log_path() -> Result<std::path::PathBuf> {
-    let workspace_directory = crate::args::get_workspace_root()?;
-    let event_log_path = workspace_directory.join(BUILD_DIRECTORY)
-        .join("event.log.json");
+    let build_directory = crate::args::get_build_directory()?;
+    let event_log_path = build_directory.join("event.log.json");
 
-    std::fs::create_dir_all(event_log_path.parent().unwrap())?;
+    std::fs::create_dir_all(&build_directory)
+        .with_context(|| format!("Failed to create build directory {}", build_directory.display()))?;
     if std::fs::metadata(&event_log_path).is_err() {
-        std::fs::File::create(&event_log_path)?;
+        std::fs::File::create(&event_log_path)
+            .with_context(|| format!("Failed to create event log {}", event_log_path.display()))?;
     }   
 
     Ok(event_log_path)
@@ -129,8 +140,8 @@ pub trait Event {
     fn timestamp(&self) -> u128 {
         std::time::SystemTime::now()
             .duration_since(std::time::UNIX_EPOCH)
-            .unwrap()
-            .as_millis()
+            .map(|d| d.as_millis())
+            .unwrap_or(0)
     }
 
     fn to_json(&self) -> Result<String> {
@@ -151,6 +162,11 @@ pub struct TestRoundEndedEvent;
 
 impl Event for TestRoundEndedEvent {}
 
+/// Event indicating that a test round was ended early by Ctrl-C.
+pub struct TestRoundInterruptedEvent;
+
+impl Event for TestRoundInterruptedEvent {}
+
 /// Event indicating the start of a test group within a test round.
 pub struct TestGroupStartedEvent {
     pub current_test_group: usize,
@@ -158,11 +174,11 @@ pub struct TestGroupStartedEvent {
 }
 
 impl TestGroupStartedEvent {
-    pub fn new() -> Self {
-        let current_test_group = get_current_test_group();
-        let total_test_groups = get_total_test_groups();
+    pub fn new() -> Result<Self> {
+        let current_test_group = get_current_test_group()?;
+        let total_test_groups = get_total_test_groups()?;
 
-        Self { current_test_group, total_test_groups }
+        Ok(Self { current_test_group, total_test_groups })
     }
 }
 
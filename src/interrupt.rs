@@ -0,0 +1,37 @@
+use std::{process::Command, sync::atomic::{AtomicBool, Ordering}};
+use anyhow::Result;
+use crate::{config::{self, VmBackend}, console, container, UUID};
+
+/// Exit code of an interrupted run, as shells report it for SIGINT
+pub const INTERRUPTED_EXIT_CODE: i32 = 130;
+
+/// Whether Ctrl-C was pressed during this session
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+/// Handle Ctrl-C (SIGINT) by marking the session as interrupted. The VM is
+/// then stopped by the thread waiting for it, and the results collected so
+/// far are archived as an interrupted round.
+///
+/// A second Ctrl-C exits immediately, killing the container of this session.
+pub fn install_handler() -> Result<()> {
+    ctrlc::set_handler(|| {
+        if INTERRUPTED.swap(true, Ordering::SeqCst) {
+            if config::get_config().is_ok_and(|config| config.vm.backend == VmBackend::Docker)
+                && let Some(session) = UUID.get() {
+                let _ = Command::new("docker")
+                    .args(["kill", &container::get_qemu_container_name(session)])
+                    .output();
+            }
+            std::process::exit(INTERRUPTED_EXIT_CODE);
+        }
+
+        log::warn!("Interrupted, stopping QEMU and keeping the results collected so far");
+        console::print_live("kboot: interrupted, stopping QEMU (press Ctrl-C again to exit immediately)");
+    })?;
+
+    Ok(())
+}
+
+pub fn is_interrupted() -> bool {
+    INTERRUPTED.load(Ordering::SeqCst)
+}
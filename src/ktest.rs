@@ -1,14 +1,16 @@
-use std::{fs, io, sync::{OnceLock, RwLock}, time::Duration};
+use std::{collections::BTreeMap, fs, path::PathBuf, sync::{Mutex, RwLock}, time::Duration};
 use anyhow::{anyhow, Result};
 use serde::{Deserialize, Serialize};
-use std::io::BufRead;
-use crate::{args, event::TestGroupStartedEvent, kview, BUILD_DIRECTORY};
+use crate::{args, console::{self, SerialLogLink}, coverage, crash::{self, CrashReport}, event::TestGroupStartedEvent, interrupt, kview, matrix::{self, MatrixEntry}, perf::{self, PerformanceComparison}, parser::TestResultParser, protocol::{Message, TestResultRecord, PROTOCOL_VERSION}, qemu::{QemuExitCode, QemuRun}, report, retry, screenshot, snapshot};
 
-/// A global, thread-safe storage for the test group being processed.
-static USE_KVIEW: OnceLock<RwLock<bool>> = OnceLock::new();
+/// A global, thread-safe storage for whether kview was requested by the test group.
+static USE_KVIEW: RwLock<bool> = RwLock::new(false);
 
-/// A global, thread-safe storage for the test group being processed.
-static TEST_GROUP: OnceLock<RwLock<TestGroup>> = OnceLock::new();
+/// A global, thread-safe storage for the parser of the test group being processed.
+static PARSER: RwLock<TestResultParser> = RwLock::new(TestResultParser::new());
+
+/// A global, thread-safe storage for the -debugcon stream of the running QEMU instance.
+static STREAM: Mutex<DebugconStream> = Mutex::new(DebugconStream::new());
 
 /// Tests from `'ktest` are delivered through the -debugcon device
 /// in a line-by-line fashion. Each line is a JSON object that
@@ -16,260 +18,702 @@ static TEST_GROUP: OnceLock<RwLock<TestGroup>> = OnceLock::new();
 /// 
 /// This function collects those lines and uses the power of 
 /// the standard library to parse them into structured data.
-pub fn process_test_results(start_event: &TestGroupStartedEvent, run_duration: Duration) -> Result<()> {
+/// 
+/// Returns the exit code that represents the test group, which differs
+/// from the QEMU exit code if all failed tests passed when retried.
+/// 
+/// With a test matrix, this is called for each of its `matrix_runs` configurations, and
+/// the round is only finished after the final one (`matrix_run` counts from 0).
+pub fn process_test_results(start_event: &TestGroupStartedEvent, qemu_run: &QemuRun, matrix_run: usize, matrix_runs: usize) -> Result<i32> {
     if !args::is_test()? { // ignore this for `cargo run` etc
-        return Ok(());
+        return Ok(qemu_run.exit_code);
     }
 
-    let workspace_dir = args::get_workspace_root()?;
-    let qemu_output_path = workspace_dir.join(BUILD_DIRECTORY)
-        .join("testing")
-        .join(format!("tests-{}.json", crate::UUID.get().unwrap()));
-
-    if !qemu_output_path.exists() { // if nothing exists, nothing to process
-        return Ok(());
+    // with `kboot test`, the report is written and the round finished once all executables ran
+    let is_first_group = start_event.current_test_group == 0 && matrix_run == 0;
+    if is_first_group && !args::is_isolated_run() {
+        report::start_report()?;
     }
 
-    let qemu_outputfile = fs::File::open(&qemu_output_path)?;
-    let reader = io::BufReader::new(qemu_outputfile);
+    // an interrupted round is archived with the results collected so far
+    let is_final_run = matrix_run + 1 == matrix_runs;
+    let is_final_group = (start_event.current_test_group + 1 >= start_event.total_test_groups && is_final_run || interrupt::is_interrupted())
+        && !args::is_isolated_run();
 
-    log::info!("====================  <test results>  ====================");
-    for line_result in reader.lines() {
-        let line = line_result?; 
-        log::info!("{}", line);
-        process_json_line(&line, run_duration)?;
+    let Some(mut test_group) = collect_test_group(qemu_run)? else {
+        // nothing to process, but the round still ends with the final group
+        if is_final_group {
+            finish_round()?;
+        }
+        return Ok(qemu_run.exit_code);
+    };
+    let mut exit_code = retry::retry_failed_tests(&mut test_group, qemu_run)?;
+    if test_group.crash.is_some() {
+        exit_code = QemuExitCode::Failed as i32;
     }
+    perf::analyze_test_group(&mut test_group)?;
 
-    process_summary()?;
-
-    let test_group = TEST_GROUP.get()
-        .ok_or_else(|| anyhow!("No test group found after processing test results"))?
-        .read()
-        .map_err(|_| anyhow!("Failed to acquire read lock on test group"))?;
-    let test_output_path = workspace_dir.join(BUILD_DIRECTORY)
+    let test_output_path = args::get_build_directory()?
         .join("testing")
         .join(format!("tests-{}.json", test_group.test_group));
     let test_output_file = fs::File::create(&test_output_path)?;
 
-    serde_json::to_writer_pretty(&test_output_file, &*test_group)?;
-    fs::remove_file(&qemu_output_path)?;
+    serde_json::to_writer_pretty(&test_output_file, &test_group)?;
+    if !args::is_isolated_run() {
+        report::write_report(&test_group)?;
+    }
+    matrix::record_result(&test_group);
+
+    crash::print_crash(&test_group);
+    retry::print_flaky_tests(&test_group);
+    perf::print_regressions(&test_group);
+    // concurrent test executables would overwrite each other's baseline
+    if args::is_save_baseline() && !args::is_isolated_run() {
+        perf::save_baseline(std::slice::from_ref(&test_group))?;
+    }
 
-    let is_final_group = start_event.current_test_group + 1 >= start_event.total_test_groups;
     if is_final_group {
-        // wait 2 seconds for file operations to settle (race caused issues in the past)
-        std::thread::sleep(Duration::from_secs(2));
+        finish_round()?;
+    }
+
+    perf::check_fail_threshold(&test_group)?;
+    Ok(exit_code)
+}
+
+/// Finish the round after its final group: complete the report, archive the
+/// testing files and start kview if the test groups asked for it.
+fn finish_round() -> Result<()> {
+    // wait 2 seconds for file operations to settle (race caused issues in the past)
+    std::thread::sleep(Duration::from_secs(2));
+
+    report::finish_report()?;
+    process_final_json()?;
+    let use_kview = USE_KVIEW.read()
+        .map_err(|_| anyhow!("Failed to acquire read lock on use_kview"))?;
+
+    // nobody would look at kview in headless runs
+    if *use_kview && !args::is_headless() {
+        kview::start_kview_if_needed()?;
+    }
+    Ok(())
+}
+
+/// Reset the test group before QEMU starts, so that the -debugcon output
+/// can be processed line by line while the kernel is running.
+pub fn start_stream() {
+    if let Ok(mut parser) = PARSER.write() {
+        *parser = TestResultParser::new();
+    }
+    if let Ok(mut stream) = STREAM.lock() {
+        *stream = DebugconStream::new();
+    }
 
-        process_final_json()?;
-        let use_kview = USE_KVIEW.get()
-            .ok_or_else(|| anyhow!("No use_kview flag found after processing test results"))?
-            .read()
-            .map_err(|_| anyhow!("Failed to acquire read lock on use_kview"))?;
+    log::info!("====================  <test results>  ====================");
+}
 
-        if use_kview.clone() {
-            kview::start_kview_if_needed()?;
+/// Process a single line of -debugcon output as soon as QEMU has written it.
+/// Each completed test is printed in the style of libtest, and the partial test
+/// group is kept up to date, so a crash still yields every test that ran before it.
+/// 
+/// Lines that cannot be processed are recorded as diagnostics and skipped.
+pub fn process_streamed_line(line: &str, timestamp: u128) {
+    log::info!("{}", line);
+
+    // QMP commands can take seconds, so they are executed once the stream is unlocked
+    let action = match STREAM.lock() {
+        Ok(mut stream) => process_line(&mut stream, line, timestamp),
+        Err(_) => return
+    };
+    match action {
+        Some(StreamAction::Screenshot(test)) => capture_screenshot(&test),
+        Some(StreamAction::SnapshotPoint(name, prelude)) => snapshot::on_snapshot_point(&name, &prelude),
+        None => {}
+    }
+}
+
+/// Replay the -debugcon output that a restored snapshot was taken after, since the
+/// restored kernel continues from its snapshot point and does not write it again.
+pub fn replay_streamed_lines(lines: &[String]) {
+    for line in lines {
+        process_streamed_line(line, console::timestamp_millis());
+    }
+}
+
+/// Process a line of -debugcon output into the stream, and return the action that
+/// the line requires once the stream is unlocked.
+fn process_line(stream: &mut DebugconStream, line: &str, timestamp: u128) -> Option<StreamAction> {
+    stream.line_count += 1;
+
+    let parsed = PARSER.write()
+        .map_err(|_| anyhow!("Failed to acquire write lock on test result parser"))
+        .and_then(|mut parser| parser.parse_line(line));
+    if !matches!(parsed, Ok(Message::SnapshotPoint(_))) && let Some(prelude) = stream.prelude.as_mut() {
+        prelude.push(line.to_string());
+    }
+    let message = match parsed {
+        Ok(message) => message,
+        Err(e) => {
+            log::warn!("Skipping invalid test output line {}: {}", stream.line_count, e);
+            stream.add_diagnostic(line, e.to_string());
+            return None;
+        }
+    };
+
+    let mut action = None;
+
+    match message {
+        Message::SnapshotPoint(snapshot_point) => {
+            let prelude = stream.prelude.take().unwrap_or_default();
+            action = Some(StreamAction::SnapshotPoint(snapshot_point.snapshot_point, prelude));
+        },
+        Message::Handshake(handshake) => {
+            if handshake.ktest_protocol > PROTOCOL_VERSION {
+                stream.add_diagnostic(line, format!("ktest protocol version {} is newer than the supported version {}, unknown fields are ignored",
+                    handshake.ktest_protocol, PROTOCOL_VERSION));
+            }
+            stream.protocol_version = handshake.ktest_protocol;
+        },
+        Message::TestGroupStart(group_start) => {
+            console::print_live(&format!("running {} tests", group_start.test_count));
+        },
+        Message::TestStart(test_start) => {
+            stream.started_test = Some(test_start.test_start);
+        },
+        Message::TestResult(record) => {
+            let outcome = TestOutcome::from_record(&record);
+            let status = match &outcome {
+                Some(outcome) => outcome.label(),
+                None => {
+                    stream.add_diagnostic(line, format!("unknown result '{}', recorded as a failure", record.result));
+                    "FAILED"
+                }
+            };
+            console::print_live(&format!("test {} ... {}", record.test, status));
+
+            if outcome.as_ref().is_none_or(TestOutcome::is_failure) {
+                action = Some(StreamAction::Screenshot(record.test.clone()));
+            }
+            if record.cycle_count.is_none() {
+                stream.add_diagnostic(line, "cycle_count is missing, recorded as 0".to_string());
+            }
+            if stream.started_test.as_deref() == Some(record.test.as_str()) {
+                stream.started_test = None;
+            }
+            if let Some(start) = stream.last_timestamp {
+                stream.test_windows.push((record.test, start, timestamp));
+            }
         }
     }
 
-    Ok(())
+    stream.last_timestamp = Some(timestamp);
+    action
+}
+
+/// Capture the display of the VM for the test that is running, before QEMU is
+/// stopped by the timeout. Benchmark runs are not captured.
+pub fn capture_running_test_screenshot() {
+    let test = STREAM.lock().ok()
+        .map(|stream| stream.started_test.clone().unwrap_or_default());
+    if let Some(test) = test {
+        capture_screenshot(&test);
+    }
 }
 
-/// Process a single line of JSON input from the test output. This function 
-/// updates the global TEST_GROUP state as needed. If a line contained a test 
-/// result, it is added to the appropriate module within the test group.
-fn process_json_line(line: &str, run_duration: Duration) -> Result<()> {
-    let json: serde_json::Value = serde_json::from_str(line)?;
-    
-    if json.get("test_group").is_some() {
-        let test_group = process_test_group_json(&json, run_duration)?;
-        TEST_GROUP.set(RwLock::new(test_group.0))
-            .map_err(|_| anyhow!("Test group already set"))?;
-        USE_KVIEW.set(RwLock::new(test_group.1))
-            .map_err(|_| anyhow!("Use kview already set"))?;
-    } else if json.get("test").is_some() {
-        let mut test = process_test_json(&json)?;
-        let mut test_group = TEST_GROUP.get()
-            .ok_or_else(|| anyhow!("Test group not set before test result"))?
-            .write()
-            .map_err(|_| anyhow!("Failed to acquire write lock on test group"))?;
-        
-        let module_name = module_from_name(&test.test);
-        test.test = function_from_name(&test.test);
-
-        if let Some(module) = test_group.modules.iter_mut().find(|m| m.module == module_name) {
-            module.tests.push(test);
+/// Capture the display of the VM for a failed test, named after the test group and test.
+fn capture_screenshot(test: &str) {
+    if args::is_bench() {
+        return;
+    }
+
+    let group_name = PARSER.read().ok()
+        .and_then(|parser| parser.test_group().map(|g| g.test_group.clone()))
+        .unwrap_or_else(|| "unknown".to_string());
+    let test_name = if test.is_empty() { "running" } else { test };
+    let name = format!("screenshot-{}-{}", group_name, test_name.replace("::", "-"));
+
+    match screenshot::capture(&name) {
+        Ok(file) => if let Ok(mut stream) = STREAM.lock() {
+            stream.screenshots.push((test.to_string(), file));
+        },
+        Err(e) => log::warn!("Failed to capture screenshot for {}: {}", test_name, e)
+    }
+}
+
+/// Finishes the test group that was streamed from the -debugcon output of the
+/// last QEMU run and computes its summary. The raw output file is removed
+/// afterwards, so this may be called once per QEMU run (e.g. for benchmark runs).
+/// 
+/// If the serial console was captured, it is renamed after the test group and
+/// failed tests are linked to the serial output written while they ran.
+pub fn collect_test_group(qemu_run: &QemuRun) -> Result<Option<TestGroup>> {
+    let qemu_output_path = get_qemu_output_path()?;
+    if !qemu_output_path.exists() {
+        return Ok(None);
+    }
+
+    let stream = std::mem::replace(
+        &mut *STREAM.lock().map_err(|_| anyhow!("Failed to acquire lock on debugcon stream"))?,
+        DebugconStream::new()
+    );
+    let parser = std::mem::take(
+        &mut *PARSER.write().map_err(|_| anyhow!("Failed to acquire write lock on test result parser"))?
+    );
+    fs::remove_file(&qemu_output_path)?;
+
+    if parser.test_group().is_some() {
+        *USE_KVIEW.write().map_err(|_| anyhow!("Failed to acquire write lock on use_kview"))? = parser.use_kview();
+    }
+    let mut test_group = parser.finish();
+
+    if let Some(test_group) = test_group.as_mut() {
+        let serial_log_path = get_serial_log_path()?;
+        let serial_lines = if serial_log_path.exists() {
+            console::read_serial_log(&serial_log_path)?
         } else {
-            test_group.modules.push(TestModule {
-                module: module_name,
-                tests: vec![test],
-            });
+            Vec::new()
+        };
+
+        matrix::apply_to_test_group(test_group);
+        test_group.summary.duration = qemu_run.duration.as_millis() as u64;
+        test_group.protocol_version = stream.protocol_version;
+        test_group.diagnostics = stream.diagnostics;
+        crash::attribute_crash(test_group, stream.started_test.as_deref(), qemu_run, &serial_lines)?;
+        test_group.update_summary();
+        apply_stream_durations(test_group, &stream.test_windows);
+        attach_screenshots(test_group, &stream.screenshots);
+        if args::is_coverage() && let Err(e) = coverage::collect_profile(&test_group.test_group) {
+            log::warn!("Failed to collect the coverage profile of {}: {}", test_group.test_group, e);
+        }
+        process_serial_log(test_group, &stream.test_windows, &serial_lines)?;
+
+        let summary = &test_group.summary;
+        let mut result_line = format!("{} passed; {} failed; {} ignored", summary.passed, summary.failed, summary.ignored);
+        if test_group.crash.is_some() {
+            result_line.push_str(&format!("; {} crashed; {} not run", summary.crashed, summary.not_run));
         }
+        console::print_human(&format!("\ntest result: {}. {}; finished in {:.2}s\n",
+            if summary.failed + summary.crashed == 0 { "ok" } else { "FAILED" },
+            result_line, qemu_run.duration.as_secs_f64()));
     }
 
-    Ok(())
+    Ok(test_group)
 }
 
-/// Process a JSON object representing a test group and return a TestGroup struct.
-fn process_test_group_json(json: &serde_json::Value, run_duration: Duration) -> Result<(TestGroup, bool)> {
-    let name = json.get("test_group")
-        .and_then(|v| v.as_str())
-        .ok_or_else(|| anyhow!("test_group field is missing or not a string"))?
-        .to_string();
-    let test_count = json.get("test_count")
-        .and_then(|v| v.as_u64())
-        .ok_or_else(|| anyhow!("test_count field is missing or not a u64"))?;
-    let use_kview = json.get("use_kview")
-        .and_then(|v| v.as_bool())
-        .unwrap_or(false);
-
-    let summary = TestSummary {
-        total: test_count,
-        passed: 0,
-        failed: 0,
-        ignored: 0,
-        duration: run_duration.as_millis() as u64
-    };
+/// Reference each screenshot from the test it was captured for. A screenshot
+/// captured while no test was known to run belongs to the crashed test, if any.
+fn attach_screenshots(test_group: &mut TestGroup, screenshots: &[(String, String)]) {
+    let crashed_test = test_group.crash.as_ref().and_then(|c| c.test.clone());
+    for (test, file) in screenshots {
+        let full_name = if test.is_empty() {
+            crashed_test.clone().unwrap_or_default()
+        } else {
+            test.clone()
+        };
+
+        let mut attached = false;
+        for module in test_group.modules.iter_mut() {
+            for result in module.tests.iter_mut().filter(|t| format!("{}::{}", module.module, t.test) == full_name) {
+                result.screenshot = Some(file.clone());
+                attached = true;
+            }
+        }
 
-    Ok((TestGroup {
-        test_group: name,
-        summary,
-        modules: Vec::new()
-    }, use_kview))
+        if !attached {
+            log::warn!("Screenshot {} does not belong to a reported test", file);
+        }
+    }
 }
 
-/// Process a JSON object representing a test result and return a TestResult struct.
-fn process_test_json(json: &serde_json::Value) -> Result<TestResult> {
-    let test = json.get("test")
-        .and_then(|v| v.as_str())
-        .ok_or_else(|| anyhow!("test field is missing or not a string"))?
-        .to_string();
-    let result = json.get("result")
-        .and_then(|v| v.as_str())
-        .ok_or_else(|| anyhow!("result field is missing or not a string"))?
-        .to_string();
-    let cycle_count = json.get("cycle_count")
-        .and_then(|v| v.as_u64())
-        .ok_or_else(|| anyhow!("cycle_count field is missing or not a u64"))?;
-
-    let location = json.get("location")
-        .and_then(|v| v.as_str())
-        .map(|s| s.to_string());
-
-    let message = json.get("message")
-        .and_then(|v| v.as_str())
-        .map(|s| s.to_string());
-
-    Ok(TestResult {
-        test,
-        result,
-        cycle_count,
-        location,
-        message
-    })
+/// Use the time between the debugcon lines around each test as its estimated
+/// duration, for tests where the kernel did not measure one itself. This is limited
+/// by the resolution at which the debugcon output is polled.
+fn apply_stream_durations(test_group: &mut TestGroup, test_windows: &[(String, u128, u128)]) {
+    for module in test_group.modules.iter_mut() {
+        for test in module.tests.iter_mut().filter(|t| t.duration_us.is_none()) {
+            let full_name = format!("{}::{}", module.module, test.test);
+            test.estimated_duration_us = test_windows.iter()
+                .find(|(name, _, _)| *name == full_name)
+                .map(|(_, start, end)| (end.saturating_sub(*start) * 1000) as u64);
+        }
+    }
 }
 
-/// After all test results have been processed, this function computes
-/// the summary statistics (passed, failed, missed) for the test group.
-/// 
-/// It updates the global TEST_GROUP state accordingly.
-fn process_summary() -> Result<()> {
-    let mut test_group = TEST_GROUP.get()
-        .ok_or_else(|| anyhow!("No test group found for summary processing"))?
-        .write()
-        .map_err(|_| anyhow!("Failed to acquire write lock on test group"))?;
-
-    test_group.summary.passed = test_group.modules.iter()
-        .map(|m| m.tests.iter().filter(|t| t.result == "pass").count() as u64)
-        .sum();
-    test_group.summary.failed = test_group.modules.iter()
-        .map(|m| m.tests.iter().filter(|t| t.result == "fail").count() as u64)
-        .sum();
-    test_group.summary.ignored = test_group.summary.total
-        .saturating_sub(test_group.summary.passed + test_group.summary.failed);
-    
+/// Rename the serial log of this session after the test group, so it is archived with
+/// the round, and link each failed test to the serial output written while it ran.
+fn process_serial_log(test_group: &mut TestGroup, test_windows: &[(String, u128, u128)], serial_lines: &[(u128, String)]) -> Result<()> {
+    let testing_dir = args::get_build_directory()?.join("testing");
+    let serial_log_path = get_serial_log_path()?;
+    if !serial_log_path.exists() {
+        return Ok(());
+    }
+
+    // retries of the same group are kept next to the original run
+    let mut file_name = format!("serial-{}.log", test_group.test_group);
+    let mut attempt = 1;
+    while testing_dir.join(&file_name).exists() {
+        attempt += 1;
+        file_name = format!("serial-{}-{}.log", test_group.test_group, attempt);
+    }
+
+    for module in test_group.modules.iter_mut() {
+        for test in module.tests.iter_mut().filter(|t| t.outcome.is_failure()) {
+            let full_name = format!("{}::{}", module.module, test.test);
+            test.serial_log = test_windows.iter()
+                .find(|(name, _, _)| *name == full_name)
+                .and_then(|(_, start, end)| console::link_serial_output(serial_lines, &file_name, *start, *end));
+        }
+    }
+
+    fs::rename(&serial_log_path, testing_dir.join(&file_name))?;
     Ok(())
 }
 
+/// Path of the serial console log written by QEMU for this session
+fn get_serial_log_path() -> Result<PathBuf> {
+    Ok(args::get_build_directory()?
+        .join("testing")
+        .join(format!("serial-{}.log", crate::UUID.get().unwrap())))
+}
+
+/// Path of the raw -debugcon output written by QEMU for this session
+fn get_qemu_output_path() -> Result<PathBuf> {
+    Ok(args::get_build_directory()?
+        .join("testing")
+        .join(format!("tests-{}.json", crate::UUID.get().unwrap())))
+}
+
 /// After all test groups have been processed, this function
-/// moves all individual test JSON files into a timestamped directory.
+/// moves all individual test JSON files and serial logs into a timestamped directory.
 /// 
 /// The original testing directory is then removed.
-fn process_final_json() -> Result<()> {
-    let workspace_dir = args::get_workspace_root()?;
-    let testing_dir = workspace_dir.join(BUILD_DIRECTORY).join("testing");
+pub fn process_final_json() -> Result<()> {
+    let build_dir = args::get_build_directory()?;
+    let testing_dir = build_dir.join("testing");
     let current_time_millis = std::time::SystemTime::now()
         .duration_since(std::time::UNIX_EPOCH)?
         .as_millis();
-    let timestamped_testing_dir = workspace_dir.join(BUILD_DIRECTORY).join(format!("testing-{}", current_time_millis));
+    let timestamped_testing_dir = build_dir.join(format!("testing-{}", current_time_millis));
 
     // create timestamped directory and move all JSON files there
     fs::create_dir_all(&timestamped_testing_dir)?;
     for entry in fs::read_dir(&testing_dir)? {
         let entry = entry?;
         let path = entry.path();
-        if path.is_file() && path.extension().and_then(|s| s.to_str()) == Some("json") {
+        let extension = path.extension().and_then(|s| s.to_str());
+        if path.is_file() && matches!(extension, Some("json") | Some("log") | Some("png")) {
             let file_name = path.file_name().ok_or_else(|| anyhow!("Failed to get file name"))?;
             let dest_path = timestamped_testing_dir.join(file_name);
             fs::rename(&path, &dest_path)?;
         }
     }
+    // without a coverage report, the round is still archived
+    if args::is_coverage() && let Err(e) = coverage::generate_report(&testing_dir, &timestamped_testing_dir) {
+        eprintln!("kboot: failed to generate the coverage report: {}", e);
+        log::warn!("Failed to generate the coverage report: {}", e);
+    }
     fs::remove_dir_all(&testing_dir)?;
 
     Ok(())
 }
 
-/// Helper function to extract the module name from a fully qualified test name.
-fn module_from_name(name: &str) -> String {
-    let parts: Vec<&str> = name.rsplitn(2, "::").collect();
-    if parts.len() == 2 {
-        parts[1].to_string()
-    } else {
-        "unknown".to_string()
-    }
+/// Work required by a line of the -debugcon stream, which must not hold the stream lock
+#[derive(Debug, PartialEq)]
+enum StreamAction {
+    /// Capture the display for the failed test
+    Screenshot(String),
+    /// Snapshot the VM at the named snapshot point, along with the lines written before it
+    SnapshotPoint(String, Vec<String>)
+}
+
+/// State of the -debugcon stream while QEMU is running.
+struct DebugconStream {
+    /// Time at which the previous line was written
+    last_timestamp: Option<u128>,
+    /// The time window (previous line, this line) in which each test ran
+    test_windows: Vec<(String, u128, u128)>,
+    /// The test that was started, but has not reported a result yet
+    started_test: Option<String>,
+    /// Number of lines received so far
+    line_count: usize,
+    /// Screenshots captured for failed tests, as (fully qualified test name, file name)
+    screenshots: Vec<(String, String)>,
+    /// Protocol version from the handshake (0 if there was none)
+    protocol_version: u32,
+    /// Problems with lines of the stream that did not stop processing
+    diagnostics: Vec<Diagnostic>,
+    /// Lines received before the snapshot point, until it was reached
+    prelude: Option<Vec<String>>
 }
 
-/// Helper function to extract the function name from a fully qualified test name.
-fn function_from_name(name: &str) -> String {
-    let parts: Vec<&str> = name.rsplitn(2, "::").collect();
-    if parts.len() == 2 {
-        parts[0].to_string()
-    } else {
-        name.to_string()
+impl DebugconStream {
+    const fn new() -> Self {
+        Self {
+            last_timestamp: None,
+            test_windows: Vec::new(),
+            started_test: None,
+            screenshots: Vec::new(),
+            line_count: 0,
+            protocol_version: 0,
+            diagnostics: Vec::new(),
+            prelude: Some(Vec::new())
+        }
     }
+
+    fn add_diagnostic(&mut self, content: &str, message: String) {
+        self.diagnostics.push(Diagnostic {
+            line: self.line_count,
+            content: content.to_string(),
+            message
+        });
+    }
+}
+
+/// Diagnostic describes a line of ktest output that was malformed or unexpected.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Diagnostic {
+    /// Line number (1-based) in the debugcon output
+    pub line: usize,
+    pub content: String,
+    pub message: String
 }
 
 /// TestGroup represents a collection of test modules and their results.
 #[derive(Serialize, Deserialize, Debug)]
-struct TestGroup {
-    test_group: String,
-    summary: TestSummary,
-    modules: Vec<TestModule>
+pub struct TestGroup {
+    pub test_group: String,
+    pub summary: TestSummary,
+    pub modules: Vec<TestModule>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub crash: Option<CrashReport>,
+    #[serde(default)]
+    pub protocol_version: u32,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub diagnostics: Vec<Diagnostic>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub configuration: Option<MatrixEntry>
+}
+
+impl TestGroup {
+    /// Recompute the pass/fail/ignore counts from the test results.
+    /// 
+    /// Tests that were never reported count as ignored, unless the kernel crashed,
+    /// in which case they count as not run.
+    pub(crate) fn update_summary(&mut self) {
+        let count = |filter: fn(&TestOutcome) -> bool| self.modules.iter()
+            .map(|m| m.tests.iter().filter(|t| filter(&t.outcome)).count() as u64)
+            .sum::<u64>();
+
+        self.summary.passed = count(TestOutcome::is_success);
+        self.summary.failed = count(TestOutcome::is_failure);
+        self.summary.crashed = count(|o| *o == TestOutcome::Crashed);
+        self.summary.not_run = count(|o| *o == TestOutcome::NotRun);
+        self.summary.ignored = count(|o| matches!(o, TestOutcome::Ignored { .. }));
+        self.summary.flaky = self.modules.iter()
+            .map(|m| m.tests.iter().filter(|t| t.flaky).count() as u64)
+            .sum();
+
+        let unreported = self.summary.total.saturating_sub(self.summary.passed + self.summary.failed
+            + self.summary.crashed + self.summary.not_run + self.summary.ignored);
+        if self.crash.is_some() {
+            self.summary.not_run += unreported;
+        } else {
+            self.summary.ignored += unreported;
+        }
+    }
 }
 
 /// TestSummary holds aggregate statistics about the test results.
 #[derive(Serialize, Deserialize, Debug)]
-struct TestSummary {
-    total: u64,
-    passed: u64,
-    failed: u64,
-    ignored: u64,
-    duration: u64
+pub struct TestSummary {
+    pub total: u64,
+    pub passed: u64,
+    pub failed: u64,
+    pub ignored: u64,
+    #[serde(default)]
+    pub crashed: u64,
+    #[serde(default)]
+    pub not_run: u64,
+    #[serde(default)]
+    pub flaky: u64,
+    #[serde(default)]
+    pub regressions: u64,
+    pub duration: u64
 }
 
 /// TestModule represents a module containing multiple test results.
 #[derive(Serialize, Deserialize, Debug)]
-struct TestModule {
-    module: String,
-    tests: Vec<TestResult>
+pub struct TestModule {
+    pub module: String,
+    pub tests: Vec<TestResult>
 }
 
 /// TestResult represents the outcome of a single test.
 #[derive(Serialize, Deserialize, Debug)]
-struct TestResult {
-    test: String,
-    result: String,
-    cycle_count: u64,
+pub struct TestResult {
+    pub test: String,
+    #[serde(flatten)]
+    pub outcome: TestOutcome,
+    pub cycle_count: u64,
+    /// Duration measured by the kernel
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub duration_us: Option<u64>,
+    /// Duration estimated by kboot from when the debugcon lines around the test were
+    /// read, if the kernel did not measure one (only as precise as the polling interval)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub estimated_duration_us: Option<u64>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tags: Vec<String>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub data: BTreeMap<String, serde_json::Value>,
     #[serde(skip_serializing_if = "Option::is_none")]
-    location: Option<String>, // failure only
+    pub location: Option<String>, // failure only
     #[serde(skip_serializing_if = "Option::is_none")]
-    message: Option<String>   // failure only
+    pub message: Option<String>,  // failure only
+    #[serde(default)]
+    pub flaky: bool,              // failed, then passed on retry
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub attempts: Option<u32>,    // retried only
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub serial_log: Option<SerialLogLink>, // failure only
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub screenshot: Option<String>,        // failure only, PNG next to the group JSON
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub performance: Option<PerformanceComparison>
+}
+
+impl TestResult {
+    pub(crate) fn new(test: String, outcome: TestOutcome, cycle_count: u64, location: Option<String>, message: Option<String>) -> Self {
+        Self {
+            test,
+            outcome,
+            cycle_count,
+            duration_us: None,
+            estimated_duration_us: None,
+            tags: Vec::new(),
+            data: BTreeMap::new(),
+            location,
+            message,
+            flaky: false,
+            attempts: None,
+            serial_log: None,
+            screenshot: None,
+            performance: None
+        }
+    }
+}
+
+/// TestOutcome is the result of a single test. It is serialized into the
+/// `result` field, next to the other fields of the test result.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "result", rename_all = "snake_case")]
+pub enum TestOutcome {
+    Pass,
+    Fail,
+    #[serde(rename = "ignore")]
+    Ignored {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        reason: Option<String>
+    },
+    /// A `#[should_panic]` test that panicked as expected
+    ShouldPanic,
+    Timeout,
+    /// The kernel stopped while this test was running
+    Crashed,
+    /// The test did not run, because the kernel crashed before it
+    NotRun
+}
+
+impl TestOutcome {
+    /// Convert the result of a ktest record, or `None` if the result is unknown.
+    pub(crate) fn from_record(record: &TestResultRecord) -> Option<Self> {
+        match record.result.as_str() {
+            "pass" => Some(Self::Pass),
+            "fail" => Some(Self::Fail),
+            "ignore" => Some(Self::Ignored { reason: record.reason.clone() }),
+            "should_panic" => Some(Self::ShouldPanic),
+            "timeout" => Some(Self::Timeout),
+            _ => None
+        }
+    }
+
+    pub fn is_success(&self) -> bool {
+        matches!(self, Self::Pass | Self::ShouldPanic)
+    }
+
+    pub fn is_failure(&self) -> bool {
+        matches!(self, Self::Fail | Self::Timeout)
+    }
+
+    /// The status shown for the test in the terminal, in the style of libtest
+    pub fn label(&self) -> &'static str {
+        match self {
+            Self::Pass | Self::ShouldPanic => "ok",
+            Self::Fail => "FAILED",
+            Self::Ignored { .. } => "ignored",
+            Self::Timeout => "TIMEOUT",
+            Self::Crashed => "CRASHED",
+            Self::NotRun => "not run"
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Tests that use the global parser and stream must not run at the same time
+    static TEST_LOCK: Mutex<()> = Mutex::new(());
+
+    #[test]
+    fn when_outcome_is_serialized_then_result_field_is_flattened() {
+        let mut test = TestResult::new("alloc".to_string(), TestOutcome::Ignored { reason: Some("slow".to_string()) }, 0, None, None);
+        test.tags.push("memory".to_string());
+
+        let json = serde_json::to_value(&test).unwrap();
+        assert_eq!(json["result"], "ignore");
+        assert_eq!(json["reason"], "slow");
+        assert_eq!(json["tags"][0], "memory");
+
+        let parsed: TestResult = serde_json::from_value(json).unwrap();
+        assert_eq!(parsed.outcome, test.outcome);
+
+        let legacy: TestResult = serde_json::from_str(r#"{"test":"alloc","result":"pass","cycle_count":5}"#).unwrap();
+        assert_eq!(legacy.outcome, TestOutcome::Pass);
+    }
+
+    #[test]
+    fn when_lines_are_streamed_then_tests_that_ran_before_a_crash_are_kept() {
+        let _lock = TEST_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        start_stream();
+        process_streamed_line(r#"{"test_group":"kernel","test_count":2}"#, 10);
+        process_streamed_line(r#"{"test":"kernel::test_alloc","result":"fail","cycle_count":5}"#, 20);
+
+        // the kernel crashed during the second test, which has no result
+        let parser = PARSER.read().unwrap();
+        let modules = &parser.test_group().unwrap().modules;
+        assert_eq!(modules.len(), 1);
+        assert_eq!((modules[0].module.as_str(), modules[0].tests.len()), ("kernel", 1));
+
+        let stream = STREAM.lock().unwrap();
+        assert!(stream.diagnostics.is_empty());
+        assert_eq!(stream.test_windows, [("kernel::test_alloc".to_string(), 10, 20)]);
+    }
+
+    #[test]
+    fn when_snapshot_point_is_streamed_then_lines_before_it_are_its_prelude() {
+        let _lock = TEST_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        *PARSER.write().unwrap() = TestResultParser::new();
+        let mut stream = DebugconStream::new();
+
+        assert_eq!(process_line(&mut stream, r#"{"ktest_protocol":1}"#, 1), None);
+        assert_eq!(process_line(&mut stream, "booting...", 2), None);
+        assert_eq!(process_line(&mut stream, r#"{"snapshot_point":"init_complete"}"#, 3), Some(StreamAction::SnapshotPoint(
+            "init_complete".to_string(),
+            vec![r#"{"ktest_protocol":1}"#.to_string(), "booting...".to_string()]
+        )));
+        assert_eq!(process_line(&mut stream, r#"{"test_group":"kernel","test_count":1,"use_kview":false}"#, 4), None);
+        assert_eq!(process_line(&mut stream, r#"{"test":"kernel::test_alloc","result":"fail","cycle_count":5}"#, 5),
+            Some(StreamAction::Screenshot("kernel::test_alloc".to_string())));
+        assert_eq!(stream.prelude, None);
+    }
 }
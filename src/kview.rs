@@ -1,23 +1,32 @@
 use anyhow::Result;
-use crate::{args, BUILD_DIRECTORY};
+use sha2::{Digest, Sha256};
+use crate::{args, console, container, BUILD_DIRECTORY};
 
+/// Port of kview in its container, and the preferred port on the host
 const PORT: u16 = 3000;
 const REMOTE_TAG: &str = "philogroves/kview:0.1.3";
 const LOCAL_TAG: &str = "philogroves/kview_local:latest";
 
 /// Start the kview docker container if it is not already running.
+///
+/// kview keeps running after kboot exits, so there is one container per workspace
+/// (rather than per session), named after the workspace path. If port 3000 is taken
+/// by the kview of another workspace, a different port is used.
 pub fn start_kview_if_needed() -> Result<()> {
+    let container_name = get_container_name()?;
+
     // check if there is a docker image for kview
-    if is_docker_container_with_name_running("kview").unwrap_or(false) {
-        log::info!("kview docker container is already running.");
+    if is_docker_container_with_name_running(&container_name).unwrap_or(false) {
+        log::info!("kview docker container {} is already running.", container_name);
         return Ok(());
     }
 
-    // if port is in use, probably running outside of container already
-    if is_already_running() {
+    // if port is in use by something other than kboot, probably running outside of container already
+    if is_already_running() && !is_port_used_by_other_kview()? {
         log::info!("Port {} is already in use, assuming kview is running.", PORT);
         return Ok(());
     }
+    let port = container::allocate_port(PORT)?;
 
     let workspace_directory = args::get_workspace_root()?;
     let build_path = workspace_directory.join(BUILD_DIRECTORY);
@@ -35,8 +44,9 @@ pub fn start_kview_if_needed() -> Result<()> {
         .arg("run")
         .arg("--rm")
         .arg("-d")
-        .args(["--name", "kview"])
-        .args(["-p", "3000:3000"])
+        .args(["--name", &container_name])
+        .args(["--label", "kboot.role=kview"])
+        .args(["-p", &format!("{}:{}", port, PORT)])
         .args(["-v", &format!("{}:/kview", build_path.display())])
         .arg(image_name);
 
@@ -47,16 +57,34 @@ pub fn start_kview_if_needed() -> Result<()> {
         return Err(anyhow::anyhow!("Failed to start kview docker container"));
     }
 
-    log::info!("Opening kview in the default web browser at http://localhost:3000");
-    webbrowser::open("http://localhost:3000")?;
+    let url = format!("http://localhost:{}", port);
+    console::print_human(&format!("kboot: kview is available at {}", url));
+    log::info!("Opening kview in the default web browser at {}", url);
+    webbrowser::open(&url)?;
 
     Ok(())
 }
 
+/// Name of the kview container of this workspace
+fn get_container_name() -> Result<String> {
+    let workspace_directory = args::get_workspace_root()?;
+    let hash = Sha256::digest(workspace_directory.to_string_lossy().as_bytes());
+    Ok(format!("kview-{}", &format!("{:x}", hash)[..12]))
+}
+
+/// Check if the preferred port is published by the kview container of another workspace.
+fn is_port_used_by_other_kview() -> Result<bool> {
+    let output = std::process::Command::new("docker")
+        .args(["ps", "--filter", "label=kboot.role=kview", "--filter", &format!("publish={}", PORT), "--format", "{{.Names}}"])
+        .output()?;
+
+    Ok(!String::from_utf8_lossy(&output.stdout).trim().is_empty())
+}
+
 /// Check if a Docker container with the specified name is currently running.
 fn is_docker_container_with_name_running(name: &str) -> Result<bool> {
     let output = std::process::Command::new("docker")
-        .args(&["ps", "--filter", &format!("name={}", name), "--format", "{{.Names}}"])
+        .args(["ps", "--filter", &format!("name={}", name), "--format", "{{.Names}}"])
         .output()?;
 
     let container_name = String::from_utf8_lossy(&output.stdout);
@@ -65,10 +93,7 @@ fn is_docker_container_with_name_running(name: &str) -> Result<bool> {
 
 /// Check if kview is already running by attempting to connect to its web interface.
 fn is_already_running() -> bool {
-    match reqwest::blocking::get("http://localhost:3000") {
-        Ok(_) => true,
-        Err(_) => false
-    }
+    reqwest::blocking::get("http://localhost:3000").is_ok()
 }
 
 /// Build the kview Docker image from the local kview directory.
@@ -1,14 +1,46 @@
-use std::{io::Error, sync::OnceLock};
+use std::{fmt, io::Error, process::ExitCode, sync::OnceLock};
 use anyhow::Result;
 use uuid::Uuid;
 
 mod builder;
 mod args;
+mod bench;
 mod clean;
+mod config;
+mod console;
+mod container;
+mod coverage;
+mod crash;
 mod event;
+mod interrupt;
 mod ktest;
 mod kview;
+mod matrix;
+mod orchestrator;
+mod parser;
+mod perf;
+pub mod protocol;
 mod qemu;
+mod qmp;
+mod report;
+mod retry;
+mod screenshot;
+mod snapshot;
+mod stats;
+mod vm;
+
+// The library API, which builds and runs images without reading the command line.
+// The kboot runner is built on top of it.
+pub use args::BootloaderSelection;
+pub use builder::{BuildError, DiskImageType, ImageBuilder, Manifest};
+pub use config::{Accel, BootMode, SmpConfig, VmBackend, VmConfig};
+pub use console::SerialLogLink;
+pub use crash::CrashReport;
+pub use ktest::{Diagnostic, TestGroup, TestModule, TestOutcome, TestResult, TestSummary};
+pub use matrix::MatrixEntry;
+pub use parser::TestResultParser;
+pub use perf::PerformanceComparison;
+pub use qemu::{QemuExitCode, QemuRun, VmRunner};
 
 /// Directory where build artifacts are stored
 pub const BUILD_DIRECTORY: &str = ".build";
@@ -17,28 +49,90 @@ pub const BUILD_DIRECTORY: &str = ".build";
 pub static UUID: OnceLock<Uuid> = OnceLock::new();
 
 /// Main entry point for the kboot runner.
-pub fn run() -> Result<(), KbootError> {
+///
+/// Returns the exit code for the process: success, the failure code of QEMU
+/// if a test failed, or the code of an interrupted run after Ctrl-C.
+pub fn run() -> Result<ExitCode, KbootError> {
     UUID.set(Uuid::new_v4()).unwrap();
 
-    if args::should_clean() {
-        return clean::clean();
+    if args::is_vm_command() {
+        return vm::run_command().map(|()| ExitCode::SUCCESS);
+    }
+
+    if args::is_clean_command() {
+        return clean::clean().map(|()| ExitCode::SUCCESS);
+    }
+
+    interrupt::install_handler()
+        .map_err(|e| KbootError::InterruptHandlerFailed(e, "Failed to install the Ctrl-C handler".to_string()))?;
+
+    if args::is_test_command() {
+        return orchestrator::run_command();
     }
 
     start_logger()?;
+    if args::has_qemu_options() {
+        args::get_qemu_options().map_err(|e| KbootError::ArgumentFailedToParse(e, "Invalid --qemu options".to_string()))?;
+    }
     let start_event = event::write_start_events()
-        .map_err(|e| KbootError::EventFailedToWrite(format!("Failed to write start events: {}", e)))?;
+        .map_err(|e| KbootError::EventFailedToWrite(e, "Failed to write start events".to_string()))?;
+
+    let mut test_results = Ok(());
+    let mut failed = false;
+    if args::is_bench() {
+        builder::build_image()?;
+        test_results = bench::run_benchmarks()
+            .map_err(|e| KbootError::TestResultsFailed(e, "Failed to run benchmarks".to_string()));
+    } else {
+        // without a test matrix, there is a single entry with the regular configuration
+        let matrix_entries = matrix::get_entries()
+            .map_err(|e| KbootError::ArgumentFailedToParse(e, "Failed to read the test matrix".to_string()))?;
+
+        for (index, entry) in matrix_entries.iter().enumerate() {
+            if interrupt::is_interrupted() {
+                break;
+            }
+
+            matrix::set_current(entry.clone())
+                .map_err(|e| KbootError::ArgumentFailedToParse(e, "Failed to select matrix configuration".to_string()))?;
+            builder::build_image()?;
+
+            let qemu_run = qemu::run()
+                .map_err(|e| KbootError::QemuFailedToRun(e, "Failed to run QEMU".to_string()))?;
+            let mut run_exit_code = qemu_run.exit_code;
 
-    builder::build_image().unwrap();
-    let run_duration = qemu::run()
-        .map_err(|e| KbootError::QemuFailedToRun(format!("Failed to run QEMU: {}", e)))?;
+            let is_test = args::is_test().map_err(|e| KbootError::ArgumentFailedToParse(e, "Failed to determine if executable is a test".to_string()))?;
+            if is_test && !args::is_no_ktest() {
+                let run_results = ktest::process_test_results(&start_event, &qemu_run, index, matrix_entries.len())
+                    .map(|group_exit_code| run_exit_code = group_exit_code)
+                    .map_err(|e| KbootError::TestResultsFailed(e, "Failed to process ktest results".to_string()));
+                if test_results.is_ok() {
+                    test_results = run_results;
+                }
+            }
 
-    if args::is_test().map_err(|_| KbootError::ArgumentFailedToParse("Failed to determine if executable is a test".to_string()))? && !args::is_no_ktest() {
-        ktest::process_test_results(&start_event, run_duration)
-            .map_err(|e| KbootError::EventFailedToWrite(format!("Failed to process ktest results: {}", e)))?;
+            // a failure in any configuration fails the run; tests must exit successfully,
+            // while other kernels may also stop QEMU without the isa-debug-exit device
+            if run_exit_code != QemuExitCode::Success as i32 && (is_test || run_exit_code == QemuExitCode::Failed as i32) {
+                failed = true;
+            }
+        }
+        matrix::print_summary();
     }
 
-    event::write_end_events(&start_event).map_err(|e| KbootError::EventFailedToWrite(format!("Failed to write end events: {}", e)))?;
-    Ok(())
+    // the round must be ended even if the results of this group failed (e.g. a performance regression)
+    event::write_end_events(&start_event).map_err(|e| KbootError::EventFailedToWrite(e, "Failed to write end events".to_string()))?;
+    test_results?;
+
+    if interrupt::is_interrupted() {
+        return Ok(ExitCode::from(interrupt::INTERRUPTED_EXIT_CODE as u8));
+    }
+
+    if failed {
+        return Ok(ExitCode::from(QemuExitCode::Failed as u8));
+    }
+
+    Ok(ExitCode::SUCCESS)
 }
 
 /// Simple startup logs to display information about the executable
@@ -53,37 +147,111 @@ fn start_logger() -> Result<(), KbootError> {
 
     log::info!("Initiating kboot runner with arguments: {:?}", args::get_arguments());
     log::info!("====================  <executable>  ====================");
-    log::info!("Executable path:             {}", args::get_executable().map_err(|_| KbootError::ArgumentFailedToParse("Failed to get executable path".to_string()))?.display());
-    log::info!("Executable parent directory: {}", args::get_executable_parent().map_err(|_| KbootError::ArgumentFailedToParse("Failed to get executable parent directory".to_string()))?.display());
-    log::info!("Is executable a doctest?     {}", args::is_doctest().map_err(|_| KbootError::ArgumentFailedToParse("Failed to determine if executable is a doctest".to_string()))?);
-    log::info!("Is executable a test?        {}", args::is_test().map_err(|_| KbootError::ArgumentFailedToParse("Failed to determine if executable is a test".to_string()))?);
-    log::info!("Executable file stem:        {}", args::get_file_stem().map_err(|_| KbootError::ArgumentFailedToParse("Failed to get executable file stem".to_string()))?);
-    log::info!("Cargo manifest directory:    {}", args::get_manifest_dir().map_err(|_| KbootError::ArgumentFailedToParse("Failed to get cargo manifest directory".to_string()))?.display());
-    log::info!("Cargo.toml file path:        {}", args::get_manifest_toml().map_err(|_| KbootError::ArgumentFailedToParse("Failed to get Cargo.toml file path".to_string()))?.display());
-    log::info!("Current working directory:   {}", args::get_workspace_root().map_err(|_| KbootError::ArgumentFailedToParse("Failed to get workspace root".to_string()))?.display());
+    log::info!("Executable path:             {}", args::get_executable().map_err(|e| KbootError::ArgumentFailedToParse(e, "Failed to get executable path".to_string()))?.display());
+    log::info!("Executable parent directory: {}", args::get_executable_parent().map_err(|e| KbootError::ArgumentFailedToParse(e, "Failed to get executable parent directory".to_string()))?.display());
+    log::info!("Is executable a doctest?     {}", args::is_doctest().map_err(|e| KbootError::ArgumentFailedToParse(e, "Failed to determine if executable is a doctest".to_string()))?);
+    log::info!("Is executable a test?        {}", args::is_test().map_err(|e| KbootError::ArgumentFailedToParse(e, "Failed to determine if executable is a test".to_string()))?);
+    log::info!("Executable file stem:        {}", args::get_file_stem().map_err(|e| KbootError::ArgumentFailedToParse(e, "Failed to get executable file stem".to_string()))?);
+    log::info!("Cargo manifest directory:    {}", args::get_manifest_dir().map_err(|e| KbootError::ArgumentFailedToParse(e, "Failed to get cargo manifest directory".to_string()))?.display());
+    log::info!("Cargo.toml file path:        {}", args::get_manifest_toml().map_err(|e| KbootError::ArgumentFailedToParse(e, "Failed to get Cargo.toml file path".to_string()))?.display());
+    log::info!("Current working directory:   {}", args::get_workspace_root().map_err(|e| KbootError::ArgumentFailedToParse(e, "Failed to get workspace root".to_string()))?.display());
     log::info!("========================================================");
 
     Ok(())
 }
 
 fn get_log_file_path() -> Result<std::path::PathBuf, KbootError> {
-    let workspace_directory = args::get_workspace_root()
-        .map_err(|_| KbootError::ArgumentFailedToParse("Failed to get workspace root".to_string()))?;
+    let build_directory = args::get_build_directory()
+        .map_err(|e| KbootError::ArgumentFailedToParse(e, "Failed to get build directory".to_string()))?;
 
-    let log_file_path = workspace_directory.join(BUILD_DIRECTORY)
-        .join("logs")
+    let log_file_path = build_directory.join("logs")
         .join(format!("kboot-{}.log", UUID.get().unwrap()));
 
     Ok(log_file_path)
 }
 
+/// Errors that end a kboot run. Each variant carries the error that caused it and a
+/// message describing what failed; `report` prints it with its causes and a suggested fix.
 #[derive(Debug)]
 pub enum KbootError {
-    /// Error indicating that the specified executable was not found.
+    /// An I/O operation on the log or build directory failed
     LoggerIoError(Error, String),
-    QemuFailedToRun(String),
-    ArgumentFailedToParse(String),
-    EventFailedToWrite(String)
+    BuildFailed(BuildError),
+    QemuFailedToRun(anyhow::Error, String),
+    ArgumentFailedToParse(anyhow::Error, String),
+    EventFailedToWrite(anyhow::Error, String),
+    TestResultsFailed(anyhow::Error, String),
+    VmCommandFailed(anyhow::Error, String),
+    TestCommandFailed(anyhow::Error, String),
+    InterruptHandlerFailed(anyhow::Error, String)
+}
+
+impl KbootError {
+    /// A suggestion for fixing the error, if there is a common cause
+    pub fn hint(&self) -> Option<String> {
+        match self {
+            KbootError::BuildFailed(e) => e.hint(),
+            KbootError::QemuFailedToRun(..) => Some("check that Docker is running (`docker info`), or set `backend = \"native\"` under [vm] in kboot.toml".to_string()),
+            KbootError::ArgumentFailedToParse(..) => Some("kboot must be run by cargo with the executable as its last argument (`runner = \"kboot\"` in .cargo/config.toml)".to_string()),
+            KbootError::EventFailedToWrite(..) => Some("the event log in .build may be damaged; `kboot clean` starts over".to_string()),
+            KbootError::VmCommandFailed(..) => Some("`kboot vm` controls a VM started by `cargo run` or `cargo test` from the same directory".to_string()),
+            KbootError::TestCommandFailed(..) => Some("run `kboot test` from the workspace root; arguments for cargo go before `--`".to_string()),
+            KbootError::LoggerIoError(..) | KbootError::TestResultsFailed(..) | KbootError::InterruptHandlerFailed(..) => None
+        }
+    }
+
+    /// Print the error, its causes and a suggested fix to stderr.
+    pub fn report(&self) {
+        eprintln!("kboot: error: {}", self);
+
+        let mut source = std::error::Error::source(self);
+        while let Some(cause) = source {
+            eprintln!("  caused by: {}", cause);
+            source = cause.source();
+        }
+
+        if let Some(hint) = self.hint() {
+            eprintln!("  hint: {}", hint);
+        }
+    }
+}
+
+impl fmt::Display for KbootError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            KbootError::BuildFailed(_) => write!(f, "Failed to build the disk image"),
+            KbootError::LoggerIoError(_, message)
+            | KbootError::QemuFailedToRun(_, message)
+            | KbootError::ArgumentFailedToParse(_, message)
+            | KbootError::EventFailedToWrite(_, message)
+            | KbootError::TestResultsFailed(_, message)
+            | KbootError::VmCommandFailed(_, message)
+            | KbootError::TestCommandFailed(_, message)
+            | KbootError::InterruptHandlerFailed(_, message) => write!(f, "{}", message)
+        }
+    }
+}
+
+impl std::error::Error for KbootError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            KbootError::LoggerIoError(e, _) => Some(e),
+            KbootError::BuildFailed(e) => Some(e),
+            KbootError::QemuFailedToRun(e, _)
+            | KbootError::ArgumentFailedToParse(e, _)
+            | KbootError::EventFailedToWrite(e, _)
+            | KbootError::TestResultsFailed(e, _)
+            | KbootError::VmCommandFailed(e, _)
+            | KbootError::TestCommandFailed(e, _)
+            | KbootError::InterruptHandlerFailed(e, _) => Some(e.as_ref())
+        }
+    }
+}
+
+impl From<BuildError> for KbootError {
+    fn from(error: BuildError) -> Self {
+        KbootError::BuildFailed(error)
+    }
 }
 
 #[cfg(test)]
@@ -118,4 +286,15 @@ mod tests {
 
         assert!(result.is_ok());
     }
+
+    #[test]
+    fn when_error_is_caused_by_another_then_source_chain_is_kept() {
+        let cause = anyhow::anyhow!("connection refused").context("Failed to connect to QMP");
+        let error = KbootError::VmCommandFailed(cause, "Failed to send the command to the VM".to_string());
+
+        let source = std::error::Error::source(&error).unwrap();
+        assert_eq!(error.to_string(), "Failed to send the command to the VM");
+        assert_eq!(source.to_string(), "Failed to connect to QMP");
+        assert_eq!(source.source().unwrap().to_string(), "connection refused");
+    }
 }
@@ -1,7 +1,12 @@
-use anyhow::Result;
-use kboot::KbootError;
+use std::process::ExitCode;
 
 /// Entry point for the runner. This file should be kept as light as possible.
-fn main() -> Result<(), KbootError> {
-    kboot::run()
+fn main() -> ExitCode {
+    match kboot::run() {
+        Ok(exit_code) => exit_code,
+        Err(error) => {
+            error.report();
+            ExitCode::FAILURE
+        }
+    }
 }
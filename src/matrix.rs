@@ -0,0 +1,179 @@
+use std::sync::RwLock;
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use crate::{args, config::{self, BootMode, VmConfig}, console, ktest::TestGroup};
+
+/// A global, thread-safe storage for the matrix configuration that is being run.
+static CURRENT: RwLock<Option<MatrixEntry>> = RwLock::new(None);
+
+/// A global, thread-safe storage for the results of each configuration of the matrix.
+static RESULTS: RwLock<Vec<MatrixResult>> = RwLock::new(Vec::new());
+
+/// Get every combination of the `[matrix]` configuration. Without a matrix (or when
+/// the executable is not a test), a single run with the regular configuration is returned.
+pub fn get_entries() -> Result<Vec<Option<MatrixEntry>>> {
+    let matrix = &config::get_config()?.matrix;
+    if matrix.is_empty() || !args::is_test()? || args::is_bench() {
+        return Ok(vec![None]);
+    }
+
+    let smp = optional_values(&matrix.smp);
+    let cpu = optional_values(&matrix.cpu);
+    let boot = optional_values(&matrix.boot);
+
+    let mut entries = Vec::new();
+    for smp in &smp {
+        for cpu in &cpu {
+            for boot in &boot {
+                entries.push(Some(MatrixEntry { smp: *smp, cpu: cpu.clone(), boot: *boot }));
+            }
+        }
+    }
+
+    Ok(entries)
+}
+
+/// Every value of a matrix dimension, or a single unset value if the dimension is empty
+fn optional_values<T: Clone>(values: &[T]) -> Vec<Option<T>> {
+    if values.is_empty() {
+        return vec![None];
+    }
+    values.iter().cloned().map(Some).collect()
+}
+
+/// Set the configuration of the matrix that the following build and QEMU run use.
+pub fn set_current(entry: Option<MatrixEntry>) -> Result<()> {
+    *CURRENT.write().map_err(|_| anyhow!("Failed to acquire write lock on matrix entry"))? = entry;
+    Ok(())
+}
+
+pub fn get_current() -> Option<MatrixEntry> {
+    CURRENT.read().ok().and_then(|current| current.clone())
+}
+
+/// Get the VM settings of the current matrix configuration, which overrides
+/// the number of CPUs and the CPU model of the `[vm]` settings.
+pub fn get_vm_config() -> Result<VmConfig> {
+    let mut vm_config = config::get_config()?.vm.clone();
+    if let Some(entry) = get_current() {
+        if let Some(smp) = entry.smp {
+            vm_config.smp.cpus = Some(smp);
+        }
+        if let Some(cpu) = entry.cpu {
+            vm_config.cpu = Some(cpu);
+        }
+    }
+
+    Ok(vm_config)
+}
+
+/// Whether the image is built for legacy BIOS boot, which the matrix may override
+pub fn is_legacy_boot() -> bool {
+    match get_current().and_then(|entry| entry.boot) {
+        Some(boot) => boot == BootMode::Bios,
+        None => args::is_legacy_boot()
+    }
+}
+
+/// Name the test group after the current matrix configuration, so that its output,
+/// history and baseline are kept separate from the other configurations.
+pub fn apply_to_test_group(test_group: &mut TestGroup) {
+    if let Some(entry) = get_current() {
+        test_group.test_group = format!("{}@{}", test_group.test_group, entry.label());
+        test_group.configuration = Some(entry);
+    }
+}
+
+/// Remember the outcome of a test group for the matrix summary.
+pub fn record_result(test_group: &TestGroup) {
+    let Some(configuration) = test_group.configuration.clone() else {
+        return;
+    };
+
+    if let Ok(mut results) = RESULTS.write() {
+        results.push(MatrixResult {
+            configuration,
+            passed: test_group.summary.passed,
+            failed: test_group.summary.failed,
+            crashed: test_group.summary.crashed
+        });
+    }
+}
+
+/// Print which configurations of the matrix passed and which failed.
+pub fn print_summary() {
+    let Ok(results) = RESULTS.read() else {
+        return;
+    };
+    if results.is_empty() {
+        return;
+    }
+
+    console::print_human("kboot: test matrix results:");
+    for result in results.iter() {
+        let status = if result.failed + result.crashed == 0 { "ok" } else { "FAILED" };
+        console::print_human(&format!("    {:<32} {} ({} passed; {} failed; {} crashed)",
+            result.configuration.label(), status, result.passed, result.failed, result.crashed));
+    }
+
+    let failed = results.iter()
+        .filter(|r| r.failed + r.crashed > 0)
+        .map(|r| r.configuration.label())
+        .collect::<Vec<String>>();
+    if !failed.is_empty() {
+        log::error!("Test matrix failed in: {}", failed.join(", "));
+    }
+}
+
+/// MatrixEntry is one combination of the `[matrix]` configuration.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct MatrixEntry {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub smp: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cpu: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub boot: Option<BootMode>
+}
+
+impl MatrixEntry {
+    /// Short name of the configuration, used in file names, e.g. `smp4-host-uefi`
+    pub fn label(&self) -> String {
+        let mut parts = Vec::new();
+        if let Some(smp) = self.smp {
+            parts.push(format!("smp{}", smp));
+        }
+        if let Some(cpu) = &self.cpu {
+            parts.push(cpu.replace(|c: char| !c.is_ascii_alphanumeric() && c != '_', "_"));
+        }
+        if let Some(boot) = self.boot {
+            parts.push(format!("{:?}", boot).to_lowercase());
+        }
+        parts.join("-")
+    }
+}
+
+/// The outcome of a test group under one configuration of the matrix
+struct MatrixResult {
+    configuration: MatrixEntry,
+    passed: u64,
+    failed: u64,
+    crashed: u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn when_entry_has_cpu_with_flags_then_label_is_file_name_safe() {
+        let entry = MatrixEntry { smp: Some(4), cpu: Some("Skylake-Client,+x2apic".to_string()), boot: Some(BootMode::Bios) };
+        assert_eq!(entry.label(), "smp4-Skylake_Client__x2apic-bios");
+    }
+
+    #[test]
+    fn when_dimension_is_empty_then_it_has_a_single_unset_value() {
+        assert_eq!(optional_values::<u32>(&[]), vec![None]);
+        assert_eq!(optional_values(&[1, 4]), vec![Some(1), Some(4)]);
+    }
+}
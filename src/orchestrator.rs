@@ -0,0 +1,329 @@
+use std::{collections::{HashMap, VecDeque}, env, fs, path::{Path, PathBuf}, process::{Command, ExitCode, Stdio}, sync::Mutex, thread, time::Instant};
+use anyhow::{anyhow, Result};
+use serde_json::Value;
+use crate::{args, config, console, event::{self, TestGroupStartedEvent, TestRoundEndedEvent, TestRoundInterruptedEvent, TestRoundStartedEvent}, interrupt, ktest::{self, TestGroup}, qemu::QemuExitCode, perf, report, KbootError};
+
+/// Directory inside the build directory that holds the build directory of each test executable
+pub const JOBS_DIRECTORY: &str = "test-jobs";
+
+/// Name of the file that the output of a test executable is written to, inside its build directory
+const OUTPUT_FILE_NAME: &str = "output.log";
+
+/// Entry point for `kboot test`, which runs the test executables of the workspace
+/// concurrently, each in its own VM, and merges their results into one test round.
+///
+/// Arguments before `--` are passed to `cargo test`, arguments after it to kboot
+/// for every test executable (e.g. `kboot test --workspace -- --limine`).
+pub fn run_command() -> Result<ExitCode, KbootError> {
+    let (cargo_args, kboot_args) = args::get_test_arguments();
+    let passed = run(cargo_args, kboot_args)
+        .map_err(|e| KbootError::TestCommandFailed(e, "Failed to run the test executables".to_string()))?;
+
+    if interrupt::is_interrupted() {
+        return Ok(ExitCode::from(interrupt::INTERRUPTED_EXIT_CODE as u8));
+    }
+    if !passed {
+        return Ok(ExitCode::from(QemuExitCode::Failed as u8));
+    }
+
+    Ok(ExitCode::SUCCESS)
+}
+
+/// A test executable built by cargo
+struct TestExecutable {
+    /// Name of the target and its kind, e.g. `kernel (lib)`
+    name: String,
+    path: PathBuf,
+    /// Directory of the package, which cargo passes to runners as `CARGO_MANIFEST_DIR`
+    manifest_dir: PathBuf
+}
+
+/// Outcome of running one test executable
+struct JobResult {
+    index: usize,
+    name: String,
+    /// Build directory of the test executable
+    directory: PathBuf,
+    success: bool
+}
+
+/// Run all test executables and return whether all of them passed.
+fn run(cargo_args: &[String], kboot_args: &[String]) -> Result<bool> {
+    let executables = discover_test_executables(cargo_args)?;
+    if executables.is_empty() {
+        console::print_human("kboot test: no test executables found");
+        return Ok(true);
+    }
+
+    let jobs = get_job_count(executables.len())?;
+    console::print_human(&format!("kboot test: running {} test executables, {} at a time", executables.len(), jobs));
+
+    let build_dir = args::get_build_directory()?;
+    fs::create_dir_all(&build_dir)?;
+    if event::is_start_of_test_round()? {
+        event::write_event(&TestRoundStartedEvent)?;
+    }
+
+    let results = run_jobs(&executables, kboot_args, &build_dir, jobs);
+    let test_groups = merge_results(&results, &build_dir)?;
+    if kboot_args.iter().any(|arg| arg == "--save-baseline") {
+        perf::save_baseline(&test_groups)?;
+    }
+    if interrupt::is_interrupted() {
+        event::write_event(&TestRoundInterruptedEvent)?;
+    } else {
+        event::write_event(&TestRoundEndedEvent)?;
+    }
+
+    print_summary(&results, &test_groups);
+    Ok(results.iter().all(|result| result.success))
+}
+
+/// Build the test executables with `cargo test --no-run` and collect them from its JSON messages.
+fn discover_test_executables(cargo_args: &[String]) -> Result<Vec<TestExecutable>> {
+    let output = Command::new("cargo")
+        .args(["test", "--no-run", "--message-format=json"])
+        .args(cargo_args)
+        .stderr(Stdio::inherit())
+        .output()?;
+    if !output.status.success() {
+        return Err(anyhow!("cargo test --no-run failed with {}", output.status));
+    }
+
+    Ok(parse_test_executables(&String::from_utf8_lossy(&output.stdout)))
+}
+
+/// Get the test executables from the JSON messages of cargo, which describe every
+/// compiled target; test executables are built with the test profile.
+fn parse_test_executables(messages: &str) -> Vec<TestExecutable> {
+    messages.lines()
+        .filter_map(|line| serde_json::from_str::<Value>(line).ok())
+        .filter(|message| message["reason"] == "compiler-artifact" && message["profile"]["test"] == true)
+        .filter_map(|message| {
+            let name = format!("{} ({})", message["target"]["name"].as_str()?, message["target"]["kind"][0].as_str()?);
+            let path = PathBuf::from(message["executable"].as_str()?);
+            let manifest_dir = Path::new(message["manifest_path"].as_str()?).parent()?.to_path_buf();
+
+            Some(TestExecutable { name, path, manifest_dir })
+        })
+        .collect()
+}
+
+/// Get the number of test executables that are run at the same time
+fn get_job_count(executables: usize) -> Result<usize> {
+    let jobs = match config::get_config()?.test.jobs {
+        Some(jobs) => jobs,
+        None => thread::available_parallelism().map(|n| n.get()).unwrap_or(1)
+    };
+
+    Ok(jobs.clamp(1, executables))
+}
+
+/// Run the test executables on `jobs` threads, each of which takes the next
+/// executable from a shared queue once its previous one finished.
+///
+/// After Ctrl-C, which the running executables receive as well, no further
+/// executables are started.
+fn run_jobs(executables: &[TestExecutable], kboot_args: &[String], build_dir: &Path, jobs: usize) -> Vec<JobResult> {
+    let queue = Mutex::new(executables.iter().enumerate().collect::<VecDeque<_>>());
+    let results = Mutex::new(Vec::new());
+
+    thread::scope(|scope| {
+        for _ in 0..jobs {
+            scope.spawn(|| {
+                while !interrupt::is_interrupted()
+                    && let Some((index, executable)) = queue.lock().ok().and_then(|mut queue| queue.pop_front()) {
+                    // every executable is a test group of the round
+                    let group_event = TestGroupStartedEvent { current_test_group: index, total_test_groups: executables.len() };
+                    if let Err(e) = event::write_event(&group_event) {
+                        eprintln!("kboot test: failed to write the event of {}: {}", executable.name, e);
+                    }
+
+                    let directory = get_job_directory(build_dir, executable);
+                    let started = Instant::now();
+                    let success = run_job(executable, kboot_args, &directory).unwrap_or_else(|e| {
+                        eprintln!("kboot test: failed to run {}: {}", executable.name, e);
+                        false
+                    });
+                    console::print_human(&format!("{} {} ({:.1}s)", if success { "ok    " } else { "FAILED" }, executable.name, started.elapsed().as_secs_f64()));
+
+                    if let Ok(mut results) = results.lock() {
+                        results.push(JobResult { index, name: executable.name.clone(), directory, success });
+                    }
+                }
+            });
+        }
+    });
+
+    let mut results = results.into_inner().unwrap_or_default();
+    results.sort_by_key(|result| result.index);
+    results
+}
+
+/// Get the build directory of a test executable. It is named after the executable
+/// (which includes cargo's hash), so images and Limine are reused by later runs.
+fn get_job_directory(build_dir: &Path, executable: &TestExecutable) -> PathBuf {
+    let file_stem = executable.path.file_stem()
+        .map(|stem| stem.to_string_lossy().to_string())
+        .unwrap_or_else(|| executable.name.clone());
+    build_dir.join(JOBS_DIRECTORY).join(file_stem)
+}
+
+/// Run kboot for one test executable in its own build directory, with its
+/// output written to a file, and return whether it passed.
+fn run_job(executable: &TestExecutable, kboot_args: &[String], directory: &Path) -> Result<bool> {
+    // results of an earlier run were merged into that round already
+    let testing_dir = directory.join("testing");
+    if testing_dir.exists() {
+        fs::remove_dir_all(&testing_dir)?;
+    }
+    let event_log_path = directory.join("event.log.json");
+    if event_log_path.exists() {
+        fs::remove_file(&event_log_path)?;
+    }
+
+    fs::create_dir_all(directory)?;
+    let output = fs::File::create(directory.join(OUTPUT_FILE_NAME))?;
+    let status = Command::new(env::current_exe()?)
+        .args(kboot_args)
+        .arg(&executable.path)
+        .env(args::BUILD_DIRECTORY_VARIABLE, directory)
+        .env("CARGO_MANIFEST_DIR", &executable.manifest_dir)
+        .stdin(Stdio::null())
+        .stdout(output.try_clone()?)
+        .stderr(output)
+        .status()?;
+
+    Ok(status.success())
+}
+
+/// Move the testing files of all test executables into the testing directory of the
+/// workspace and archive them as one round, like the final group of a sequential round.
+///
+/// Returns the test groups of the round, in the order of the executables.
+fn merge_results(results: &[JobResult], build_dir: &Path) -> Result<Vec<TestGroup>> {
+    let testing_dir = build_dir.join("testing");
+    fs::create_dir_all(&testing_dir)?;
+
+    let mut job_test_groups = Vec::new();
+    for result in results {
+        let job_testing_dir = result.directory.join("testing");
+        if job_testing_dir.exists() {
+            job_test_groups.push((result.name.as_str(), read_test_groups(&job_testing_dir)?, job_testing_dir));
+        }
+    }
+    check_test_group_names(&job_test_groups)?;
+
+    let mut test_groups = Vec::new();
+    for (_, job_groups, job_testing_dir) in job_test_groups {
+        test_groups.extend(job_groups);
+        move_directory_contents(&job_testing_dir, &testing_dir)?;
+    }
+
+    report::start_report()?;
+    for test_group in &test_groups {
+        report::write_report(test_group)?;
+    }
+    report::finish_report()?;
+
+    ktest::process_final_json()?;
+    Ok(test_groups)
+}
+
+/// Fail if two test executables reported test groups of the same name, since their
+/// output files are named after the group and would overwrite each other.
+fn check_test_group_names(job_test_groups: &[(&str, Vec<TestGroup>, PathBuf)]) -> Result<()> {
+    let mut executables = HashMap::new();
+    for (name, job_groups, _) in job_test_groups {
+        for test_group in job_groups {
+            if let Some(other) = executables.insert(test_group.test_group.as_str(), *name) {
+                return Err(anyhow!("Test executables {} and {} both report the test group {}; their results would overwrite each other, so each executable must use its own test group name",
+                    other, name, test_group.test_group));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Read the test groups written to a testing directory. The raw `-debugcon`
+/// output is also named `tests-*.json`, but is not a test group.
+fn read_test_groups(testing_dir: &Path) -> Result<Vec<TestGroup>> {
+    let mut paths = fs::read_dir(testing_dir)?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| path.file_name()
+            .and_then(|name| name.to_str())
+            .is_some_and(|name| name.starts_with("tests-") && name.ends_with(".json")))
+        .collect::<Vec<PathBuf>>();
+    paths.sort();
+
+    Ok(paths.iter()
+        .filter_map(|path| fs::read_to_string(path).ok())
+        .filter_map(|content| serde_json::from_str::<TestGroup>(&content).ok())
+        .collect())
+}
+
+fn move_directory_contents(source: &Path, destination: &Path) -> Result<()> {
+    fs::create_dir_all(destination)?;
+    for entry in fs::read_dir(source)? {
+        let path = entry?.path();
+        let file_name = path.file_name().ok_or_else(|| anyhow!("Failed to get file name"))?;
+        if path.is_dir() {
+            move_directory_contents(&path, &destination.join(file_name))?;
+        } else {
+            fs::rename(&path, destination.join(file_name))?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Print the totals of the round and where to find the output of failed executables.
+fn print_summary(results: &[JobResult], test_groups: &[TestGroup]) {
+    let passed_jobs = results.iter().filter(|result| result.success).count();
+    let passed = test_groups.iter().map(|group| group.summary.passed).sum::<u64>();
+    let failed = test_groups.iter().map(|group| group.summary.failed + group.summary.crashed).sum::<u64>();
+    let ignored = test_groups.iter().map(|group| group.summary.ignored).sum::<u64>();
+
+    console::print_human(&format!("kboot test: {} of {} test executables passed; {} tests passed, {} failed, {} ignored",
+        passed_jobs, results.len(), passed, failed, ignored));
+    for result in results.iter().filter(|result| !result.success) {
+        console::print_human(&format!("  {}: see {}", result.name, result.directory.join(OUTPUT_FILE_NAME).display()));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::parser::TestResultParser;
+    use super::*;
+
+    #[test]
+    fn when_parse_test_executables_then_only_test_profile_artifacts_with_executables() {
+        let messages = [
+            r#"{"reason":"compiler-artifact","package_id":"kernel","manifest_path":"/ws/kernel/Cargo.toml","target":{"kind":["lib"],"name":"kernel"},"profile":{"test":true},"executable":"/ws/target/x86_64-unknown-none/debug/deps/kernel-1a2b"}"#,
+            r#"{"reason":"compiler-artifact","package_id":"kernel","manifest_path":"/ws/kernel/Cargo.toml","target":{"kind":["lib"],"name":"kernel"},"profile":{"test":false},"executable":null}"#,
+            r#"{"reason":"compiler-artifact","package_id":"memory","manifest_path":"/ws/memory/Cargo.toml","target":{"kind":["custom-build"],"name":"build-script-build"},"profile":{"test":false},"executable":"/ws/target/debug/build/memory/build-script-build"}"#,
+            r#"{"reason":"build-finished","success":true}"#
+        ].join("\n");
+
+        let executables = parse_test_executables(&messages);
+
+        assert_eq!(executables.len(), 1);
+        assert_eq!(executables[0].name, "kernel (lib)");
+        assert_eq!(executables[0].path, PathBuf::from("/ws/target/x86_64-unknown-none/debug/deps/kernel-1a2b"));
+        assert_eq!(executables[0].manifest_dir, PathBuf::from("/ws/kernel"));
+    }
+
+    #[test]
+    fn when_two_executables_report_the_same_test_group_then_merge_fails() {
+        let group = |name: &str| TestResultParser::parse(&format!(r#"{{"test_group":"{}","test_count":0,"use_kview":false}}"#, name)).unwrap();
+        let mut job_test_groups = vec![
+            ("kernel (lib)", vec![group("kernel@smp1"), group("kernel@smp4")], PathBuf::from("a")),
+            ("memory (test)", vec![group("memory")], PathBuf::from("b"))
+        ];
+        assert!(check_test_group_names(&job_test_groups).is_ok());
+
+        job_test_groups.push(("kernel (test)", vec![group("kernel@smp4")], PathBuf::from("c")));
+        let error = check_test_group_names(&job_test_groups).unwrap_err().to_string();
+        assert!(error.starts_with("Test executables kernel (lib) and kernel (test) both report the test group kernel@smp4"));
+    }
+}
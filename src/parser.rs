@@ -0,0 +1,204 @@
+use anyhow::{anyhow, Result};
+use crate::{ktest::{Diagnostic, TestGroup, TestModule, TestOutcome, TestResult, TestSummary}, protocol::{self, Message, TestGroupStart, TestResultRecord}};
+
+/// Parses the lines that ktest writes to the `-debugcon` device into a [`TestGroup`].
+///
+/// Lines are fed one at a time with [`parse_line`](Self::parse_line), e.g. while QEMU
+/// is still running, and the group is completed with [`finish`](Self::finish). For
+/// output that was written to a file, [`parse`](Self::parse) does both at once.
+///
+/// ```no_run
+/// let output = std::fs::read_to_string("tests.json")?;
+/// if let Some(test_group) = kboot::TestResultParser::parse(&output) {
+///     println!("{}: {} passed, {} failed", test_group.test_group, test_group.summary.passed, test_group.summary.failed);
+/// }
+/// # Ok::<(), std::io::Error>(())
+/// ```
+#[derive(Debug, Default)]
+pub struct TestResultParser {
+    test_group: Option<TestGroup>,
+    use_kview: bool
+}
+
+impl TestResultParser {
+    pub const fn new() -> Self {
+        Self {
+            test_group: None,
+            use_kview: false
+        }
+    }
+
+    /// Parse a single line of ktest output. A test result is added to the module
+    /// it belongs to within the test group; other messages are only returned.
+    ///
+    /// Returns an error if the line is not a valid message or does not fit the
+    /// current state of the test group (e.g. a result before the group started).
+    pub fn parse_line(&mut self, line: &str) -> Result<Message> {
+        let message = protocol::parse_line(line)?;
+
+        match &message {
+            Message::TestGroupStart(group_start) => {
+                if self.test_group.is_some() {
+                    return Err(anyhow!("Test group already set"));
+                }
+
+                self.test_group = Some(test_group_from_start(group_start));
+                self.use_kview = group_start.use_kview;
+            },
+            Message::TestResult(record) => {
+                let test_group = self.test_group.as_mut()
+                    .ok_or_else(|| anyhow!("Test group not set before test result"))?;
+
+                let mut test = test_from_record(record);
+                let module_name = module_from_name(&test.test);
+                test.test = function_from_name(&test.test);
+
+                if let Some(module) = test_group.modules.iter_mut().find(|m| m.module == module_name) {
+                    module.tests.push(test);
+                } else {
+                    test_group.modules.push(TestModule {
+                        module: module_name,
+                        tests: vec![test],
+                    });
+                }
+            },
+            Message::SnapshotPoint(_) | Message::Handshake(_) | Message::TestStart(_) => {}
+        }
+
+        Ok(message)
+    }
+
+    /// The test group parsed so far, or `None` if it has not started yet
+    pub fn test_group(&self) -> Option<&TestGroup> {
+        self.test_group.as_ref()
+    }
+
+    /// Whether the test group asked for kview to be opened after the run
+    pub fn use_kview(&self) -> bool {
+        self.use_kview
+    }
+
+    /// Complete the test group and compute its summary. Tests that were announced,
+    /// but never reported, count as ignored.
+    pub fn finish(self) -> Option<TestGroup> {
+        let mut test_group = self.test_group?;
+        test_group.update_summary();
+        Some(test_group)
+    }
+
+    /// Parse the complete output of a test run. Lines that cannot be parsed are
+    /// recorded as diagnostics of the test group and skipped.
+    ///
+    /// Returns `None` if the output does not contain a test group.
+    pub fn parse(output: &str) -> Option<TestGroup> {
+        let mut parser = Self::new();
+        let mut protocol_version = 0;
+        let mut diagnostics = Vec::new();
+
+        for (index, line) in output.lines().enumerate().filter(|(_, line)| !line.trim().is_empty()) {
+            match parser.parse_line(line) {
+                Ok(Message::Handshake(handshake)) => protocol_version = handshake.ktest_protocol,
+                Ok(_) => {},
+                Err(e) => diagnostics.push(Diagnostic {
+                    line: index + 1,
+                    content: line.to_string(),
+                    message: e.to_string()
+                })
+            }
+        }
+
+        let mut test_group = parser.finish()?;
+        test_group.protocol_version = protocol_version;
+        test_group.diagnostics = diagnostics;
+        Some(test_group)
+    }
+}
+
+/// Create an empty test group from the protocol message that starts it.
+fn test_group_from_start(group_start: &TestGroupStart) -> TestGroup {
+    let summary = TestSummary {
+        total: group_start.test_count,
+        passed: 0,
+        failed: 0,
+        ignored: 0,
+        crashed: 0,
+        not_run: 0,
+        flaky: 0,
+        regressions: 0,
+        duration: 0
+    };
+
+    TestGroup {
+        test_group: group_start.test_group.clone(),
+        summary,
+        modules: Vec::new(),
+        crash: None,
+        protocol_version: 0,
+        diagnostics: Vec::new(),
+        configuration: None
+    }
+}
+
+/// Create a test result from its protocol message.
+///
+/// Unknown results are recorded as failures, so they are not silently lost.
+fn test_from_record(record: &TestResultRecord) -> TestResult {
+    let mut test = TestResult::new(
+        record.test.clone(),
+        TestOutcome::from_record(record).unwrap_or(TestOutcome::Fail),
+        record.cycle_count.unwrap_or(0),
+        record.location.clone(),
+        record.message.clone()
+    );
+    test.tags = record.tags.clone();
+    test.duration_us = record.duration_us;
+    test.data = record.data.clone();
+    test
+}
+
+/// Helper function to extract the module name from a fully qualified test name.
+fn module_from_name(name: &str) -> String {
+    let parts: Vec<&str> = name.rsplitn(2, "::").collect();
+    if parts.len() == 2 {
+        parts[1].to_string()
+    } else {
+        "unknown".to_string()
+    }
+}
+
+/// Helper function to extract the function name from a fully qualified test name.
+fn function_from_name(name: &str) -> String {
+    let parts: Vec<&str> = name.rsplitn(2, "::").collect();
+    if parts.len() == 2 {
+        parts[0].to_string()
+    } else {
+        name.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn when_output_is_parsed_then_results_are_grouped_by_module() {
+        let output = [
+            r#"{"ktest_protocol":1}"#,
+            r#"{"test_group":"kernel","test_count":3,"use_kview":false}"#,
+            r#"{"test":"kernel::memory::test_alloc","result":"pass","cycle_count":1200}"#,
+            "panicked at src/lib.rs:10",
+            r#"{"test":"kernel::memory::test_free","result":"fail","cycle_count":800,"message":"double free"}"#
+        ].join("\n");
+
+        let test_group = TestResultParser::parse(&output).unwrap();
+
+        assert_eq!(test_group.test_group, "kernel");
+        assert_eq!(test_group.protocol_version, 1);
+        assert_eq!(test_group.modules.len(), 1);
+        assert_eq!(test_group.modules[0].module, "kernel::memory");
+        assert_eq!(test_group.modules[0].tests[1].test, "test_free");
+        assert_eq!((test_group.summary.passed, test_group.summary.failed, test_group.summary.ignored), (1, 1, 1));
+        assert_eq!(test_group.diagnostics.len(), 1);
+        assert_eq!(test_group.diagnostics[0].line, 4);
+    }
+}
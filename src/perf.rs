@@ -0,0 +1,255 @@
+use std::{collections::BTreeMap, fs, path::{Path, PathBuf}};
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use crate::{args, config::{self, PerformanceConfig}, console, ktest::{TestGroup, TestOutcome}, stats, BUILD_DIRECTORY};
+
+/// Cycle counts per test group, keyed by fully qualified test name.
+/// This is the layout of the baseline file that may be committed to a repository.
+type Baseline = BTreeMap<String, BTreeMap<String, u64>>;
+
+/// Compares the cycle count of every passed test in the group against a reference.
+///
+/// The reference is taken from the committed baseline file if it contains the test,
+/// otherwise from the same test in the most recent rounds in `.build/testing-*`.
+pub fn analyze_test_group(test_group: &mut TestGroup) -> Result<()> {
+    let perf_config = &config::get_config()?.performance;
+    let baseline = load_baseline(perf_config)?;
+    let committed = baseline.get(&test_group.test_group);
+    let history = load_history(&test_group.test_group, perf_config.window)?;
+
+    let mut regressions = 0;
+    for module in test_group.modules.iter_mut() {
+        for test in module.tests.iter_mut().filter(|t| t.outcome == TestOutcome::Pass) {
+            let name = format!("{}::{}", module.module, test.test);
+            let samples = match committed.and_then(|c| c.get(&name)) {
+                Some(cycles) => vec![*cycles as f64],
+                None => match history.get(&name) {
+                    Some(samples) => samples.clone(),
+                    None => continue
+                }
+            };
+
+            let comparison = compare(test.cycle_count, &samples, perf_config);
+            if comparison.as_ref().is_some_and(|c| c.regression) {
+                regressions += 1;
+            }
+            test.performance = comparison;
+        }
+    }
+
+    test_group.summary.regressions = regressions;
+    Ok(())
+}
+
+/// Compare a cycle count against reference samples. A slowdown is a regression when it
+/// exceeds the configured percentage and, if the samples vary, the configured z-score.
+fn compare(cycle_count: u64, samples: &[f64], perf_config: &PerformanceConfig) -> Option<PerformanceComparison> {
+    let mean = stats::mean(samples)?;
+    if mean <= 0.0 {
+        return None;
+    }
+
+    let current = cycle_count as f64;
+    let change_percent = (current - mean) / mean * 100.0;
+    let significant = match stats::std_dev(samples) {
+        Some(std_dev) if std_dev > 0.0 => (current - mean) / std_dev >= perf_config.z_score,
+        _ => true // a single sample or identical samples, only the percentage counts
+    };
+
+    Some(PerformanceComparison {
+        baseline_cycles: mean.round() as u64,
+        samples: samples.len(),
+        change_percent,
+        significant,
+        regression: significant && change_percent >= perf_config.threshold_percent
+    })
+}
+
+/// Print all flagged regressions of the test group to the terminal.
+pub fn print_regressions(test_group: &TestGroup) {
+    if test_group.summary.regressions == 0 {
+        return;
+    }
+
+    console::print_human(&format!("kboot: {} performance regression(s) in {}:", test_group.summary.regressions, test_group.test_group));
+    for module in &test_group.modules {
+        for test in &module.tests {
+            if let Some(perf) = test.performance.as_ref().filter(|p| p.regression) {
+                let message = format!("{}::{}: {} -> {} cycles ({:+.1}%)",
+                    module.module, test.test, perf.baseline_cycles, test.cycle_count, perf.change_percent);
+                console::print_human(&format!("    {}", message));
+                log::warn!("Performance regression: {}", message);
+            }
+        }
+    }
+}
+
+/// Fail if any test slowed down by more than `fail_percent`, when it is configured.
+/// Like regressions, slowdowns within the noise of the samples are not counted,
+/// but `fail_percent` applies on its own, even below `threshold_percent`.
+pub fn check_fail_threshold(test_group: &TestGroup) -> Result<()> {
+    let Some(fail_percent) = config::get_config()?.performance.fail_percent else {
+        return Ok(());
+    };
+
+    let slow_tests: Vec<String> = test_group.modules.iter()
+        .flat_map(|m| m.tests.iter().map(move |t| (m, t)))
+        .filter(|(_, t)| t.performance.as_ref().is_some_and(|p| p.is_slower_than(fail_percent)))
+        .map(|(m, t)| format!("{}::{}", m.module, t.test))
+        .collect();
+
+    if slow_tests.is_empty() {
+        return Ok(());
+    }
+
+    Err(anyhow!("{} test(s) in {} slowed down by more than {}%: {}",
+        slow_tests.len(), test_group.test_group, fail_percent, slow_tests.join(", ")))
+}
+
+/// Record the cycle counts of all passed tests in the groups into the baseline file.
+///
+/// The file is read and written once, so the groups of a round must be saved together:
+/// the test executables run by `kboot test` leave this to the orchestrator.
+pub fn save_baseline(test_groups: &[TestGroup]) -> Result<()> {
+    let perf_config = &config::get_config()?.performance;
+    let mut baseline = load_baseline(perf_config)?;
+
+    for test_group in test_groups {
+        let group_baseline = baseline.entry(test_group.test_group.clone()).or_default();
+        for module in &test_group.modules {
+            for test in module.tests.iter().filter(|t| t.outcome == TestOutcome::Pass) {
+                group_baseline.insert(format!("{}::{}", module.module, test.test), test.cycle_count);
+            }
+        }
+    }
+
+    let baseline_path = get_baseline_path(perf_config)?;
+    fs::write(&baseline_path, serde_json::to_string_pretty(&baseline)?)?;
+    for test_group in test_groups {
+        log::info!("Saved cycle count baseline for {} to {}", test_group.test_group, baseline_path.display());
+    }
+
+    Ok(())
+}
+
+/// Load the committed baseline file, or an empty baseline if it does not exist.
+fn load_baseline(perf_config: &PerformanceConfig) -> Result<Baseline> {
+    let baseline_path = get_baseline_path(perf_config)?;
+    if !baseline_path.exists() {
+        return Ok(Baseline::new());
+    }
+
+    let content = fs::read_to_string(&baseline_path)?;
+    serde_json::from_str(&content)
+        .map_err(|e| anyhow!("Failed to parse baseline file {}: {}", baseline_path.display(), e))
+}
+
+fn get_baseline_path(perf_config: &PerformanceConfig) -> Result<PathBuf> {
+    Ok(args::get_workspace_root()?.join(&perf_config.baseline_file))
+}
+
+/// Collect cycle counts of passed tests for the given group from the
+/// most recent `window` rounds that contain it.
+fn load_history(test_group: &str, window: usize) -> Result<BTreeMap<String, Vec<f64>>> {
+    let build_dir = args::get_workspace_root()?.join(BUILD_DIRECTORY);
+    let mut history: BTreeMap<String, Vec<f64>> = BTreeMap::new();
+
+    let group_files = get_round_directories(&build_dir)?.into_iter()
+        .map(|dir| dir.join(format!("tests-{}.json", test_group)))
+        .filter(|path| path.exists())
+        .take(window);
+
+    for path in group_files {
+        let content = fs::read_to_string(&path)?;
+        let previous: TestGroup = match serde_json::from_str(&content) {
+            Ok(previous) => previous,
+            Err(e) => {
+                log::warn!("Skipping unreadable test history {}: {}", path.display(), e);
+                continue;
+            }
+        };
+
+        for module in &previous.modules {
+            for test in module.tests.iter().filter(|t| t.outcome == TestOutcome::Pass) {
+                history.entry(format!("{}::{}", module.module, test.test))
+                    .or_default()
+                    .push(test.cycle_count as f64);
+            }
+        }
+    }
+
+    Ok(history)
+}
+
+/// Get all timestamped round directories (`testing-<millis>`), newest first.
+pub fn get_round_directories(build_dir: &Path) -> Result<Vec<PathBuf>> {
+    if !build_dir.exists() {
+        return Ok(Vec::new());
+    }
+
+    let mut rounds: Vec<(u128, PathBuf)> = Vec::new();
+    for entry in fs::read_dir(build_dir)? {
+        let path = entry?.path();
+        let timestamp = path.file_name()
+            .and_then(|n| n.to_str())
+            .and_then(|n| n.strip_prefix("testing-"))
+            .and_then(|t| t.parse::<u128>().ok());
+
+        if let Some(timestamp) = timestamp && path.is_dir() {
+            rounds.push((timestamp, path));
+        }
+    }
+
+    rounds.sort_by_key(|(timestamp, _)| std::cmp::Reverse(*timestamp));
+    Ok(rounds.into_iter().map(|(_, path)| path).collect())
+}
+
+/// Result of comparing a test's cycle count against its baseline.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PerformanceComparison {
+    pub baseline_cycles: u64,
+    pub samples: usize,
+    pub change_percent: f64,
+    /// Whether the change exceeds the noise of the samples (the configured z-score)
+    #[serde(default)]
+    pub significant: bool,
+    pub regression: bool
+}
+
+impl PerformanceComparison {
+    /// Whether the test slowed down significantly, by more than the percentage
+    fn is_slower_than(&self, percent: f64) -> bool {
+        self.significant && self.change_percent > percent
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn when_slowdown_exceeds_noise_and_threshold_then_regression() {
+        let perf_config = PerformanceConfig::default();
+        let samples = [1000.0, 1010.0, 990.0, 1005.0, 995.0];
+
+        let noisy = compare(1015, &samples, &perf_config).unwrap();
+        assert!(!noisy.regression);
+
+        let slow = compare(1500, &samples, &perf_config).unwrap();
+        assert!(slow.regression);
+        assert_eq!(slow.baseline_cycles, 1000);
+        assert!((slow.change_percent - 50.0).abs() < 0.01);
+    }
+
+    #[test]
+    fn when_slowdown_is_below_threshold_but_above_fail_percent_then_it_fails() {
+        let perf_config = PerformanceConfig { fail_percent: Some(5.0), ..PerformanceConfig::default() };
+
+        let slow = compare(1070, &[1000.0], &perf_config).unwrap();
+        assert!(!slow.regression);
+        assert!(slow.is_slower_than(5.0));
+
+        let noisy = compare(1070, &[1000.0, 1100.0, 900.0], &perf_config).unwrap();
+        assert!(!noisy.is_slower_than(5.0));
+    }
+}
@@ -0,0 +1,4 @@
+//! The wire protocol between `ktest` (inside the kernel) and kboot, which is
+//! defined in the `no_std` crate `ktest-protocol` so that `ktest` can share it.
+
+pub use ktest_protocol::*;
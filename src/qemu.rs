@@ -1,54 +1,222 @@
-use std::{path::PathBuf, time::Duration};
+use std::{path::{self, Path, PathBuf}, process::{Child, Command, Stdio}, thread, time::{Duration, Instant}};
 use anyhow::{anyhow, Result};
-use crate::{args, BUILD_DIRECTORY, UUID};
+use uuid::Uuid;
+use crate::{args, builder::DiskImageType, config::{self, Accel, SmpConfig, VmBackend, VmConfig}, console::{self, DebugconWatcher}, container::{self, PublishedPort}, coverage, interrupt, ktest, matrix, qmp::{self, QmpClient, QmpState}, snapshot, UUID};
 
-/// Executes the QEMU virtual machine inside a Docker container, booting 
-/// the UEFI image (*.img) that was built in the `build.rs` script.
+/// Executes the QEMU virtual machine inside a Docker container (or natively,
+/// if configured), booting the UEFI image (*.img) that was built in the `build.rs` script.
 /// 
 /// The virtual machine is accessible through command line and web (noVNC)
-/// interfaces. The web interface is available at `http://localhost:8006`
-pub fn run() -> Result<Duration> {
-    // check if docker is running, otherwise exit with error
-    if !is_docker_running() {
-        eprintln!("Docker does not seem to be running. Please start Docker and try again.");
-        std::process::exit(1);
+/// interfaces. The web interface is available at `http://localhost:8006`,
+/// and QEMU can be controlled through QMP at `localhost:4444` (see `kboot vm`).
+/// If these ports are taken (e.g. by a concurrent run), other free ports are used.
+/// 
+/// If a `[vm] timeout` is configured, QEMU is stopped after that many seconds.
+pub fn run() -> Result<QemuRun> {
+    let vm_config = &matrix::get_vm_config()?;
+
+    // check if docker is running, otherwise fail with an error
+    if vm_config.backend == VmBackend::Docker && !is_docker_running() {
+        return Err(anyhow!("Docker does not seem to be running. Please start Docker and try again."));
+    }
+
+    // containers of runs that were killed would otherwise keep running
+    if vm_config.backend == VmBackend::Docker && let Err(e) = container::remove_orphaned_containers() {
+        log::warn!("Failed to remove orphaned containers: {}", e);
+    }
+
+    let qemu_options = if args::has_qemu_options() { args::get_qemu_options()? } else { vec![] };
+    if !qemu_options.is_empty() {
+        log::info!("QEMU options detected: {}", qemu_options.join(" "));
     }
 
-    if args::has_qemu_options() {
-        log::info!("QEMU options detected: {}", args::get_qemu_options()?.join(" "));
+    for warning in get_hardware_warnings(vm_config) {
+        eprintln!("kboot: {}", warning);
+        log::warn!("{}", warning);
     }
 
-    // prepare the arguments for running QEMU in Docker
-    let mut run_args = RunArguments::default()?;
+    // the command line configures the same runner that is available to build scripts
+    let build_path = args::get_build_directory()?;
+    let runner = VmRunner::new(build_path.join("kernel.img"))
+        .build_directory(build_path)
+        .vm_config(vm_config.clone())
+        .image_type(if matrix::is_legacy_boot() { DiskImageType::Bios } else { DiskImageType::Uefi })
+        .qemu_args(qemu_options)
+        .test(args::is_test()?)
+        .interactive(!args::is_headless())
+        .port_range(config::get_config()?.ports.range)
+        .session(*UUID.get().unwrap())
+        .prefer_ports(!args::is_isolated_run());
+    let mut run_args = runner.get_run_arguments()?;
+    run_args.cli = true;
 
-    // if the executable is a test executable, add the test arguments
-    if args::is_test()? {
-        run_args.qemu_test_args.extend(TEST_ARGUMENTS.iter().map(|s| s.to_string()));
+    // test output is processed while QEMU runs, and archived with the test round
+    if runner.test {
         setup_test_output(&mut run_args)?;
-    }    
+    }
 
-    // if custom QEMU arguments are provided, use them
-    if args::has_qemu_options() {
-        run_args.qemu_run_args = args::get_qemu_options()?;
+    // restore the snapshot of this image if one exists, otherwise create it;
+    // the snapshot point is only seen while the -debugcon output is processed
+    if args::is_snapshot() && run_args.debugcon_path.is_some() {
+        let snapshot_dir = snapshot::get_snapshot_dir()?;
+        std::fs::create_dir_all(&snapshot_dir)?;
+        run_args.snapshot_volume = Some(format!("{}:/snapshots", snapshot_dir.display()));
+        run_args.release_port = Some(runner.allocate_port(snapshot::RELEASE_PORT)?);
+        let snapshot_path = snapshot::get_snapshot_path(&get_machine_description(&run_args, vm_config)?)?;
+        if snapshot_path.exists() {
+            run_args.restore_snapshot = Some(snapshot_path);
+            snapshot::set_pending(None);
+        } else {
+            snapshot::set_pending(Some(snapshot_path));
+        }
     }
 
     run_args.print();
 
     // run QEMU in Docker and capture the exit code
     let mut stopwatch = stopwatch::Stopwatch::start_new();
-    let exit_code = run_qemu(&run_args)?;
+    let (exit_code, timed_out) = match run_qemu(&run_args, vm_config) {
+        Err(e) if let Some(snapshot_path) = run_args.restore_snapshot.take() => {
+            eprintln!("Failed to restore snapshot, booting from scratch: {}", e);
+            log::warn!("Failed to restore snapshot {}, booting from scratch: {}", snapshot_path.display(), e);
+            snapshot::discard(&snapshot_path)?;
+            snapshot::set_pending(Some(snapshot_path));
+            run_qemu(&run_args, vm_config)?
+        },
+        result => result?
+    };
     stopwatch.stop();
 
-    if exit_code == QemuExitCode::Failed as i32 {
+    if is_interrupted(&run_args) {
+        eprintln!("QEMU was stopped because kboot was interrupted");
+        log::warn!("QEMU was stopped because kboot was interrupted");
+    } else if timed_out {
+        eprintln!("QEMU was stopped after the timeout of {}s", config::get_config()?.vm.timeout.unwrap_or_default());
+        log::error!("QEMU was stopped after the timeout");
+    } else if exit_code == QemuExitCode::Failed as i32 {
         eprintln!("QEMU exited with failure code: {}", exit_code);
-        std::process::exit(exit_code);
+        log::error!("QEMU exited with failure code: {}", exit_code);
     } else if exit_code == QemuExitCode::Success as i32 {
         log::info!("QEMU exited successfully with code: {}", exit_code);
     } else {
         log::warn!("QEMU exited with unknown code: {}", exit_code);
     }
 
-    Ok(stopwatch.elapsed())
+    Ok(QemuRun { duration: stopwatch.elapsed(), exit_code, timed_out, interrupted: is_interrupted(&run_args) })
+}
+
+/// Get the directory in which QEMU sees the testing directory: where it is
+/// mounted in the container, or the directory itself when running natively.
+pub fn get_qemu_testing_dir() -> Result<String> {
+    Ok(get_testing_dir_in_vm(config::get_config()?.vm.backend, &args::get_build_directory()?.join("testing")))
+}
+
+fn get_testing_dir_in_vm(backend: VmBackend, testing_path: &Path) -> String {
+    match backend {
+        VmBackend::Docker => "/testing/logs".to_string(),
+        VmBackend::Native => testing_path.display().to_string()
+    }
+}
+
+/// Get the directory in which QEMU sees the snapshot directory
+pub fn get_qemu_snapshot_dir() -> Result<String> {
+    Ok(match config::get_config()?.vm.backend {
+        VmBackend::Docker => "/snapshots".to_string(),
+        VmBackend::Native => snapshot::get_snapshot_dir()?.display().to_string()
+    })
+}
+
+/// Translate the typed hardware settings into QEMU arguments. For the container,
+/// memory, CPU count and acceleration are passed as environment variables instead
+/// (see `get_container_environment`), since its start script sizes the VM with them.
+fn get_hardware_arguments(vm: &VmConfig) -> Vec<String> {
+    let mut arguments = Vec::new();
+    let native = vm.backend == VmBackend::Native;
+
+    if native && let Some(memory) = &vm.memory {
+        arguments.extend(["-m".to_string(), memory.clone()]);
+    }
+
+    // the container adds its own -smp for CPU_CORES, which a second one would conflict with
+    let smp = &vm.smp;
+    if native && (smp.cpus.is_some() || has_topology(smp)) {
+        let topology = [("cpus", smp.cpus), ("sockets", smp.sockets), ("cores", smp.cores), ("threads", smp.threads)]
+            .iter()
+            .filter_map(|(name, value)| value.map(|value| format!("{}={}", name, value)))
+            .collect::<Vec<String>>();
+        arguments.extend(["-smp".to_string(), topology.join(",")]);
+    }
+
+    if let Some(machine) = &vm.machine {
+        arguments.extend(["-machine".to_string(), machine.clone()]);
+    }
+
+    if vm.cpu.is_some() || !vm.cpu_features.is_empty() {
+        let mut cpu = vec![vm.cpu.clone().unwrap_or_else(|| "max".to_string())];
+        cpu.extend(vm.cpu_features.iter().cloned());
+        arguments.extend(["-cpu".to_string(), cpu.join(",")]);
+    }
+
+    if native {
+        match vm.accel {
+            Some(Accel::Kvm) => arguments.extend(["-accel".to_string(), "kvm".to_string()]),
+            Some(Accel::Tcg) => arguments.extend(["-accel".to_string(), "tcg".to_string()]),
+            // use KVM if available, otherwise fall back to emulation
+            None => arguments.extend(["-accel", "kvm", "-accel", "tcg"].map(String::from))
+        }
+    }
+
+    for device in &vm.devices {
+        arguments.extend(["-device".to_string(), device.clone()]);
+    }
+
+    arguments
+}
+
+/// Environment variables of the `qemux/qemu` container for the hardware settings
+/// and the firmware that boots the image
+fn get_container_environment(vm: &VmConfig, image_type: DiskImageType) -> Vec<String> {
+    let boot_mode = match image_type {
+        DiskImageType::Bios => "legacy",
+        DiskImageType::Uefi => "uefi"
+    };
+    let mut environment = vec![format!("BOOT_MODE={}", boot_mode)];
+    if let Some(memory) = &vm.memory {
+        environment.push(format!("RAM_SIZE={}", memory));
+    }
+    if let Some(cpus) = get_cpu_count(&vm.smp) {
+        environment.push(format!("CPU_CORES={}", cpus));
+    }
+    if vm.accel == Some(Accel::Tcg) {
+        environment.push("KVM=N".to_string());
+    }
+    environment
+}
+
+/// Warnings about hardware settings that the backend cannot apply as configured
+fn get_hardware_warnings(vm: &VmConfig) -> Vec<String> {
+    let mut warnings = Vec::new();
+    if vm.backend == VmBackend::Docker {
+        if vm.accel == Some(Accel::Kvm) {
+            warnings.push("`accel = \"kvm\"` cannot be enforced in the container, which uses KVM if /dev/kvm is available and fails otherwise".to_string());
+        }
+        if has_topology(&vm.smp) {
+            warnings.push(format!("the container only supports a number of CPUs, so the [vm.smp] topology is ignored and {} CPUs are used",
+                get_cpu_count(&vm.smp).unwrap_or_default()));
+        }
+    }
+    warnings
+}
+
+fn has_topology(smp: &SmpConfig) -> bool {
+    smp.sockets.is_some() || smp.cores.is_some() || smp.threads.is_some()
+}
+
+/// Number of CPUs of the VM: the configured count, or the product of the topology
+fn get_cpu_count(smp: &SmpConfig) -> Option<u32> {
+    smp.cpus.or_else(|| has_topology(smp).then(|| {
+        [smp.sockets, smp.cores, smp.threads].iter().map(|value| value.unwrap_or(1)).product()
+    }))
 }
 
 /// A simple helper to determine if Docker daemon is running.
@@ -63,51 +231,352 @@ fn is_docker_running() -> bool {
     }
 }
 
-/// Setup for the -debugcon output to a file
+/// Setup for processing and archiving the output of a test run of the command line
 fn setup_test_output(run_args: &mut RunArguments) -> Result<()> {
+    // results are processed while QEMU runs, unless ktest processing is disabled
+    if !args::is_no_ktest() {
+        run_args.debugcon_path = Some(run_args.testing_path.join(get_test_output_file_name(&run_args.session)));
+    }
+
+    // the coverage profile is written to its own channel, next to the test output
+    if args::is_coverage() && !args::is_bench() {
+        run_args.qemu_test_args.extend(coverage::get_qemu_arguments()?);
+    }
+
+    // the serial console is archived with the test round, which benchmarks are not part of
+    if !args::is_bench() {
+        run_args.serial_log_path = Some(run_args.testing_path.join(format!("serial-{}.log", run_args.session)));
+    }
+
+    Ok(())
+}
+
+/// Write the -debugcon output of QEMU to a file in the testing directory.
+fn add_debugcon_output(run_args: &mut RunArguments, backend: VmBackend) -> Result<()> {
+    let file_name = get_test_output_file_name(&run_args.session);
     run_args.qemu_test_args.push("-debugcon".to_string());
-    run_args.qemu_test_args.push(format!("file:/testing/logs/tests-{}.json", UUID.get().unwrap()));
+    run_args.qemu_test_args.push(format!("file:{}/{}", get_testing_dir_in_vm(backend, &run_args.testing_path), file_name));
 
     std::fs::create_dir_all(&run_args.testing_path)?;
-    let log_path = run_args.testing_path.join(format!("tests-{}.json", UUID.get().unwrap()));
-    std::fs::File::create(&log_path)?;
+    std::fs::File::create(run_args.testing_path.join(file_name))?;
 
     Ok(())
 }
 
-/// Run QEMU inside a Docker container with the specified arguments.
-fn run_qemu(run_args: &RunArguments)-> Result<i32> {
+/// Name of the file with the -debugcon output of a session
+fn get_test_output_file_name(session: &Uuid) -> String {
+    format!("tests-{}.json", session)
+}
+
+/// Run QEMU inside a Docker container (or natively) with the specified arguments.
+/// 
+/// Returns the exit code and whether QEMU was stopped because of the timeout.
+fn run_qemu(run_args: &RunArguments, vm_config: &VmConfig) -> Result<(i32, bool)> {
+    let qemu_args = get_qemu_arguments(run_args, |port, container_port| get_listen_address(vm_config.backend, port, container_port));
+    let mut command = match vm_config.backend {
+        VmBackend::Docker => get_docker_command(run_args, vm_config, &qemu_args),
+        VmBackend::Native => get_native_command(run_args, vm_config, &qemu_args)?
+    };
+
+    // the serial console is piped through kboot, so it can be copied to a log file
+    if run_args.serial_log_path.is_some() {
+        command.stdout(Stdio::piped());
+    } else if console::is_stdout_reserved() {
+        command.stdout(std::io::stderr());
+    }
+
+    // perform the execution of the run command and capture the exit code
+    let mut child = command.spawn()?;
+    let serial_tee = child.stdout.take()
+        .zip(run_args.serial_log_path.clone())
+        .map(|(stdout, log_path)| console::tee_serial(stdout, log_path));
+    let debugcon_watcher = run_args.debugcon_path.clone().map(|path| {
+        ktest::start_stream();
+        DebugconWatcher::start(path, ktest::process_streamed_line)
+    });
+
+    // ports chosen by Docker are known once it started the container
+    let session = &run_args.session;
+    let ports = resolve_port(session, run_args.qmp_port, qmp::QMP_PORT, &mut child).and_then(|qmp_port| Ok((
+        qmp_port,
+        run_args.release_port.map(|port| resolve_port(session, port, snapshot::RELEASE_PORT, &mut child)).transpose()?,
+        run_args.vnc_port.map(|port| resolve_port(session, port, NOVNC_PORT, &mut child)).transpose()?
+    )));
+    let (qmp_port, release_port, vnc_port) = match ports {
+        Ok(ports) => ports,
+        Err(e) => {
+            kill_vm(session, vm_config.backend, &mut child);
+            return Err(e);
+        }
+    };
+    if vm_config.backend == VmBackend::Docker && let Some(vnc_port) = vnc_port {
+        console::print_human(&format!("kboot: web display (noVNC) at http://localhost:{}", vnc_port));
+    }
+
+    let build_dir = run_args.build_path.clone();
+    let qmp_address = format!("127.0.0.1:{}", qmp_port);
+    // the helpers that control the VM of the command line (e.g. screenshots) find it through these
+    if run_args.cli {
+        qmp::set_current_address(Some(qmp_address.clone()));
+        snapshot::set_release_address(release_port.map(|port| format!("127.0.0.1:{}", port)));
+    }
+    qmp::write_state(&build_dir, &QmpState {
+        address: qmp_address.clone(),
+        container: container::get_qemu_container_name(session),
+        session: session.to_string(),
+        testing_dir: get_testing_dir_in_vm(vm_config.backend, &run_args.testing_path)
+    })?;
+
+    // a VM that cannot be restored is stopped, and the error returned once it exited;
+    // a restored kernel waits at its snapshot point until its earlier output was replayed
+    let mut restore_error = None;
+    if let Some(snapshot_path) = &run_args.restore_snapshot
+        && let Err(e) = snapshot::restore(snapshot_path).and_then(|prelude| {
+            ktest::replay_streamed_lines(&prelude);
+            snapshot::release()
+        }) {
+        stop_vm(session, &qmp_address, vm_config.backend, &mut child);
+        restore_error = Some(e);
+    }
+
+    // wait for QEMU to exit, stopping it once the timeout has passed
+    let timeout = vm_config.timeout.map(Duration::from_secs);
+    let started = Instant::now();
+    let mut timed_out = false;
+    let mut interrupted = false;
+    let status = loop {
+        if let Some(status) = child.try_wait()? {
+            break status;
+        }
+        if !interrupted && is_interrupted(run_args) {
+            interrupted = true;
+            stop_vm(session, &qmp_address, vm_config.backend, &mut child);
+        }
+        if let Some(timeout) = timeout && !timed_out && started.elapsed() >= timeout {
+            timed_out = true;
+            console::print_live(&format!("kboot: timeout of {}s reached, stopping QEMU", timeout.as_secs()));
+            if run_args.debugcon_path.is_some() {
+                ktest::capture_running_test_screenshot();
+            }
+            stop_vm(session, &qmp_address, vm_config.backend, &mut child);
+        }
+        thread::sleep(WAIT_INTERVAL);
+    };
+    qmp::remove_state(&build_dir)?;
+    if run_args.cli {
+        qmp::set_current_address(None);
+        snapshot::set_release_address(None);
+    }
+
+    let exit_code = match status.code() {
+        Some(exit_code) => exit_code,
+        // QEMU receives the Ctrl-C as well, which may terminate it
+        None if is_interrupted(run_args) => interrupt::INTERRUPTED_EXIT_CODE,
+        None => return Err(anyhow!("Failed to get exit code from QEMU process"))
+    };
+
+    if let Some(serial_tee) = serial_tee {
+        serial_tee.join().map_err(|_| anyhow!("Serial console thread panicked"))??;
+    }
+    if let Some(watcher) = debugcon_watcher {
+        watcher.stop()?;
+    }
+
+    if let Some(e) = restore_error {
+        return Err(e);
+    }
+
+    Ok((exit_code, timed_out))
+}
+
+/// Get the arguments for QEMU, with the servers for QMP and the snapshot release
+/// listening on the address for their port and container port.
+fn get_qemu_arguments(run_args: &RunArguments, listen_address: impl Fn(PublishedPort, u16) -> String) -> Vec<String> {
+    // QMP and the hardware settings come first, so custom and test arguments can override them
+    let mut qemu_args = qmp::get_qemu_arguments(&listen_address(run_args.qmp_port, qmp::QMP_PORT));
+    if let Some(release_port) = run_args.release_port {
+        qemu_args.extend(snapshot::get_qemu_arguments(&listen_address(release_port, snapshot::RELEASE_PORT)));
+    }
+    qemu_args.extend(run_args.qemu_hardware_args.iter().cloned());
+    qemu_args.extend(run_args.qemu_run_args.iter().cloned());
+    qemu_args.extend(run_args.qemu_test_args.iter().cloned());
+    if run_args.restore_snapshot.is_some() {
+        qemu_args.extend(["-incoming".to_string(), "defer".to_string()]);
+    }
+    qemu_args
+}
+
+/// Describe the machine that a run boots: the full QEMU command line and, in the
+/// container, the environment that sizes the VM and the image providing QEMU.
+/// Host ports and the session only change how the VM is reached and where its
+/// output is written, so they are left out.
+fn get_machine_description(run_args: &RunArguments, vm_config: &VmConfig) -> Result<Vec<String>> {
+    let mut description = get_qemu_arguments(run_args, |_, container_port| container_port.to_string());
+    match vm_config.backend {
+        VmBackend::Docker => {
+            description.extend(get_container_environment(vm_config, run_args.image_type));
+            description.push(QEMU_CONTAINER_IMAGE.to_string());
+        },
+        VmBackend::Native => {
+            let command = get_native_command(run_args, vm_config, &[])?;
+            description.push(command.get_program().to_string_lossy().to_string());
+            description.extend(command.get_args().map(|arg| arg.to_string_lossy().to_string()));
+        }
+    }
+
+    let session = run_args.session.to_string();
+    Ok(description.into_iter().map(|arg| arg.replace(&session, "")).collect())
+}
+
+/// Build the command that runs QEMU in the `qemux/qemu` container.
+fn get_docker_command(run_args: &RunArguments, vm_config: &VmConfig, qemu_args: &[String]) -> Command {
     // build the docker command to run the qemu image
-    let mut docker_binding = std::process::Command::new("docker");
-    let command_builder = docker_binding
+    let mut command_builder = Command::new("docker");
+    command_builder
         .arg("run")                 // docker run command
         .arg("--rm");               // remove the container after it exits
         
-    #[cfg(not(feature = "ci"))]
-    command_builder.arg("-it");     // interactive terminal during runtime (works with kernel input)
-    #[cfg(feature = "ci")]
-    command_builder.arg("-t");      // non-interactive terminal for CI environments
+    if !run_args.interactive {
+        command_builder.arg("-t");  // non-interactive terminal for CI environments
+    } else if !run_args.qemu_test_args.is_empty() {
+        // tests keep the terminal out of raw mode, so that Ctrl-C interrupts kboot
+        // (and is forwarded to the container) instead of being typed into the VM
+        command_builder.args(["-i", "--sig-proxy=true"]);
+    } else {
+        command_builder.arg("-it"); // interactive terminal during runtime (works with kernel input)
+    }
 
-    command_builder.args(["--name", "qemu"])   // name of the container
-        .args(["-p", "8006:8006"])  // port 8006 for web display (noVNC)
+    command_builder.args(["--name", &container::get_qemu_container_name(&run_args.session)])   // name of the container, unique per session
+        .args(container::get_qemu_labels(&run_args.session))   // labels to find the container if kboot dies
+        .args(run_args.vnc_port.iter().flat_map(|port| ["-p".to_string(), port.get_argument(NOVNC_PORT, false)]))  // web display (noVNC)
+        .args(["-p", &run_args.qmp_port.get_argument(qmp::QMP_PORT, true)]) // QMP, local only
+        .args(run_args.release_port.iter().flat_map(|port| ["-p".to_string(), port.get_argument(snapshot::RELEASE_PORT, true)])) // snapshot point release, local only
         // volumes (local filesystem -> container mappings)
         .args(["-v", &format!("{}/qemu-storage:/storage", run_args.build_path.display())])
         .args(["-v", &format!("{}:/boot.img", run_args.image_path.display())])
         .args(["-v", &format!("{}:/testing/logs", run_args.testing_path.display())])
+        .args(run_args.snapshot_volume.iter().flat_map(|volume| ["-v".to_string(), volume.clone()]))
         .arg("--device=/dev/kvm")
         .arg("--device=/dev/net/tun")
         .args(["--cap-add", "NET_ADMIN"])
+        // hardware settings understood by the container
+        .args(get_container_environment(vm_config, run_args.image_type).into_iter().flat_map(|variable| ["-e".to_string(), variable]))
         // QEMU arguments
-        .arg("-e").arg(&format!("ARGUMENTS={} {}", run_args.qemu_run_args.join(" "), run_args.qemu_test_args.join(" ")))
-        // run qemu in container using a specific version for stability, not latest
-        .arg("qemux/qemu:7.12");
+        .arg("-e").arg(format!("ARGUMENTS={}", qemu_args.join(" ")))
+        .arg(QEMU_CONTAINER_IMAGE);
 
-    // perform the execution of the run command and capture the exit code
-    let exit_code = command_builder.status()?
-        .code().ok_or_else(|| anyhow!("Failed to get exit code from QEMU process"))?;
-    Ok(exit_code)
+    command_builder
+}
+
+/// Build the command that runs the QEMU binary installed on the host.
+fn get_native_command(run_args: &RunArguments, vm_config: &VmConfig, qemu_args: &[String]) -> Result<Command> {
+    let mut command = Command::new(&vm_config.qemu_binary);
+    command.args(["-drive", &format!("format=raw,file={}", run_args.image_path.display())]);
+
+    if run_args.image_type == DiskImageType::Uefi {
+        let firmware = match &vm_config.firmware {
+            Some(firmware) => firmware.clone(),
+            None => find_uefi_firmware()
+                .ok_or_else(|| anyhow!("No UEFI firmware (OVMF) found, set `firmware` under [vm] in kboot.toml"))?
+        };
+        command.arg("-bios").arg(firmware);
+    }
+
+    // tests run with -nographic, which already connects the serial console to stdio
+    if run_args.qemu_test_args.is_empty() {
+        command.args(["-serial", "stdio"]);
+        if !run_args.interactive {
+            command.args(["-display", "none"]);
+        }
+    }
+
+    command.args(qemu_args);
+    Ok(command)
+}
+
+/// Look for the OVMF firmware in the locations used by common Linux distributions
+fn find_uefi_firmware() -> Option<PathBuf> {
+    const FIRMWARE_PATHS: [&str; 4] = [
+        "/usr/share/ovmf/OVMF.fd",
+        "/usr/share/OVMF/OVMF.fd",
+        "/usr/share/edk2/x64/OVMF.fd",
+        "/usr/share/edk2-ovmf/x64/OVMF.fd"
+    ];
+
+    FIRMWARE_PATHS.iter()
+        .map(Path::new)
+        .find(|path| path.exists())
+        .map(Path::to_path_buf)
+}
+
+/// Stop QEMU cleanly through QMP, or kill it (or its container) if QMP is not reachable.
+fn stop_vm(session: &Uuid, qmp_address: &str, backend: VmBackend, child: &mut Child) {
+    let quit = QmpClient::connect(qmp_address).and_then(|mut client| client.quit());
+    if let Err(e) = quit {
+        log::warn!("Failed to quit QEMU through QMP, killing it instead: {}", e);
+        kill_vm(session, backend, child);
+    }
+}
+
+/// Kill QEMU, or its container
+fn kill_vm(session: &Uuid, backend: VmBackend, child: &mut Child) {
+    let _ = match backend {
+        VmBackend::Docker => Command::new("docker")
+            .args(["kill", &container::get_qemu_container_name(session)])
+            .output()
+            .map(|_| ()),
+        VmBackend::Native => child.kill()
+    };
+    let _ = child.wait();
+}
+
+/// Whether the run is stopped because the command line was interrupted by Ctrl-C
+fn is_interrupted(run_args: &RunArguments) -> bool {
+    run_args.cli && interrupt::is_interrupted()
 }
 
+/// Address on which QEMU listens for a port of the VM: every interface of the container,
+/// from which Docker publishes it on the host, or the loopback interface of the host.
+fn get_listen_address(backend: VmBackend, port: PublishedPort, container_port: u16) -> String {
+    match (backend, port) {
+        (VmBackend::Native, PublishedPort::Fixed(port)) => format!("127.0.0.1:{}", port),
+        _ => format!("0.0.0.0:{}", container_port)
+    }
+}
+
+/// Get the host port of a port of the VM, waiting for Docker to publish it if it chooses the port.
+fn resolve_port(session: &Uuid, port: PublishedPort, container_port: u16, child: &mut Child) -> Result<u16> {
+    if let PublishedPort::Fixed(port) = port {
+        return Ok(port);
+    }
+
+    let started = Instant::now();
+    loop {
+        if let Some(port) = container::get_published_port(session, container_port)? {
+            return Ok(port);
+        }
+        if child.try_wait()?.is_some() {
+            return Err(anyhow!("The QEMU container exited before its port {} was published", container_port));
+        }
+        if started.elapsed() >= PUBLISH_TIMEOUT {
+            return Err(anyhow!("Port {} of the QEMU container was not published within {}s", container_port, PUBLISH_TIMEOUT.as_secs()));
+        }
+        thread::sleep(WAIT_INTERVAL);
+    }
+}
+
+/// Image of the container that runs QEMU, a specific version for stability, not latest
+const QEMU_CONTAINER_IMAGE: &str = "qemux/qemu:7.12";
+
+/// Port of the web display (noVNC) in the container, and the preferred port on the host
+const NOVNC_PORT: u16 = 8006;
+
+/// Interval at which the QEMU process is checked for exit
+const WAIT_INTERVAL: Duration = Duration::from_millis(100);
+
+/// How long to wait for Docker to publish the ports of the container
+const PUBLISH_TIMEOUT: Duration = Duration::from_secs(30);
+
 /// Arguments for QEMU when running tests.
 const TEST_ARGUMENTS: [&str; 4] = [
     "-device", "isa-debug-exit,iobase=0xf4,iosize=0x04",
@@ -116,43 +585,236 @@ const TEST_ARGUMENTS: [&str; 4] = [
     // -debugcon will be conditionally added for tests
 ];
 
-/// A collection of arguments needed to run QEMU in Docker.
+/// A collection of arguments needed to run QEMU.
 struct RunArguments {
+    /// Names the container and the output files of the run
+    session: Uuid,
+    /// Whether this is the run of the command line, which is controlled through the
+    /// process-wide QMP and snapshot addresses and stopped when kboot is interrupted
+    cli: bool,
     build_path: PathBuf,
     image_path: PathBuf,
     testing_path: PathBuf,
+    debugcon_path: Option<PathBuf>,
+    serial_log_path: Option<PathBuf>,
+    /// Snapshot to restore instead of booting
+    restore_snapshot: Option<PathBuf>,
+    /// Volume mapping of the snapshot directory, if snapshots are enabled
+    snapshot_volume: Option<String>,
+    /// Host port of the web display (noVNC), which is not published in headless mode
+    vnc_port: Option<PublishedPort>,
+    /// Host port of the QMP server
+    qmp_port: PublishedPort,
+    /// Host port that releases the kernel from its snapshot point, if snapshots are used
+    release_port: Option<PublishedPort>,
+    image_type: DiskImageType,
+    /// Whether a user may interact with the VM, through the terminal or a display
+    interactive: bool,
+    /// Arguments for the `[vm]` hardware settings
+    qemu_hardware_args: Vec<String>,
     qemu_run_args: Vec<String>,
     qemu_test_args: Vec<String>
 }
 
 impl RunArguments {
-    /// Create default RunArguments based on the provided command line arguments.
-    fn default() -> Result<Self> {
-        let workspace_directory = args::get_workspace_root()?;
-        let build_path = workspace_directory.join(BUILD_DIRECTORY);
-        let image_path = build_path.join("kernel.img");
-        let testing_path = build_path.join("testing");
-
-        Ok(Self {
-            build_path,
-            image_path,
-            testing_path,
-            qemu_run_args: vec![],
-            qemu_test_args: vec![]
-        })
-    }
-
     fn print(&self) {
         log::info!("=======================  <qemu>  =======================");
+        log::info!("Session:        {}", self.session);
         log::info!("Build path:     {}", self.build_path.display());
         log::info!("Image path:     {}", self.image_path.display());
         log::info!("Testing path:   {}", self.testing_path.display());
+        log::info!("Serial log:     {:?}", self.serial_log_path);
+        log::info!("Snapshot:       {:?}", self.restore_snapshot);
+        log::info!("Ports:          noVNC {:?}, QMP {:?}, release {:?}", self.vnc_port, self.qmp_port, self.release_port);
+        log::info!("Hardware args:  {:?}", self.qemu_hardware_args);
         log::info!("QEMU run args:  {:?}", self.qemu_run_args);
         log::info!("QEMU test args: {:?}", self.qemu_test_args);
         log::info!("========================================================");
     }
 }
 
+/// Runs a disk image in QEMU, without reading the command line or `kboot.toml`.
+/// Unlike `cargo run`, the VM is headless unless it is made interactive.
+///
+/// ```no_run
+/// use kboot::{VmConfig, VmRunner};
+///
+/// let vm_config = VmConfig { memory: Some("512M".to_string()), timeout: Some(60), ..VmConfig::default() };
+/// let runner = VmRunner::new(".build/kernel.img").vm_config(vm_config).test(true);
+/// let qemu_run = runner.run()?;
+///
+/// let output = std::fs::read_to_string(runner.test_output_path())?;
+/// let test_group = kboot::TestResultParser::parse(&output);
+/// println!("exit code {}, {:?}", qemu_run.exit_code, test_group.map(|g| g.summary));
+/// # Ok::<(), anyhow::Error>(())
+/// ```
+pub struct VmRunner {
+    image_path: PathBuf,
+    build_directory: PathBuf,
+    vm_config: VmConfig,
+    image_type: DiskImageType,
+    qemu_args: Vec<String>,
+    test: bool,
+    interactive: bool,
+    port_range: Option<[u16; 2]>,
+    session: Uuid,
+    /// Whether the well-known ports (e.g. 8006 for noVNC) are used if they are free
+    prefer_ports: bool
+}
+
+impl VmRunner {
+    /// Run a UEFI image with the default `[vm]` settings, using `.build` relative to the current directory.
+    pub fn new(image_path: impl Into<PathBuf>) -> Self {
+        Self {
+            image_path: image_path.into(),
+            build_directory: PathBuf::from(crate::BUILD_DIRECTORY),
+            vm_config: VmConfig::default(),
+            image_type: DiskImageType::Uefi,
+            qemu_args: Vec::new(),
+            test: false,
+            interactive: false,
+            port_range: None,
+            session: Uuid::new_v4(),
+            prefer_ports: false
+        }
+    }
+
+    /// Directory for the QEMU storage, the testing directory and the QMP state.
+    pub fn build_directory(mut self, build_directory: impl Into<PathBuf>) -> Self {
+        self.build_directory = build_directory.into();
+        self
+    }
+
+    /// Backend, hardware and timeout of the VM, as in the `[vm]` section of `kboot.toml`.
+    pub fn vm_config(mut self, vm_config: VmConfig) -> Self {
+        self.vm_config = vm_config;
+        self
+    }
+
+    pub fn image_type(mut self, image_type: DiskImageType) -> Self {
+        self.image_type = image_type;
+        self
+    }
+
+    /// Additional arguments for QEMU, which may override the hardware settings.
+    pub fn qemu_args(mut self, qemu_args: impl IntoIterator<Item = impl Into<String>>) -> Self {
+        self.qemu_args = qemu_args.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// Run a ktest executable: QEMU exits through `isa-debug-exit` and the test
+    /// output is written to [`test_output_path`](Self::test_output_path).
+    pub fn test(mut self, test: bool) -> Self {
+        self.test = test;
+        self
+    }
+
+    /// Attach the terminal to the container and publish the web display (noVNC).
+    pub fn interactive(mut self, interactive: bool) -> Self {
+        self.interactive = interactive;
+        self
+    }
+
+    /// Range (inclusive) of the host ports of the VM, as in the `[ports]` section of `kboot.toml`.
+    pub fn port_range(mut self, port_range: Option<[u16; 2]>) -> Self {
+        self.port_range = port_range;
+        self
+    }
+
+    /// Session of the command line, whose ID also names its log file
+    pub(crate) fn session(mut self, session: Uuid) -> Self {
+        self.session = session;
+        self
+    }
+
+    /// Runners are independent of each other, so they use whichever ports are free;
+    /// single runs of the command line keep the well-known ports
+    pub(crate) fn prefer_ports(mut self, prefer_ports: bool) -> Self {
+        self.prefer_ports = prefer_ports;
+        self
+    }
+
+    /// File that the -debugcon output of a test run is written to
+    pub fn test_output_path(&self) -> PathBuf {
+        self.build_directory.join("testing").join(get_test_output_file_name(&self.session))
+    }
+
+    /// Boot the image and wait for QEMU to exit, or for the timeout to pass.
+    pub fn run(&self) -> Result<QemuRun> {
+        if self.vm_config.backend == VmBackend::Docker && !is_docker_running() {
+            return Err(anyhow!("Docker does not seem to be running"));
+        }
+
+        let run_args = self.get_run_arguments()?;
+        run_args.print();
+        let started = Instant::now();
+        let (exit_code, timed_out) = run_qemu(&run_args, &self.vm_config)?;
+
+        Ok(QemuRun { duration: started.elapsed(), exit_code, timed_out, interrupted: false })
+    }
+
+    /// Arguments for running the image; the container and output files are named after the session.
+    fn get_run_arguments(&self) -> Result<RunArguments> {
+        // Docker only mounts absolute paths
+        let build_path = path::absolute(&self.build_directory)?;
+        std::fs::create_dir_all(&build_path)?;
+        let mut run_args = RunArguments {
+            session: self.session,
+            cli: false,
+            testing_path: build_path.join("testing"),
+            image_path: path::absolute(&self.image_path)?,
+            build_path,
+            debugcon_path: None,
+            serial_log_path: None,
+            restore_snapshot: None,
+            snapshot_volume: None,
+            vnc_port: if self.interactive { Some(self.allocate_port(NOVNC_PORT)?) } else { None },
+            qmp_port: self.allocate_port(qmp::QMP_PORT)?,
+            release_port: None,
+            image_type: self.image_type,
+            interactive: self.interactive,
+            qemu_hardware_args: get_hardware_arguments(&self.vm_config),
+            qemu_run_args: self.qemu_args.clone(),
+            qemu_test_args: vec![]
+        };
+
+        if self.test {
+            run_args.qemu_test_args.extend(TEST_ARGUMENTS.iter().map(|s| s.to_string()));
+            add_debugcon_output(&mut run_args, self.vm_config.backend)?;
+        }
+
+        Ok(run_args)
+    }
+
+    /// Choose the host port of a port of the VM. QEMU binds ports on the host itself when it
+    /// runs natively, so they must be free beforehand; Docker may choose them instead.
+    fn allocate_port(&self, preferred: u16) -> Result<PublishedPort> {
+        let preferred = self.prefer_ports.then_some(preferred);
+        match self.vm_config.backend {
+            VmBackend::Docker => Ok(container::choose_published_port(preferred, self.port_range)),
+            VmBackend::Native => Ok(PublishedPort::Fixed(container::find_free_port(preferred, self.port_range)?))
+        }
+    }
+}
+
+/// The outcome of a single QEMU execution.
+#[derive(Debug, Clone, Copy)]
+pub struct QemuRun {
+    pub duration: Duration,
+    pub exit_code: i32,
+    /// Whether QEMU was stopped because the configured timeout passed
+    pub timed_out: bool,
+    /// Whether QEMU was stopped because kboot was interrupted by Ctrl-C
+    pub interrupted: bool
+}
+
+impl QemuRun {
+    /// Whether the kernel signaled a failure through the `isa-debug-exit` device
+    pub fn is_failure(&self) -> bool {
+        self.exit_code == QemuExitCode::Failed as i32
+    }
+}
+
 /// Exit codes for QEMU. These codes are written to the I/O port `0xf4`
 /// to signal QEMU to exit with the given code.
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -161,3 +823,77 @@ pub enum QemuExitCode {
     Success = 0x10,
     Failed = 0x11
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn when_hardware_is_configured_then_arguments_depend_on_backend() {
+        let mut vm = VmConfig {
+            memory: Some("2G".to_string()),
+            cpu_features: vec!["+x2apic".to_string()],
+            accel: Some(Accel::Tcg),
+            devices: vec!["virtio-rng-pci".to_string()],
+            ..VmConfig::default()
+        };
+        vm.smp.cpus = Some(4);
+        vm.smp.threads = Some(2);
+
+        assert_eq!(get_hardware_arguments(&vm), ["-cpu", "max,+x2apic", "-device", "virtio-rng-pci"]);
+        assert_eq!(get_container_environment(&vm, DiskImageType::Uefi), ["BOOT_MODE=uefi", "RAM_SIZE=2G", "CPU_CORES=4", "KVM=N"]);
+
+        vm.backend = VmBackend::Native;
+        assert_eq!(get_hardware_arguments(&vm), ["-m", "2G", "-smp", "cpus=4,threads=2", "-cpu", "max,+x2apic", "-accel", "tcg", "-device", "virtio-rng-pci"]);
+    }
+
+    #[test]
+    fn when_container_cannot_apply_hardware_settings_then_they_are_warned_about() {
+        let mut vm = VmConfig { accel: Some(Accel::Kvm), ..VmConfig::default() };
+        vm.smp.sockets = Some(2);
+        vm.smp.cores = Some(4);
+
+        assert_eq!(get_container_environment(&vm, DiskImageType::Bios), ["BOOT_MODE=legacy", "CPU_CORES=8"]);
+        assert_eq!(get_hardware_warnings(&vm).len(), 2);
+
+        vm.backend = VmBackend::Native;
+        assert!(get_hardware_warnings(&vm).is_empty());
+    }
+
+    #[test]
+    fn when_runners_are_created_then_each_has_its_own_session() {
+        let build_directory = std::env::temp_dir().join(format!("kboot-runner-{}", Uuid::new_v4()));
+        let first = VmRunner::new("kernel.img").build_directory(&build_directory).test(true);
+        let second = VmRunner::new("kernel.img").build_directory(&build_directory).test(true);
+        assert_ne!(first.test_output_path(), second.test_output_path());
+
+        let first_args = first.get_run_arguments().unwrap();
+        let second_args = second.get_run_arguments().unwrap();
+        assert_ne!(container::get_qemu_container_name(&first_args.session), container::get_qemu_container_name(&second_args.session));
+        assert!(!first_args.cli && first.test_output_path().exists());
+        assert!(matches!(first_args.qmp_port, PublishedPort::Assigned(None)));
+
+        std::fs::remove_dir_all(build_directory).unwrap();
+    }
+
+    #[test]
+    fn when_only_session_and_ports_differ_then_machine_description_is_the_same() {
+        let build_directory = std::env::temp_dir().join(format!("kboot-machine-{}", Uuid::new_v4()));
+        let vm = VmConfig { memory: Some("2G".to_string()), ..VmConfig::default() };
+        let runner = |vm: &VmConfig| VmRunner::new("kernel.img").build_directory(&build_directory).vm_config(vm.clone()).test(true);
+
+        let mut first_args = runner(&vm).get_run_arguments().unwrap();
+        first_args.qmp_port = PublishedPort::Fixed(4444);
+        let mut second_args = runner(&vm).get_run_arguments().unwrap();
+        second_args.qmp_port = PublishedPort::Fixed(4450);
+        let first = get_machine_description(&first_args, &vm).unwrap();
+        assert_eq!(first, get_machine_description(&second_args, &vm).unwrap());
+        assert!(first.contains(&"RAM_SIZE=2G".to_string()));
+
+        let larger_vm = VmConfig { memory: Some("4G".to_string()), ..VmConfig::default() };
+        let larger_args = runner(&larger_vm).get_run_arguments().unwrap();
+        assert_ne!(first, get_machine_description(&larger_args, &larger_vm).unwrap());
+
+        std::fs::remove_dir_all(build_directory).unwrap();
+    }
+}
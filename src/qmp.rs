@@ -0,0 +1,179 @@
+use std::{fs, io::{BufRead, BufReader, Write}, net::{TcpStream, ToSocketAddrs}, path::{Path, PathBuf}, sync::RwLock, time::Duration};
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+/// Port on which QEMU listens for QMP connections inside the container,
+/// and the preferred port on the host
+pub const QMP_PORT: u16 = 4444;
+
+/// A global, thread-safe storage for the QMP address of the QEMU instance of this session.
+static ADDRESS: RwLock<Option<String>> = RwLock::new(None);
+
+/// Name of the file describing the running instance, inside the build directory
+const STATE_FILE_NAME: &str = "qmp.json";
+
+/// How long to wait for a response from QEMU before giving up
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Arguments for QEMU that enable the QMP server on the given address (`host:port`)
+pub fn get_qemu_arguments(listen_address: &str) -> Vec<String> {
+    vec!["-qmp".to_string(), format!("tcp:{},server=on,wait=off", listen_address)]
+}
+
+/// Set the address on which the QEMU instance of this session accepts QMP connections.
+pub fn set_current_address(address: Option<String>) {
+    if let Ok(mut current) = ADDRESS.write() {
+        *current = address;
+    }
+}
+
+/// Connect to the QEMU instance of this session.
+pub fn connect_current() -> Result<QmpClient> {
+    let address = ADDRESS.read().ok()
+        .and_then(|address| address.clone())
+        .ok_or_else(|| anyhow!("QEMU is not running"))?;
+    QmpClient::connect(&address)
+}
+
+/// A minimal client for the QEMU Machine Protocol (QMP).
+///
+/// Commands are sent one at a time; asynchronous events that arrive
+/// while waiting for a response are logged and skipped.
+pub struct QmpClient {
+    reader: BufReader<TcpStream>,
+    writer: TcpStream
+}
+
+impl QmpClient {
+    /// Connect to a QMP server and leave capabilities negotiation mode,
+    /// so that commands can be executed.
+    pub fn connect(address: &str) -> Result<Self> {
+        let socket_address = address.to_socket_addrs()?
+            .next()
+            .ok_or_else(|| anyhow!("Invalid QMP address: {}", address))?;
+        let stream = TcpStream::connect_timeout(&socket_address, RESPONSE_TIMEOUT)?;
+        stream.set_read_timeout(Some(RESPONSE_TIMEOUT))?;
+
+        let mut client = Self {
+            reader: BufReader::new(stream.try_clone()?),
+            writer: stream
+        };
+
+        let greeting = client.read_message()?;
+        if greeting.get("QMP").is_none() {
+            return Err(anyhow!("Unexpected QMP greeting: {}", greeting));
+        }
+        client.execute("qmp_capabilities", None)?;
+
+        Ok(client)
+    }
+
+    /// Execute a QMP command and return its `return` value.
+    pub fn execute(&mut self, command: &str, arguments: Option<Value>) -> Result<Value> {
+        let mut request = json!({ "execute": command });
+        if let Some(arguments) = arguments {
+            request["arguments"] = arguments;
+        }
+        writeln!(self.writer, "{}", request)?;
+        self.writer.flush()?;
+
+        loop {
+            let message = self.read_message()?;
+            if let Some(event) = message.get("event") {
+                log::info!("QMP event: {}", event);
+                continue;
+            }
+            if let Some(error) = message.get("error") {
+                return Err(anyhow!("QMP command {} failed: {}", command, error));
+            }
+
+            return message.get("return")
+                .cloned()
+                .ok_or_else(|| anyhow!("Unexpected QMP response: {}", message));
+        }
+    }
+
+    /// Get the run state of the VM (e.g. `running`, `paused`, `shutdown`).
+    pub fn query_status(&mut self) -> Result<String> {
+        let status = self.execute("query-status", None)?;
+        status.get("status")
+            .and_then(|s| s.as_str())
+            .map(|s| s.to_string())
+            .ok_or_else(|| anyhow!("Unexpected query-status response: {}", status))
+    }
+
+    pub fn system_reset(&mut self) -> Result<()> {
+        self.execute("system_reset", None)?;
+        Ok(())
+    }
+
+    /// Stop QEMU. The connection is closed by QEMU afterwards.
+    pub fn quit(&mut self) -> Result<()> {
+        self.execute("quit", None)?;
+        Ok(())
+    }
+
+    /// Write the current display to a file on QEMU's side, in PPM format.
+    pub fn screendump(&mut self, filename: &str) -> Result<()> {
+        self.execute("screendump", Some(json!({ "filename": filename })))?;
+        Ok(())
+    }
+
+    /// Press a combination of keys at once, given as QEMU key codes
+    /// joined by dashes (e.g. `ctrl-alt-delete` or `ret`).
+    pub fn send_keys(&mut self, combination: &str) -> Result<()> {
+        let keys = combination.split('-')
+            .map(|key| json!({ "type": "qcode", "data": key }))
+            .collect::<Vec<Value>>();
+        self.execute("send-key", Some(json!({ "keys": keys })))?;
+        Ok(())
+    }
+
+    fn read_message(&mut self) -> Result<Value> {
+        let mut line = String::new();
+        if self.reader.read_line(&mut line)? == 0 {
+            return Err(anyhow!("QMP connection closed by QEMU"));
+        }
+        Ok(serde_json::from_str(&line)?)
+    }
+}
+
+/// Describes the running QEMU instance, so that `kboot vm` can find it.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct QmpState {
+    /// Address of the QMP server on the host
+    pub address: String,
+    /// Name of the Docker container running QEMU
+    pub container: String,
+    /// Session of the kboot runner that started QEMU
+    pub session: String,
+    /// Testing directory as seen by QEMU, where `screendump` files can be written
+    pub testing_dir: String
+}
+
+/// Get the path of the state file of the running instance
+pub fn get_state_path(build_dir: &Path) -> PathBuf {
+    build_dir.join(STATE_FILE_NAME)
+}
+
+pub fn write_state(build_dir: &Path, state: &QmpState) -> Result<()> {
+    fs::write(get_state_path(build_dir), serde_json::to_string_pretty(state)?)?;
+    Ok(())
+}
+
+pub fn read_state(build_dir: &Path) -> Result<Option<QmpState>> {
+    let path = get_state_path(build_dir);
+    if !path.exists() {
+        return Ok(None);
+    }
+    Ok(Some(serde_json::from_str(&fs::read_to_string(path)?)?))
+}
+
+pub fn remove_state(build_dir: &Path) -> Result<()> {
+    let path = get_state_path(build_dir);
+    if path.exists() {
+        fs::remove_file(path)?;
+    }
+    Ok(())
+}
@@ -0,0 +1,262 @@
+use std::{fs, io::{self, Write}, path::PathBuf};
+use anyhow::Result;
+use serde_json::json;
+use crate::{args, config::{self, OutputFormat}, ktest::{TestGroup, TestOutcome, TestResult}};
+
+/// File in the testing directory that counts the groups reported in the current round,
+/// since the groups of a round may be reported by separate kboot processes
+const REPORTED_GROUPS_FILE_NAME: &str = "report-groups";
+
+/// Start the report of a new round, forgetting the groups of an earlier round that
+/// did not finish (e.g. because kboot was killed).
+pub fn start_report() -> Result<()> {
+    let path = get_reported_groups_path()?;
+    if path.exists() {
+        fs::remove_file(path)?;
+    }
+    Ok(())
+}
+
+/// Write the test group in the format selected by the `[output]` configuration,
+/// so tools that consume TAP or libtest JSON can use kboot results directly.
+///
+/// The first group of the round truncates the output file, later groups are appended.
+/// TAP documents end with their plan, which `finish_report` writes once the round is
+/// over, so it matches the groups that were actually reported.
+pub fn write_report(test_group: &TestGroup) -> Result<()> {
+    let output_config = &config::get_config()?.output;
+    let per_group = output_config.file.as_ref().is_some_and(|file| file.to_string_lossy().contains("{group}"));
+    let reported_groups = read_reported_groups()?;
+    let is_first_group = reported_groups == 0;
+
+    let report = match output_config.format {
+        OutputFormat::Kboot => return Ok(()),
+        // a file per group is a TAP document of its own
+        OutputFormat::Tap if per_group => format!("TAP version 13\n{}1..1\n", format_tap(test_group, 1)),
+        OutputFormat::Tap if is_first_group => format!("TAP version 13\n{}", format_tap(test_group, 1)),
+        OutputFormat::Tap => format_tap(test_group, reported_groups + 1),
+        OutputFormat::LibtestJson => format_libtest_json(test_group)
+    };
+
+    let file_name = output_config.file.as_ref()
+        .map(|file| file.to_string_lossy().replace("{group}", &test_group.test_group));
+    write_output(file_name.as_deref(), &report, !per_group && !is_first_group)?;
+    fs::write(get_reported_groups_path()?, (reported_groups + 1).to_string())?;
+
+    log::info!("Wrote {:?} report for {}", output_config.format, test_group.test_group);
+    Ok(())
+}
+
+/// Finish the report of the round, once all of its groups were written. A TAP document
+/// gets its trailing plan for the number of groups reported; a round without any
+/// reported group is an empty document.
+pub fn finish_report() -> Result<()> {
+    let output_config = &config::get_config()?.output;
+    let per_group = output_config.file.as_ref().is_some_and(|file| file.to_string_lossy().contains("{group}"));
+    let reported_groups = read_reported_groups()?;
+    start_report()?;
+
+    if output_config.format != OutputFormat::Tap || per_group {
+        return Ok(());
+    }
+
+    let file_name = output_config.file.as_ref().map(|file| file.to_string_lossy().to_string());
+    if reported_groups == 0 {
+        write_output(file_name.as_deref(), "TAP version 13\n1..0\n", false)
+    } else {
+        write_output(file_name.as_deref(), &format!("1..{}\n", reported_groups), true)
+    }
+}
+
+/// Write a report to the file relative to the workspace root, or to stdout if there is none
+fn write_output(file_name: Option<&str>, report: &str, append: bool) -> Result<()> {
+    let Some(file_name) = file_name else {
+        print!("{}", report);
+        io::stdout().flush()?;
+        return Ok(());
+    };
+
+    let path = args::get_workspace_root()?.join(file_name);
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    let mut output = fs::OpenOptions::new()
+        .create(true)
+        .write(true)
+        .append(append)
+        .truncate(!append)
+        .open(&path)?;
+    output.write_all(report.as_bytes())?;
+    Ok(())
+}
+
+/// Number of groups reported so far in the current round
+fn read_reported_groups() -> Result<usize> {
+    let path = get_reported_groups_path()?;
+    if !path.exists() {
+        return Ok(0);
+    }
+    Ok(fs::read_to_string(path)?.trim().parse()?)
+}
+
+fn get_reported_groups_path() -> Result<PathBuf> {
+    let testing_dir = args::get_build_directory()?.join("testing");
+    fs::create_dir_all(&testing_dir)?;
+    Ok(testing_dir.join(REPORTED_GROUPS_FILE_NAME))
+}
+
+/// Format the test group as test point `number` of a TAP 13 document, with its tests
+/// as an indented subtest. The version line and the plan are left to the document.
+///
+/// Failures carry a YAML block with their message and location, skipped tests use
+/// the SKIP directive.
+fn format_tap(test_group: &TestGroup, number: usize) -> String {
+    let tests = all_tests(test_group);
+    let mut tap = format!("# Subtest: {}\n    1..{}\n", test_group.test_group, tests.len());
+
+    for (index, (name, test)) in tests.iter().enumerate() {
+        let index = index + 1;
+        match &test.outcome {
+            TestOutcome::Pass | TestOutcome::ShouldPanic => tap.push_str(&format!("    ok {} - {}\n", index, name)),
+            TestOutcome::Ignored { reason } => {
+                tap.push_str(&format!("    ok {} - {} # SKIP {}\n", index, name, reason.as_deref().unwrap_or("ignored")));
+            },
+            TestOutcome::NotRun => tap.push_str(&format!("    ok {} - {} # SKIP not run, the kernel crashed\n", index, name)),
+            TestOutcome::Fail | TestOutcome::Timeout | TestOutcome::Crashed => {
+                tap.push_str(&format!("    not ok {} - {}\n", index, name));
+                tap.push_str("      ---\n");
+                tap.push_str(&format!("      result: {}\n", test.outcome.label()));
+                // JSON strings are valid YAML scalars, which takes care of escaping
+                if let Some(message) = &test.message {
+                    tap.push_str(&format!("      message: {}\n", json!(message)));
+                }
+                if let Some(location) = &test.location {
+                    tap.push_str(&format!("      location: {}\n", json!(location)));
+                }
+                tap.push_str("      ...\n");
+            }
+        }
+    }
+
+    let failed = tests.iter().any(|(_, test)| matches!(test.outcome, TestOutcome::Fail | TestOutcome::Timeout | TestOutcome::Crashed));
+    let status = if failed { "not ok" } else { "ok" };
+    tap.push_str(&format!("{} {} - {}\n", status, number, test_group.test_group));
+    tap
+}
+
+/// Format the test group as the JSON events of libtest's `--format json`,
+/// one event per line, as one test suite.
+fn format_libtest_json(test_group: &TestGroup) -> String {
+    let tests = all_tests(test_group);
+    let mut events = vec![json!({ "type": "suite", "event": "started", "test_count": tests.len() })];
+
+    for (name, test) in &tests {
+        events.push(json!({ "type": "test", "event": "started", "name": name }));
+
+        let mut event = json!({ "type": "test", "name": name });
+        match &test.outcome {
+            TestOutcome::Pass | TestOutcome::ShouldPanic => event["event"] = json!("ok"),
+            TestOutcome::Ignored { reason } => {
+                event["event"] = json!("ignored");
+                if let Some(reason) = reason {
+                    event["message"] = json!(reason);
+                }
+            },
+            TestOutcome::NotRun => {
+                event["event"] = json!("ignored");
+                event["message"] = json!("not run, the kernel crashed");
+            },
+            TestOutcome::Fail | TestOutcome::Timeout | TestOutcome::Crashed => {
+                event["event"] = json!("failed");
+                let mut stdout = format!("test {} ... {}\n", name, test.outcome.label());
+                if let Some(message) = &test.message {
+                    stdout.push_str(&format!("{}\n", message));
+                }
+                if let Some(location) = &test.location {
+                    stdout.push_str(&format!("at {}\n", location));
+                }
+                event["stdout"] = json!(stdout);
+            }
+        }
+        if let Some(duration_us) = test.duration_us.or(test.estimated_duration_us) {
+            event["exec_time"] = json!(duration_us as f64 / 1_000_000.0);
+        }
+        events.push(event);
+    }
+
+    let summary = &test_group.summary;
+    let failed = summary.failed + summary.crashed;
+    events.push(json!({
+        "type": "suite",
+        "event": if failed == 0 { "ok" } else { "failed" },
+        "passed": summary.passed,
+        "failed": failed,
+        "ignored": summary.ignored + summary.not_run,
+        "measured": 0,
+        "filtered_out": 0,
+        "exec_time": summary.duration as f64 / 1000.0
+    }));
+
+    events.iter().map(|event| format!("{}\n", event)).collect()
+}
+
+/// Get all tests of the group with their fully qualified names, in reported order
+fn all_tests(test_group: &TestGroup) -> Vec<(String, &TestResult)> {
+    test_group.modules.iter()
+        .flat_map(|m| m.tests.iter().map(move |t| (format!("{}::{}", m.module, t.test), t)))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ktest::{TestModule, TestSummary};
+
+    #[test]
+    fn when_group_has_failure_and_skip_then_tap_is_formatted() {
+        let mut failed = TestResult::new("free".to_string(), TestOutcome::Fail, 0, Some("src/mem.rs:10".to_string()), Some("double \"free\"".to_string()));
+        failed.duration_us = Some(1500);
+        let test_group = TestGroup {
+            test_group: "kernel".to_string(),
+            summary: TestSummary { total: 3, passed: 1, failed: 1, ignored: 1, crashed: 0, not_run: 0, flaky: 0, regressions: 0, duration: 10 },
+            modules: vec![TestModule {
+                module: "mem".to_string(),
+                tests: vec![
+                    TestResult::new("alloc".to_string(), TestOutcome::Pass, 5, None, None),
+                    failed,
+                    TestResult::new("slow".to_string(), TestOutcome::Ignored { reason: Some("too slow".to_string()) }, 0, None, None)
+                ]
+            }],
+            crash: None,
+            protocol_version: 1,
+            diagnostics: Vec::new(),
+            configuration: None
+        };
+
+        let tap = [
+            "# Subtest: kernel",
+            "    1..3",
+            "    ok 1 - mem::alloc",
+            "    not ok 2 - mem::free",
+            "      ---",
+            "      result: FAILED",
+            r#"      message: "double \"free\"""#,
+            r#"      location: "src/mem.rs:10""#,
+            "      ...",
+            "    ok 3 - mem::slow # SKIP too slow",
+            "not ok 1 - kernel",
+            ""
+        ].join("\n");
+        assert_eq!(format_tap(&test_group, 1), tap);
+        assert!(format_tap(&test_group, 2).ends_with("\nnot ok 2 - kernel\n"));
+
+        let events = format_libtest_json(&test_group).lines()
+            .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(events.len(), 8);
+        assert_eq!(events[4]["event"], "failed");
+        assert_eq!(events[4]["exec_time"], 0.0015);
+        assert_eq!(events[7]["event"], "failed");
+    }
+}
@@ -0,0 +1,74 @@
+use anyhow::Result;
+use crate::{config, console, ktest::{self, TestGroup}, qemu::{self, QemuExitCode, QemuRun}};
+
+/// Re-boots the test group up to the configured number of attempts while it
+/// has failed tests. A failed test that passes on a later attempt is marked as
+/// flaky and counted as passed; its original failure location and message are kept.
+///
+/// `ktest` cannot yet be told to run only specific tests, so the whole group
+/// is re-run and only the results of the previously failed tests are used.
+///
+/// Returns the exit code that represents the group after all attempts.
+pub fn retry_failed_tests(test_group: &mut TestGroup, qemu_run: &QemuRun) -> Result<i32> {
+    let attempts = config::get_config()?.retry.attempts;
+    if attempts == 0 || test_group.summary.failed == 0 || qemu_run.interrupted {
+        return Ok(qemu_run.exit_code);
+    }
+
+    for attempt in 1..=attempts {
+        let failed_count = test_group.summary.failed;
+        if failed_count == 0 {
+            break;
+        }
+
+        console::print_human(&format!("kboot: retrying {} failed test(s) in {} (attempt {}/{})",
+            failed_count, test_group.test_group, attempt, attempts));
+        log::info!("Retrying {} failed test(s) in {} (attempt {}/{})", failed_count, test_group.test_group, attempt, attempts);
+
+        let retry_run = qemu::run()?;
+        let Some(retry_group) = ktest::collect_test_group(&retry_run)? else {
+            log::warn!("No ktest output found for retry attempt {}", attempt);
+            continue;
+        };
+
+        for module in test_group.modules.iter_mut() {
+            for test in module.tests.iter_mut().filter(|t| t.outcome.is_failure()) {
+                test.attempts = Some(attempt + 1);
+
+                let retried = retry_group.modules.iter()
+                    .filter(|m| m.module == module.module)
+                    .flat_map(|m| m.tests.iter())
+                    .find(|t| t.test == test.test);
+
+                if let Some(retried) = retried && retried.outcome.is_success() {
+                    log::warn!("Test {}::{} is flaky, passed on attempt {}", module.module, test.test, attempt + 1);
+                    test.outcome = retried.outcome.clone();
+                    test.cycle_count = retried.cycle_count;
+                    test.flaky = true;
+                }
+            }
+        }
+
+        test_group.update_summary();
+    }
+
+    if test_group.summary.failed == 0 && qemu_run.is_failure() {
+        return Ok(QemuExitCode::Success as i32);
+    }
+
+    Ok(qemu_run.exit_code)
+}
+
+/// Print all tests of the group that only passed on retry to the terminal.
+pub fn print_flaky_tests(test_group: &TestGroup) {
+    if test_group.summary.flaky == 0 {
+        return;
+    }
+
+    console::print_human(&format!("kboot: {} flaky test(s) in {}:", test_group.summary.flaky, test_group.test_group));
+    for module in &test_group.modules {
+        for test in module.tests.iter().filter(|t| t.flaky) {
+            console::print_human(&format!("    {}::{} (passed on attempt {})", module.module, test.test, test.attempts.unwrap_or(1)));
+        }
+    }
+}
@@ -0,0 +1,113 @@
+use std::{fs, io::BufWriter, path::Path};
+use anyhow::{anyhow, Result};
+use crate::{args, qemu, qmp};
+
+/// Capture the display of the running VM through QMP and store it as a PNG file
+/// in the testing directory, which is archived with the round. The VM is paused
+/// while the display is written, so the kernel cannot draw over it.
+///
+/// Returns the file name of the screenshot, which is `<name>.png`, or `<name>-<n>.png`
+/// if a screenshot with that name exists already (e.g. from a retry).
+pub fn capture(name: &str) -> Result<String> {
+    let testing_dir = args::get_build_directory()?.join("testing");
+    let mut file_stem = name.to_string();
+    let mut attempt = 1;
+    while testing_dir.join(format!("{}.png", file_stem)).exists() {
+        attempt += 1;
+        file_stem = format!("{}-{}", name, attempt);
+    }
+
+    // QEMU writes the screenshot to the testing directory as it sees it
+    let mut client = qmp::connect_current()?;
+    let running = client.query_status()? == "running";
+    if running {
+        client.execute("stop", None)?;
+    }
+    let screendump = client.screendump(&format!("{}/{}.ppm", qemu::get_qemu_testing_dir()?, file_stem));
+    if running {
+        client.execute("cont", None)?;
+    }
+    screendump?;
+
+    let ppm_path = testing_dir.join(format!("{}.ppm", file_stem));
+    let png_name = format!("{}.png", file_stem);
+    convert_ppm_to_png(&ppm_path, &testing_dir.join(&png_name))?;
+    fs::remove_file(&ppm_path)?;
+
+    log::info!("Captured screenshot {}", png_name);
+    Ok(png_name)
+}
+
+/// Convert a binary PPM image (P6), as written by QEMU's `screendump`, to PNG.
+fn convert_ppm_to_png(ppm_path: &Path, png_path: &Path) -> Result<()> {
+    let ppm = fs::read(ppm_path)?;
+    let (width, height, pixels) = parse_ppm(&ppm)?;
+
+    let mut encoder = png::Encoder::new(BufWriter::new(fs::File::create(png_path)?), width, height);
+    encoder.set_color(png::ColorType::Rgb);
+    encoder.set_depth(png::BitDepth::Eight);
+    encoder.write_header()?.write_image_data(pixels)?;
+
+    Ok(())
+}
+
+/// Parse a P6 PPM image with 8 bits per channel into its size and RGB pixels.
+fn parse_ppm(ppm: &[u8]) -> Result<(u32, u32, &[u8])> {
+    let mut position = 0;
+    let mut fields = Vec::new();
+
+    // header: magic, width, height and maximum value, separated by whitespace and comments
+    while fields.len() < 4 {
+        while position < ppm.len() && ppm[position].is_ascii_whitespace() {
+            position += 1;
+        }
+        if ppm.get(position) == Some(&b'#') {
+            while position < ppm.len() && ppm[position] != b'\n' {
+                position += 1;
+            }
+            continue;
+        }
+
+        let start = position;
+        while position < ppm.len() && !ppm[position].is_ascii_whitespace() {
+            position += 1;
+        }
+        if start == position {
+            return Err(anyhow!("Screenshot has an incomplete PPM header"));
+        }
+        fields.push(std::str::from_utf8(&ppm[start..position])?);
+    }
+
+    if fields[0] != "P6" || fields[3] != "255" {
+        return Err(anyhow!("Unsupported screenshot format: {} with maximum value {}", fields[0], fields[3]));
+    }
+    let width: u32 = fields[1].parse()?;
+    let height: u32 = fields[2].parse()?;
+
+    // a single whitespace character separates the header from the pixels
+    let pixels = ppm.get(position + 1..)
+        .ok_or_else(|| anyhow!("Screenshot has no pixel data"))?;
+    let expected = width as usize * height as usize * 3;
+    if pixels.len() < expected {
+        return Err(anyhow!("Screenshot has {} bytes of pixel data, expected {}", pixels.len(), expected));
+    }
+
+    Ok((width, height, &pixels[..expected]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn when_ppm_has_comment_then_pixels_are_parsed() {
+        let mut ppm = b"P6\n# written by QEMU\n2 1\n255\n".to_vec();
+        ppm.extend_from_slice(&[255, 0, 0, 0, 0, 255]);
+
+        let (width, height, pixels) = parse_ppm(&ppm).unwrap();
+        assert_eq!((width, height), (2, 1));
+        assert_eq!(pixels, &[255, 0, 0, 0, 0, 255]);
+
+        assert!(parse_ppm(b"P6\n2 1\n255\n\x00\x00").is_err());
+    }
+}
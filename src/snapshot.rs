@@ -0,0 +1,259 @@
+use std::{fs, io::Write, net::TcpStream, path::{Path, PathBuf}, sync::{Mutex, RwLock}, thread, time::{Duration, Instant}};
+use anyhow::{anyhow, Result};
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use crate::{args, builder::{self, Manifest}, console, qemu, qmp::{self, QmpClient}, BUILD_DIRECTORY};
+
+/// Name of the directory snapshots are stored in, inside the build directory
+const SNAPSHOT_DIRECTORY: &str = "snapshots";
+
+/// How long to wait for QEMU to accept QMP connections when restoring
+const STARTUP_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Interval at which the state of a migration is checked
+const MIGRATION_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Port on which QEMU accepts the connection that releases the kernel from its
+/// snapshot point inside the container, and the preferred port on the host
+pub const RELEASE_PORT: u16 = 4445;
+
+/// I/O port of the serial device (COM2) on which the kernel waits to be released
+const RELEASE_IOBASE: &str = "0x2f8";
+
+/// Byte sent to the kernel to release it from its snapshot point
+const RELEASE_BYTE: u8 = b'\n';
+
+/// Snapshot to create at the snapshot point of the running kernel, if one is missing
+static PENDING_SNAPSHOT: Mutex<Option<PathBuf>> = Mutex::new(None);
+
+/// Address on the host that releases the kernel of this session from its snapshot point
+static RELEASE_ADDRESS: RwLock<Option<String>> = RwLock::new(None);
+
+/// Get the directory in which snapshots are stored
+pub fn get_snapshot_dir() -> Result<PathBuf> {
+    Ok(args::get_workspace_root()?.join(BUILD_DIRECTORY).join(SNAPSHOT_DIRECTORY))
+}
+
+/// Get the snapshot file for the current image and the machine that boots it. A snapshot
+/// can only be restored on an identical machine, so the key covers the kernel with its
+/// boot inputs and the description of the machine (see `qemu::get_machine_description`).
+/// The image itself is not part of the key, since every build of it differs.
+pub fn get_snapshot_path(machine: &[String]) -> Result<PathBuf> {
+    let manifest = builder::read_manifest()?;
+    Ok(get_snapshot_dir()?.join(get_snapshot_file_name(&manifest, machine)))
+}
+
+fn get_snapshot_file_name(manifest: &Manifest, machine: &[String]) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(manifest.inputs_sha256.as_bytes());
+    for arg in machine {
+        hasher.update([0]);
+        hasher.update(arg.as_bytes());
+    }
+    let key = format!("{:x}", hasher.finalize());
+    format!("{}.state", &key[..16])
+}
+
+/// Arguments for QEMU that add the serial device (COM2) through which kboot releases
+/// the kernel from its snapshot point, accepting a connection on the given address.
+pub fn get_qemu_arguments(listen_address: &str) -> Vec<String> {
+    let (host, port) = listen_address.rsplit_once(':').unwrap_or((listen_address, ""));
+    vec![
+        "-chardev".to_string(), format!("socket,id=kboot-release,host={},port={},server=on,wait=off", host, port),
+        "-device".to_string(), format!("isa-serial,chardev=kboot-release,iobase={},irq=3", RELEASE_IOBASE)
+    ]
+}
+
+/// Set the address on the host through which the kernel of this session is released.
+pub fn set_release_address(address: Option<String>) {
+    if let Ok(mut current) = RELEASE_ADDRESS.write() {
+        *current = address;
+    }
+}
+
+/// Create the snapshot at the kernel's snapshot point, once QEMU reaches it.
+pub fn set_pending(snapshot_path: Option<PathBuf>) {
+    if let Ok(mut pending) = PENDING_SNAPSHOT.lock() {
+        *pending = snapshot_path;
+    }
+}
+
+/// Called when the kernel reached its snapshot point and waits to be released. If a
+/// snapshot is pending, the VM is paused, its state is migrated to the snapshot file
+/// along with the debugcon lines written before the snapshot point (the prelude),
+/// and the VM is resumed. The kernel is released in any case.
+pub fn on_snapshot_point(name: &str, prelude: &[String]) {
+    if let Some(snapshot_path) = PENDING_SNAPSHOT.lock().ok().and_then(|mut pending| pending.take()) {
+        match create(&snapshot_path, prelude) {
+            Ok(()) => {
+                console::print_live(&format!("kboot: saved snapshot at '{}'", name));
+                log::info!("Saved snapshot at '{}' to {}", name, snapshot_path.display());
+            },
+            Err(e) => {
+                console::print_live(&format!("kboot: failed to save snapshot at '{}': {}", name, e));
+                log::warn!("Failed to save snapshot at '{}': {}", name, e);
+            }
+        }
+    }
+
+    if let Err(e) = release() {
+        log::warn!("Failed to release the kernel from snapshot point '{}': {}", name, e);
+    }
+}
+
+fn create(snapshot_path: &Path, prelude: &[String]) -> Result<()> {
+    let file_name = file_name(snapshot_path)?;
+    let partial_path = snapshot_path.with_extension("partial");
+
+    let mut client = connect()?;
+    client.execute("stop", None)?;
+    // the snapshot is written by QEMU, to the snapshot directory as it sees it
+    let uri = format!("exec:cat > {}/{}.partial", qemu::get_qemu_snapshot_dir()?, file_name);
+    let result = client.execute("migrate", Some(json!({ "uri": uri })))
+        .and_then(|_| wait_for_migration(&mut client));
+    client.execute("cont", None)?;
+    result?;
+
+    // only complete snapshots are renamed, so a failed run is never restored
+    write_prelude(snapshot_path, prelude)?;
+    fs::rename(partial_path, snapshot_path)?;
+    Ok(())
+}
+
+/// Release the kernel from its snapshot point by sending a byte to its COM2 device.
+pub fn release() -> Result<()> {
+    let address = RELEASE_ADDRESS.read().ok()
+        .and_then(|address| address.clone())
+        .ok_or_else(|| anyhow!("QEMU is not running"))?;
+    let mut stream = TcpStream::connect(&address)?;
+    stream.write_all(&[RELEASE_BYTE])?;
+    stream.flush()?;
+    Ok(())
+}
+
+/// Restore the snapshot into QEMU, which was started with `-incoming defer`.
+/// QEMU resumes the kernel automatically once the state was loaded, and the kernel
+/// waits at its snapshot point until it is released.
+///
+/// Returns the debugcon lines that the kernel wrote before the snapshot point, which
+/// are not written again by the restored kernel.
+pub fn restore(snapshot_path: &Path) -> Result<Vec<String>> {
+    let file_name = file_name(snapshot_path)?;
+    let started = Instant::now();
+    let mut client = loop {
+        match connect() {
+            Ok(client) => break client,
+            Err(e) if started.elapsed() >= STARTUP_TIMEOUT => return Err(anyhow!("QMP did not become available: {}", e)),
+            Err(_) => thread::sleep(Duration::from_millis(500))
+        }
+    };
+
+    let uri = format!("exec:cat {}/{}.state", qemu::get_qemu_snapshot_dir()?, file_name);
+    client.execute("migrate-incoming", Some(json!({ "uri": uri })))?;
+    wait_for_migration(&mut client)?;
+
+    log::info!("Restored snapshot {}", snapshot_path.display());
+    read_prelude(snapshot_path)
+}
+
+/// Remove a snapshot that could not be restored, e.g. after QEMU was updated.
+pub fn discard(snapshot_path: &Path) -> Result<()> {
+    for path in [snapshot_path.to_path_buf(), get_prelude_path(snapshot_path)] {
+        if path.exists() {
+            fs::remove_file(path)?;
+        }
+    }
+    Ok(())
+}
+
+/// Get the file with the debugcon lines written before the snapshot point
+fn get_prelude_path(snapshot_path: &Path) -> PathBuf {
+    snapshot_path.with_extension("debugcon")
+}
+
+fn write_prelude(snapshot_path: &Path, prelude: &[String]) -> Result<()> {
+    let content = prelude.iter().map(|line| format!("{}\n", line)).collect::<String>();
+    fs::write(get_prelude_path(snapshot_path), content)?;
+    Ok(())
+}
+
+fn read_prelude(snapshot_path: &Path) -> Result<Vec<String>> {
+    let path = get_prelude_path(snapshot_path);
+    let content = fs::read_to_string(&path)
+        .map_err(|e| anyhow!("Failed to read the debugcon output of snapshot {}: {}", path.display(), e))?;
+    Ok(content.lines().map(str::to_string).collect())
+}
+
+fn connect() -> Result<QmpClient> {
+    qmp::connect_current()
+}
+
+fn file_name(snapshot_path: &Path) -> Result<String> {
+    snapshot_path.file_stem()
+        .and_then(|s| s.to_str())
+        .map(|s| s.to_string())
+        .ok_or_else(|| anyhow!("Invalid snapshot path: {}", snapshot_path.display()))
+}
+
+fn wait_for_migration(client: &mut QmpClient) -> Result<()> {
+    loop {
+        let migration = client.execute("query-migrate", None)?;
+        match migration.get("status").and_then(Value::as_str) {
+            Some("completed") => return Ok(()),
+            Some("failed") | Some("cancelled") => {
+                let error = migration.get("error-desc").and_then(Value::as_str).unwrap_or("unknown error");
+                return Err(anyhow!("Migration failed: {}", error));
+            },
+            _ => thread::sleep(MIGRATION_POLL_INTERVAL)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn when_snapshot_is_saved_then_prelude_is_stored_next_to_it() {
+        let snapshot_dir = tempfile::tempdir().unwrap();
+        let snapshot_path = snapshot_dir.path().join("0123456789abcdef.state");
+        let prelude = [r#"{"ktest_protocol":1}"#.to_string(), "booting...".to_string()];
+
+        write_prelude(&snapshot_path, &prelude).unwrap();
+        fs::write(&snapshot_path, b"state").unwrap();
+        assert_eq!(read_prelude(&snapshot_path).unwrap(), prelude);
+
+        discard(&snapshot_path).unwrap();
+        assert!(!snapshot_path.exists());
+        assert!(read_prelude(&snapshot_path).is_err());
+    }
+
+    #[test]
+    fn when_release_device_is_added_then_it_listens_on_the_address() {
+        assert_eq!(get_qemu_arguments("127.0.0.1:4445"), [
+            "-chardev", "socket,id=kboot-release,host=127.0.0.1,port=4445,server=on,wait=off",
+            "-device", "isa-serial,chardev=kboot-release,iobase=0x2f8,irq=3"
+        ]);
+    }
+
+    #[test]
+    fn when_same_kernel_is_built_twice_then_snapshot_path_is_the_same() {
+        let build_dir = tempfile::tempdir().unwrap();
+        let kernel = build_dir.path().join("kernel");
+        fs::write(&kernel, b"kernel").unwrap();
+        let machine = ["-m".to_string(), "512M".to_string()];
+
+        let build = || builder::ImageBuilder::new(&kernel)
+            .build_directory(build_dir.path())
+            .cmdline("log=debug")
+            .build()
+            .unwrap();
+        let first = build();
+        let second = build();
+        // the images differ, since their partition GUIDs are random
+        assert_ne!(first.image_sha256, second.image_sha256);
+
+        assert_eq!(get_snapshot_file_name(&first, &machine), get_snapshot_file_name(&second, &machine));
+        assert_ne!(get_snapshot_file_name(&first, &machine), get_snapshot_file_name(&first, &machine[..1]));
+    }
+}
@@ -0,0 +1,86 @@
+/// Arithmetic mean of the samples, or `None` if there are no samples.
+pub fn mean(samples: &[f64]) -> Option<f64> {
+    if samples.is_empty() {
+        return None;
+    }
+
+    Some(samples.iter().sum::<f64>() / samples.len() as f64)
+}
+
+/// Sample standard deviation (n - 1), or `None` if there are fewer than two samples.
+pub fn std_dev(samples: &[f64]) -> Option<f64> {
+    if samples.len() < 2 {
+        return None;
+    }
+
+    let mean = mean(samples)?;
+    let variance = samples.iter()
+        .map(|s| (s - mean).powi(2))
+        .sum::<f64>() / (samples.len() - 1) as f64;
+
+    Some(variance.sqrt())
+}
+
+/// Median of the samples, or `None` if there are no samples.
+pub fn median(samples: &[f64]) -> Option<f64> {
+    percentile(samples, 50.0)
+}
+
+/// Percentile (0-100) of the samples using linear interpolation between
+/// the closest ranks, or `None` if there are no samples.
+pub fn percentile(samples: &[f64], percent: f64) -> Option<f64> {
+    if samples.is_empty() {
+        return None;
+    }
+
+    let mut sorted = samples.to_vec();
+    sorted.sort_by(|a, b| a.total_cmp(b));
+
+    let rank = (percent / 100.0).clamp(0.0, 1.0) * (sorted.len() - 1) as f64;
+    let lower = sorted[rank.floor() as usize];
+    let upper = sorted[rank.ceil() as usize];
+
+    Some(lower + (upper - lower) * rank.fract())
+}
+
+/// Number of mild and severe outliers, classified with Tukey's fences
+/// (1.5 and 3 times the interquartile range outside of the quartiles).
+pub fn outliers(samples: &[f64]) -> Outliers {
+    let (Some(q1), Some(q3)) = (percentile(samples, 25.0), percentile(samples, 75.0)) else {
+        return Outliers::default();
+    };
+
+    let iqr = q3 - q1;
+    let mut outliers = Outliers::default();
+    for sample in samples {
+        let distance = if *sample < q1 { q1 - sample } else { sample - q3 };
+        if distance > 3.0 * iqr {
+            outliers.severe += 1;
+        } else if distance > 1.5 * iqr {
+            outliers.mild += 1;
+        }
+    }
+
+    outliers
+}
+
+/// Counts of samples outside of the inner (mild) and outer (severe) fences
+#[derive(serde::Serialize, serde::Deserialize, Debug, Default, Clone, Copy, PartialEq)]
+pub struct Outliers {
+    pub mild: usize,
+    pub severe: usize
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn when_samples_contain_outliers_then_classified_by_fences() {
+        let samples = [10.0, 11.0, 11.0, 12.0, 12.0, 13.0, 13.0, 14.0, 19.0, 40.0];
+
+        assert_eq!(median(&samples), Some(12.5));
+        assert_eq!(percentile(&samples, 25.0), Some(11.25));
+        assert_eq!(outliers(&samples), Outliers { mild: 1, severe: 1 });
+    }
+}
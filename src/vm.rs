@@ -0,0 +1,164 @@
+use std::{fs, path::Path};
+use anyhow::{anyhow, Result};
+use serde_json::Value;
+use crate::{args, orchestrator, qmp::{self, QmpClient}, KbootError};
+
+/// Usage of the `kboot vm` subcommand
+const USAGE: &str = "usage: kboot vm [--job <name>] <status|reset|quit|screendump [file]|sendkey <keys>...|<qmp command> [json arguments]>";
+
+/// Entry point for `kboot vm <command>`, which sends a command to the
+/// QEMU instance that is currently running through its QMP socket.
+///
+/// With `--job <name>`, the command is sent to the VM of a test executable
+/// run by `kboot test`, whose build directory is `.build/test-jobs/<name>`.
+pub fn run_command() -> Result<(), KbootError> {
+    let build_dir = args::get_build_directory()
+        .map_err(|e| KbootError::VmCommandFailed(e, "Failed to get the build directory".to_string()))?;
+    let (job, vm_args) = split_job(args::get_vm_arguments())
+        .map_err(|e| KbootError::VmCommandFailed(e, "Invalid arguments for kboot vm".to_string()))?;
+    let vm_build_dir = match job {
+        Some(job) => build_dir.join(orchestrator::JOBS_DIRECTORY).join(job),
+        None => build_dir.clone()
+    };
+
+    execute(&vm_build_dir, vm_args)
+        .map_err(|e| {
+            let jobs = get_running_jobs(&build_dir);
+            if job.is_none() && !jobs.is_empty() {
+                KbootError::VmCommandFailed(e, format!("No VM found; VMs of test executables are running, select one with --job <name>: {}", jobs.join(", ")))
+            } else {
+                KbootError::VmCommandFailed(e, "Failed to send the command to the VM".to_string())
+            }
+        })
+}
+
+/// Split the `--job <name>` option from the arguments of `kboot vm`.
+fn split_job(vm_args: &[String]) -> Result<(Option<&str>, &[String])> {
+    match vm_args.first().map(String::as_str) {
+        Some("--job") => {
+            let job = vm_args.get(1).ok_or_else(|| anyhow!("{}", USAGE))?;
+            Ok((Some(job.as_str()), &vm_args[2..]))
+        },
+        _ => Ok((None, vm_args))
+    }
+}
+
+/// Get the names of the test executables run by `kboot test` whose VM is running
+fn get_running_jobs(build_dir: &Path) -> Vec<String> {
+    let Ok(entries) = fs::read_dir(build_dir.join(orchestrator::JOBS_DIRECTORY)) else {
+        return Vec::new();
+    };
+
+    let mut jobs = entries
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| qmp::get_state_path(path).exists())
+        .filter_map(|path| path.file_name().map(|name| name.to_string_lossy().to_string()))
+        .collect::<Vec<String>>();
+    jobs.sort();
+    jobs
+}
+
+/// A command for the running VM, from the arguments of `kboot vm`
+#[derive(Debug, PartialEq)]
+enum VmCommand {
+    Status,
+    Reset,
+    Quit,
+    /// Write a screenshot to the file of this name in the testing directory
+    Screendump(String),
+    /// Press each key combination, e.g. `ctrl-alt-delete`
+    SendKeys(Vec<String>),
+    /// Any other QMP command, with its arguments as JSON
+    Qmp(String, Option<Value>)
+}
+
+fn parse_command(vm_args: &[String]) -> Result<VmCommand> {
+    let Some(command) = vm_args.first() else {
+        return Err(anyhow!("{}", USAGE));
+    };
+
+    Ok(match command.as_str() {
+        "status" => VmCommand::Status,
+        "reset" => VmCommand::Reset,
+        "quit" => VmCommand::Quit,
+        "screendump" => VmCommand::Screendump(vm_args.get(1).cloned().unwrap_or_else(|| "screendump.ppm".to_string())),
+        "sendkey" if vm_args.len() < 2 => return Err(anyhow!("{}", USAGE)),
+        "sendkey" => VmCommand::SendKeys(vm_args[1..].to_vec()),
+        other => {
+            let arguments = vm_args.get(1)
+                .map(|arguments| serde_json::from_str(arguments))
+                .transpose()?;
+            VmCommand::Qmp(other.to_string(), arguments)
+        }
+    })
+}
+
+fn execute(build_dir: &Path, vm_args: &[String]) -> Result<()> {
+    let command = parse_command(vm_args)?;
+
+    let state = qmp::read_state(build_dir)?
+        .ok_or_else(|| anyhow!("No running QEMU instance found (missing {})", qmp::get_state_path(build_dir).display()))?;
+    let mut client = QmpClient::connect(&state.address)?;
+    match command {
+        VmCommand::Status => println!("{}", client.query_status()?),
+        VmCommand::Reset => client.system_reset()?,
+        VmCommand::Quit => client.quit()?,
+        VmCommand::Screendump(file_name) => {
+            // the file is written by QEMU, into the testing directory as QEMU sees it
+            client.screendump(&format!("{}/{}", state.testing_dir, file_name))?;
+            println!("Screenshot written to {}", build_dir.join("testing").join(file_name).display());
+        },
+        VmCommand::SendKeys(combinations) => {
+            for combination in &combinations {
+                client.send_keys(combination)?;
+            }
+        },
+        VmCommand::Qmp(command, arguments) => {
+            let response = client.execute(&command, arguments)?;
+            println!("{}", serde_json::to_string_pretty(&response)?);
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use super::*;
+
+    fn parse(vm_args: &[&str]) -> Result<VmCommand> {
+        parse_command(&vm_args.iter().map(|arg| arg.to_string()).collect::<Vec<String>>())
+    }
+
+    #[test]
+    fn when_vm_arguments_are_parsed_then_commands_and_defaults_are_recognized() {
+        assert_eq!(parse(&["status"]).unwrap(), VmCommand::Status);
+        assert_eq!(parse(&["screendump"]).unwrap(), VmCommand::Screendump("screendump.ppm".to_string()));
+        assert_eq!(parse(&["sendkey", "ctrl-alt-delete", "ret"]).unwrap(), VmCommand::SendKeys(vec!["ctrl-alt-delete".to_string(), "ret".to_string()]));
+        assert_eq!(parse(&["query-cpus-fast"]).unwrap(), VmCommand::Qmp("query-cpus-fast".to_string(), None));
+        assert_eq!(parse(&["human-monitor-command", r#"{"command-line":"info registers"}"#]).unwrap(),
+            VmCommand::Qmp("human-monitor-command".to_string(), Some(json!({ "command-line": "info registers" }))));
+
+        assert!(parse(&[]).is_err());
+        assert!(parse(&["sendkey"]).is_err());
+        assert!(parse(&["stop", "{not json"]).is_err());
+    }
+
+    #[test]
+    fn when_job_is_selected_then_it_is_split_from_the_command() {
+        let vm_args = ["--job", "kernel-1a2b", "status"].map(String::from);
+        assert_eq!(split_job(&vm_args).unwrap(), (Some("kernel-1a2b"), &vm_args[2..]));
+        assert_eq!(split_job(&vm_args[2..]).unwrap(), (None, &vm_args[2..]));
+        assert!(split_job(&vm_args[..1]).is_err());
+
+        let build_dir = tempfile::tempdir().unwrap();
+        for job in ["memory-3c4d", "kernel-1a2b", "finished-5e6f"] {
+            fs::create_dir_all(build_dir.path().join(orchestrator::JOBS_DIRECTORY).join(job)).unwrap();
+        }
+        for job in ["memory-3c4d", "kernel-1a2b"] {
+            fs::write(qmp::get_state_path(&build_dir.path().join(orchestrator::JOBS_DIRECTORY).join(job)), "{}").unwrap();
+        }
+        assert_eq!(get_running_jobs(build_dir.path()), ["kernel-1a2b", "memory-3c4d"]);
+    }
+}